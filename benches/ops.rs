@@ -0,0 +1,542 @@
+// Criterion benchmarks of the `OpenCLArray` ops and transfers over a sweep of sizes.
+//
+// Every device op is measured twice: `<device>/kernel` is the device time of the kernels
+// alone, from profiling events, and `<device>/wall` is the host-side time until the queue
+// has finished, launch overhead included. `ndarray` is the CPU baseline where one exists.
+// Set `CARYA_BENCH_DEVICES` to a comma-separated list of device name substrings to compare
+// several devices, e.g. `GeForce,cpu` to include POCL on the CPU ("GeForce" by default).
+//
+// Besides Criterion's own reports, a run writes the mean, median and standard deviation of
+// every benchmark to `target/criterion/carya-summary.json` for tracking regressions.
+
+use carya::prelude::*;
+
+use criterion::measurement::WallTime;
+use criterion::{BenchmarkGroup, BenchmarkId, Criterion, Throughput};
+use ndarray::prelude::*;
+use ndarray_rand::rand_distr::Uniform;
+use ndarray_rand::RandomExt;
+use ocl::Error;
+use serde_json::{json, Value};
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+// Sides of the square operands of the cheap ops, and of the factorizations, which work one
+// column at a time
+const SIZES: &[usize] = &[64, 256, 1024];
+const FACTORIZATION_SIZES: &[usize] = &[32, 128, 256];
+
+struct Device {
+    label: String,
+    backend: CLBackEnd,
+}
+
+fn devices() -> Vec<Device> {
+    let names = env::var("CARYA_BENCH_DEVICES").unwrap_or_else(|_| "GeForce".into());
+    names
+        .split(',')
+        .map(|name| Device {
+            label: name.trim().to_string(),
+            backend: CLBackEnd::from_config(BackendConfig {
+                profiling: true,
+                autotune: true,
+                ..BackendConfig::new(name.trim())
+            })
+            .expect("no OpenCL device matching CARYA_BENCH_DEVICES"),
+        })
+        .collect()
+}
+
+fn random(rows: usize, cols: usize) -> Array2<f32> {
+    Array::random((rows, cols), Uniform::new(-1., 1.))
+}
+
+// A symmetric positive definite n x n array
+fn spd(n: usize) -> Array2<f32> {
+    let m = random(n, n);
+    m.dot(&m.t()) + Array2::<f32>::eye(n) * n as f32
+}
+
+fn upload(backend: &CLBackEnd, a: &Array2<f32>) -> OpenCLArray {
+    OpenCLArray::from_array(backend.clone(), a).unwrap()
+}
+
+// Benchmarks `op` on every device, with `setup` building its operands once per size
+fn bench_device<S, F>(
+    group: &mut BenchmarkGroup<WallTime>,
+    devices: &[Device],
+    size: usize,
+    setup: impl Fn(&CLBackEnd) -> S,
+    op: F,
+) where
+    F: Fn(&mut S) -> Result<(), Error>,
+{
+    for device in devices {
+        let backend = &device.backend;
+        let profiler = backend.profiler.as_ref().unwrap();
+        let mut state = setup(backend);
+        backend.synchronize().unwrap();
+
+        let kernel = format!("{}/kernel", device.label);
+        group.bench_function(BenchmarkId::new(kernel, size), |b| {
+            b.iter_custom(|iters| {
+                profiler.clear();
+                for _ in 0..iters {
+                    op(&mut state).unwrap();
+                }
+                let ns: u64 = profiler
+                    .records()
+                    .unwrap()
+                    .iter()
+                    .filter(|record| record.kind == CommandKind::Kernel)
+                    .map(ProfileRecord::duration_ns)
+                    .sum();
+                Duration::from_nanos(ns)
+            })
+        });
+
+        let wall = format!("{}/wall", device.label);
+        group.bench_function(BenchmarkId::new(wall, size), |b| {
+            b.iter_custom(|iters| {
+                let start = Instant::now();
+                for _ in 0..iters {
+                    op(&mut state).unwrap();
+                }
+                backend.synchronize().unwrap();
+                let elapsed = start.elapsed();
+                profiler.clear();
+                elapsed
+            })
+        });
+    }
+}
+
+fn bench_ndarray<S>(
+    group: &mut BenchmarkGroup<WallTime>,
+    size: usize,
+    mut state: S,
+    op: impl Fn(&mut S),
+) {
+    group.bench_function(BenchmarkId::new("ndarray", size), |b| {
+        b.iter(|| op(&mut state))
+    });
+}
+
+type Binary = fn(&OpenCLArray, &OpenCLArray, &mut OpenCLArray) -> Result<(), Error>;
+type Unary = fn(&OpenCLArray, &mut OpenCLArray) -> Result<(), Error>;
+type Reference = fn(&Array2<f32>, &Array2<f32>) -> Array2<f32>;
+
+fn elementwise(c: &mut Criterion) {
+    let devices = devices();
+    let binary: [(&str, Binary, Reference); 3] = [
+        ("add", OpenCLArray::add, |a, b| a + b),
+        ("subtract", OpenCLArray::subtract, |a, b| a - b),
+        ("hadamard", OpenCLArray::hadamard, |a, b| a * b),
+    ];
+    for &(name, op, reference) in &binary {
+        let mut group = c.benchmark_group(name);
+        for &n in SIZES {
+            group.throughput(Throughput::Elements((n * n) as u64));
+            let (a, b) = (random(n, n), random(n, n));
+            bench_device(
+                &mut group,
+                &devices,
+                n,
+                |backend| {
+                    let out = OpenCLArray::new(backend.clone(), n, n).unwrap();
+                    (upload(backend, &a), upload(backend, &b), out)
+                },
+                |(a, b, out)| op(a, b, out),
+            );
+            bench_ndarray(&mut group, n, (&a, &b), |(a, b)| {
+                reference(a, b);
+            });
+        }
+        group.finish();
+    }
+
+    let unary: [(&str, Unary); 3] = [
+        ("sigmoid", OpenCLArray::sigmoid),
+        ("sigmoid_prime", OpenCLArray::sigmoid_prime),
+        ("scalar_multiply", |a, b| a.scalar_multiply(2., b)),
+    ];
+    for &(name, op) in &unary {
+        let mut group = c.benchmark_group(name);
+        for &n in SIZES {
+            group.throughput(Throughput::Elements((n * n) as u64));
+            let a = random(n, n);
+            bench_device(
+                &mut group,
+                &devices,
+                n,
+                |backend| {
+                    let out = OpenCLArray::new(backend.clone(), n, n).unwrap();
+                    (upload(backend, &a), out)
+                },
+                |(a, out)| op(a, out),
+            );
+            if name == "sigmoid" {
+                bench_ndarray(&mut group, n, &a, |a| {
+                    a.mapv(|x| 1. / (1. + (-x).exp()));
+                });
+            }
+        }
+        group.finish();
+    }
+
+    let mut group = c.benchmark_group("square");
+    for &n in SIZES {
+        group.throughput(Throughput::Elements((n * n) as u64));
+        let a = random(n, n);
+        bench_device(
+            &mut group,
+            &devices,
+            n,
+            |backend| upload(backend, &a),
+            |a| a.square(),
+        );
+        bench_ndarray(&mut group, n, &a, |a| {
+            a.mapv(|x| x * x);
+        });
+    }
+    group.finish();
+}
+
+fn products(c: &mut Criterion) {
+    let devices = devices();
+
+    let mut group = c.benchmark_group("dot");
+    for &n in SIZES {
+        group.throughput(Throughput::Elements((n * n * n) as u64));
+        let (a, b) = (random(n, n), random(n, n));
+        bench_device(
+            &mut group,
+            &devices,
+            n,
+            |backend| {
+                let out = OpenCLArray::new(backend.clone(), n, n).unwrap();
+                (upload(backend, &a), upload(backend, &b), out)
+            },
+            |(a, b, out)| a.dot(b, out),
+        );
+        bench_ndarray(&mut group, n, (&a, &b), |(a, b)| {
+            a.dot(*b);
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("dot_transposed_view");
+    for &n in SIZES {
+        group.throughput(Throughput::Elements((n * n * n) as u64));
+        let (a, b) = (random(n, n), random(n, n));
+        bench_device(
+            &mut group,
+            &devices,
+            n,
+            |backend| {
+                let out = OpenCLArray::new(backend.clone(), n, n).unwrap();
+                (upload(backend, &a).t_view(), upload(backend, &b), out)
+            },
+            |(a, b, out)| a.dot(b, out),
+        );
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("batched_dot");
+    for &n in &[16, 64] {
+        let batch = 64;
+        group.throughput(Throughput::Elements((batch * n * n * n) as u64));
+        let (a, b) = (random(batch * n, n), random(batch * n, n));
+        bench_device(
+            &mut group,
+            &devices,
+            n,
+            |backend| {
+                let out = OpenCLArray::new(backend.clone(), batch * n, n).unwrap();
+                (upload(backend, &a), upload(backend, &b), out)
+            },
+            |(a, b, out)| a.batched_dot(b, out, batch),
+        );
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("gemv");
+    for &n in SIZES {
+        group.throughput(Throughput::Elements((n * n) as u64));
+        let (a, x) = (random(n, n), random(n, 1));
+        bench_device(
+            &mut group,
+            &devices,
+            n,
+            |backend| {
+                let y = OpenCLArray::new(backend.clone(), n, 1).unwrap();
+                (upload(backend, &a), upload(backend, &x), y)
+            },
+            |(a, x, y)| a.gemv(1., x, 0., y),
+        );
+        bench_ndarray(&mut group, n, (&a, &x), |(a, x)| {
+            a.dot(*x);
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("inner");
+    for &n in SIZES {
+        group.throughput(Throughput::Elements((n * n) as u64));
+        let (a, b) = (random(n, n), random(n, n));
+        bench_device(
+            &mut group,
+            &devices,
+            n,
+            |backend| (upload(backend, &a), upload(backend, &b)),
+            |(a, b)| a.inner(b).map(drop),
+        );
+        bench_ndarray(&mut group, n, (&a, &b), |(a, b)| {
+            (*a * *b).sum();
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("spmv");
+    for &n in SIZES {
+        // About ten entries per row
+        let dense = random(n, n).mapv(|x| if x.abs() < 10. / n as f32 { x } else { 0. });
+        group.throughput(Throughput::Elements(
+            dense.iter().filter(|&&x| x != 0.).count() as u64,
+        ));
+        let x = random(n, 1);
+        bench_device(
+            &mut group,
+            &devices,
+            n,
+            |backend| {
+                let a = OpenCLCsrMatrix::from_array(backend.clone(), &dense).unwrap();
+                let y = OpenCLArray::new(backend.clone(), n, 1).unwrap();
+                (a, upload(backend, &x), y)
+            },
+            |(a, x, y)| a.spmv(x, y),
+        );
+    }
+    group.finish();
+}
+
+fn transposes(c: &mut Criterion) {
+    let devices = devices();
+
+    let mut group = c.benchmark_group("t");
+    for &n in SIZES {
+        group.throughput(Throughput::Elements((n * n) as u64));
+        let a = random(n, n + 1);
+        bench_device(
+            &mut group,
+            &devices,
+            n,
+            |backend| upload(backend, &a),
+            |a| a.t().map(drop),
+        );
+        bench_ndarray(&mut group, n, &a, |a| {
+            a.t().to_owned();
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("t_in_place");
+    for &n in SIZES {
+        group.throughput(Throughput::Elements((n * n) as u64));
+        let a = random(n, n);
+        bench_device(
+            &mut group,
+            &devices,
+            n,
+            |backend| upload(backend, &a),
+            |a| a.t_v2(),
+        );
+    }
+    group.finish();
+}
+
+fn factorizations(c: &mut Criterion) {
+    let devices = devices();
+    type Factorization = fn(&OpenCLArray) -> Result<(), Error>;
+    let ops: [(&str, Factorization); 5] = [
+        ("lu", |a| a.lu().map(drop)),
+        ("cholesky", |a| a.cholesky().map(drop)),
+        ("qr", |a| a.qr().map(drop)),
+        ("inv", |a| a.inv().map(drop)),
+        ("det", |a| a.det().map(drop)),
+    ];
+    for &(name, op) in &ops {
+        let mut group = c.benchmark_group(name);
+        group.sample_size(10);
+        for &n in FACTORIZATION_SIZES {
+            let a = spd(n);
+            bench_device(
+                &mut group,
+                &devices,
+                n,
+                |backend| upload(backend, &a),
+                |a| op(a),
+            );
+        }
+        group.finish();
+    }
+
+    let mut group = c.benchmark_group("eigh");
+    group.sample_size(10);
+    for &n in &[16, 64] {
+        let a = spd(n);
+        bench_device(
+            &mut group,
+            &devices,
+            n,
+            |backend| upload(backend, &a),
+            |a| a.eigh().map(drop),
+        );
+    }
+    group.finish();
+}
+
+// Host-side conversions between `Array2` and the flat vectors the device reads, and the
+// transfers themselves, measured separately
+fn transfers(c: &mut Criterion) {
+    let devices = devices();
+
+    let mut group = c.benchmark_group("host_conversion");
+    for &n in SIZES {
+        group.throughput(Throughput::Bytes((n * n * 4) as u64));
+        let a = random(n, n);
+        group.bench_function(BenchmarkId::new("create_vec", n), |b| {
+            b.iter(|| create_vec(&a))
+        });
+        let v = create_vec(&a);
+        group.bench_function(BenchmarkId::new("from_shape_vec", n), |b| {
+            b.iter(|| Array::from_shape_vec((n, n), v.clone()).unwrap())
+        });
+    }
+    group.finish();
+
+    for &(name, upload_only) in &[("upload", true), ("download", false)] {
+        let mut group = c.benchmark_group(name);
+        for &n in SIZES {
+            group.throughput(Throughput::Bytes((n * n * 4) as u64));
+            let v = create_vec(&random(n, n));
+            for device in &devices {
+                let backend = &device.backend;
+                let a = OpenCLArray::from_vec(backend.clone(), n, n, v.clone()).unwrap();
+                group.bench_function(BenchmarkId::new(&device.label, n), |b| {
+                    b.iter(|| {
+                        if upload_only {
+                            OpenCLArray::from_vec(backend.clone(), n, n, v.clone()).unwrap();
+                        } else {
+                            a.clone().to_vec().unwrap();
+                        }
+                        backend.profiler.as_ref().unwrap().clear();
+                    })
+                });
+
+                // The same transfers staged through page-locked memory
+                let mut pinned = PinnedHostBuffer::new(backend, n * n).unwrap();
+                let label = format!("{}/pinned", device.label);
+                group.bench_function(BenchmarkId::new(&label, n), |b| {
+                    b.iter(|| {
+                        if upload_only {
+                            pinned.write(&v).unwrap();
+                            pinned.upload(&a).unwrap();
+                            backend.synchronize().unwrap();
+                        } else {
+                            pinned.download(&a).unwrap();
+                            pinned.to_vec().unwrap();
+                        }
+                        backend.profiler.as_ref().unwrap().clear();
+                    })
+                });
+            }
+        }
+        group.finish();
+    }
+
+    // Conversion and transfer together, as most callers use them
+    let mut group = c.benchmark_group("round_trip");
+    for &n in SIZES {
+        group.throughput(Throughput::Bytes((n * n * 4) as u64));
+        let a = random(n, n);
+        for device in &devices {
+            let backend = &device.backend;
+            group.bench_function(BenchmarkId::new(&device.label, n), |b| {
+                b.iter(|| {
+                    upload(backend, &a).to_array().unwrap();
+                    backend.profiler.as_ref().unwrap().clear();
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+// Every `new/` directory under `dir` which Criterion wrote a benchmark into
+fn benchmark_dirs(dir: &Path, found: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(Result::ok) {
+        let path = entry.path();
+        if path.ends_with("new") && path.join("estimates.json").exists() {
+            found.push(path);
+        } else if path.is_dir() {
+            benchmark_dirs(&path, found);
+        }
+    }
+}
+
+// Collects the latest estimates of every benchmark into a single JSON file
+fn write_summary() -> std::io::Result<()> {
+    let root = env::var_os("CRITERION_HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("target/criterion"));
+    let mut dirs = Vec::new();
+    benchmark_dirs(&root, &mut dirs);
+    dirs.sort();
+
+    let read =
+        |path: PathBuf| -> Option<Value> { serde_json::from_slice(&fs::read(path).ok()?).ok() };
+    let benchmarks: Vec<Value> = dirs
+        .into_iter()
+        .filter_map(|dir| {
+            let (benchmark, estimates) = (
+                read(dir.join("benchmark.json"))?,
+                read(dir.join("estimates.json"))?,
+            );
+            let ns = |statistic: &str| estimates[statistic]["point_estimate"].clone();
+            Some(json!({
+                "id": benchmark["full_id"],
+                "group": benchmark["group_id"],
+                "function": benchmark["function_id"],
+                "size": benchmark["value_str"],
+                "throughput": benchmark["throughput"],
+                "mean_ns": ns("mean"),
+                "median_ns": ns("median"),
+                "std_dev_ns": ns("std_dev"),
+            }))
+        })
+        .collect();
+    fs::create_dir_all(&root)?;
+    fs::write(
+        root.join("carya-summary.json"),
+        serde_json::to_string_pretty(&json!({ "benchmarks": benchmarks }))?,
+    )
+}
+
+fn main() {
+    let mut criterion = Criterion::default().configure_from_args();
+    elementwise(&mut criterion);
+    products(&mut criterion);
+    transposes(&mut criterion);
+    factorizations(&mut criterion);
+    transfers(&mut criterion);
+    criterion.final_summary();
+    write_summary().expect("couldn't write the benchmark summary");
+}
@@ -1,5 +1,6 @@
 use accel::error::AccelError;
 use accel::*;
+use log::info;
 use std::sync::Arc;
 
 #[kernel]
@@ -72,7 +73,7 @@ impl CudaArray {
 impl BackEnd {
     pub fn new() -> Result<Self, AccelError> {
         let device = Device::nth(0)?;
-        println!("The device is: {}", device.get_name()?);
+        info!("The device is: {}", device.get_name()?);
         let ctx = device.create_context();
         Ok(BackEnd { ctx: ctx })
     }
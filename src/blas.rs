@@ -0,0 +1,337 @@
+use crate::kernels::*;
+use crate::opencl::*;
+use crate::profiler::*;
+
+use ndarray::Axis;
+use ocl::error::Error;
+use ocl::{Buffer, Event, SpatialDims::*};
+
+// Number of partial sums a reduction is split into before they are added up. A multiple of
+// every default local size, so the launch is never padded.
+const REDUCE_PARTS: usize = 1024;
+
+impl OpenCLArray {
+    /// Whether the array is a row (`1 x n`) or column (`n x 1`) vector. The level-1 and
+    /// level-2 routines accept vectors of either orientation.
+    pub fn is_vector(&self) -> bool {
+        self.rows == 1 || self.cols == 1
+    }
+
+    // Same shape, or vectors of the same length in either orientation
+    fn assert_conformable(&self, b: &OpenCLArray) {
+        if self.is_vector() && b.is_vector() {
+            assert_eq!(self.len(), b.len());
+        } else {
+            assert_eq!((self.rows, self.cols), (b.rows, b.cols));
+        }
+    }
+
+    // Runs the partial-sum kernel `name` over `inputs` (and any `extra` arguments after the
+    // usual ones) followed by `reduce_sum`, reading the result back
+    pub(crate) fn reduce(
+        &self,
+        name: &str,
+        inputs: &[&OpenCLArray],
+        extra: &[Arg],
+    ) -> Result<f32, Error> {
+        let partial = OpenCLArray::uninitialized(self.backend.clone(), 1, REDUCE_PARTS)?;
+        let out = OpenCLArray::uninitialized(self.backend.clone(), 1, 1)?;
+
+        let mut args: Vec<Arg> = inputs.iter().map(|a| Arg::Buffer(&a.v)).collect();
+        args.extend(&[
+            Arg::Buffer(&partial.v),
+            Arg::Ulong(self.len() as u64),
+            Arg::Ulong(REDUCE_PARTS as u64),
+        ]);
+        args.extend(extra);
+        self.backend.enq_kernel(name, &args, One(REDUCE_PARTS))?;
+        self.backend.enq_kernel(
+            "reduce_sum",
+            &[
+                Arg::Buffer(&partial.v),
+                Arg::Buffer(&out.v),
+                Arg::Ulong(REDUCE_PARTS as u64),
+            ],
+            One(1),
+        )?;
+        Ok(out.to_vec()?[0])
+    }
+
+    /// The sum of the element-wise products of two same-shape arrays or two vectors (BLAS
+    /// `dot`), read back from the device
+    pub fn inner(&self, b: &OpenCLArray) -> Result<f32, Error> {
+        self.assert_conformable(b);
+        let (a, b) = (self.contiguous()?, b.contiguous()?);
+        self.reduce("dot_partial", &[&a, &b], &[])
+    }
+
+    /// The Euclidean (Frobenius, for matrices) norm
+    pub fn norm(&self) -> Result<f32, Error> {
+        Ok(self.inner(self)?.sqrt())
+    }
+
+    /// `self = alpha * x + beta * self`. `self` isn't read if `beta` is zero.
+    pub fn axpby(&mut self, alpha: f32, x: &OpenCLArray, beta: f32) -> Result<(), Error> {
+        self.assert_conformable(x);
+        assert!(!self.transposed);
+        let x = x.contiguous()?;
+        self.backend.enq_kernel(
+            "axpby",
+            &[
+                Arg::Buffer(&x.v),
+                Arg::Buffer(&self.v),
+                Arg::Float(alpha),
+                Arg::Float(beta),
+                Arg::Ulong(self.len() as u64),
+            ],
+            One(self.len()),
+        )
+    }
+
+    /// `self += alpha * x`
+    pub fn axpy(&mut self, alpha: f32, x: &OpenCLArray) -> Result<(), Error> {
+        self.axpby(alpha, x, 1.)
+    }
+
+    /// The Euclidean norm of a vector
+    pub fn nrm2(&self) -> Result<f32, Error> {
+        assert!(self.is_vector());
+        self.norm()
+    }
+
+    /// The sum of the absolute values of the elements
+    pub fn asum(&self) -> Result<f32, Error> {
+        self.reduce("asum_partial", &[self], &[])
+    }
+
+    /// The index of the first element with the largest absolute value, or `None` if empty
+    pub fn iamax(&self) -> Result<Option<usize>, Error> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        let a = self.contiguous()?;
+        let partial = OpenCLArray::uninitialized(self.backend.clone(), 1, REDUCE_PARTS)?;
+        let index = Buffer::<u64>::builder()
+            .queue(self.backend.queue().clone())
+            .len(REDUCE_PARTS)
+            .build()?;
+        let parts = Arg::Ulong(REDUCE_PARTS as u64);
+
+        self.backend.enq_kernel(
+            "iamax_partial",
+            &[
+                Arg::Buffer(&a.v),
+                Arg::Buffer(&partial.v),
+                Arg::Indices(&index),
+                Arg::Ulong(self.len() as u64),
+                parts,
+            ],
+            One(REDUCE_PARTS),
+        )?;
+        self.backend.enq_kernel(
+            "iamax_reduce",
+            &[Arg::Buffer(&partial.v), Arg::Indices(&index), parts],
+            One(1),
+        )?;
+        let mut best = [0u64];
+        index
+            .read(&mut best[..])
+            .queue(self.backend.queue())
+            .len(1)
+            .enq()?;
+        Ok(Some(best[0] as usize))
+    }
+
+    /// `self *= alpha`
+    pub fn scal(&mut self, alpha: f32) -> Result<(), Error> {
+        self.backend.enq_kernel(
+            "multiply_by_scalar",
+            &[
+                Arg::Buffer(&self.v),
+                Arg::Buffer(&self.v),
+                Arg::Float(alpha),
+                Arg::Ulong(self.len() as u64),
+            ],
+            One(self.len()),
+        )
+    }
+
+    /// `y = alpha * A x + beta * y` for this `rows x cols` array `A`, a vector `x` of length
+    /// `cols` and a vector `y` of length `rows`. `y` isn't read if `beta` is zero.
+    pub fn gemv(
+        &self,
+        alpha: f32,
+        x: &OpenCLArray,
+        beta: f32,
+        y: &mut OpenCLArray,
+    ) -> Result<(), Error> {
+        assert!(x.is_vector() && y.is_vector());
+        assert_eq!((x.len(), y.len()), (self.cols, self.rows));
+        assert!(!y.transposed);
+        let (a, x) = (self.contiguous()?, x.contiguous()?);
+        self.backend.enq_kernel(
+            "gemv",
+            &[
+                Arg::Buffer(&a.v),
+                Arg::Buffer(&x.v),
+                Arg::Buffer(&y.v),
+                Arg::Float(alpha),
+                Arg::Float(beta),
+                Arg::Ulong(self.rows as u64),
+                Arg::Ulong(self.cols as u64),
+            ],
+            One(self.rows),
+        )
+    }
+
+    /// Rank-one update `self += alpha * x y^T`, for vectors `x` of length `rows` and `y` of
+    /// length `cols`
+    pub fn ger(&mut self, alpha: f32, x: &OpenCLArray, y: &OpenCLArray) -> Result<(), Error> {
+        assert!(x.is_vector() && y.is_vector());
+        assert_eq!((x.len(), y.len()), (self.rows, self.cols));
+        assert!(!self.transposed);
+        let (x, y) = (x.contiguous()?, y.contiguous()?);
+        self.backend.enq_kernel(
+            "ger",
+            &[
+                Arg::Buffer(&self.v),
+                Arg::Buffer(&x.v),
+                Arg::Buffer(&y.v),
+                Arg::Float(alpha),
+                Arg::Ulong(self.rows as u64),
+                Arg::Ulong(self.cols as u64),
+            ],
+            Two(self.rows, self.cols),
+        )
+    }
+
+    /// Multiplies a batch of matrices in one launch, with `self` holding the `batch` left-hand
+    /// `n x m` matrices stacked into a `(batch * n) x m` array, `b` the right-hand `m x k` ones
+    /// stacked into a `(batch * m) x k` array and `c` the `(batch * n) x k` results. Either
+    /// operand may instead be a single matrix, which is then used for every product.
+    pub fn batched_dot(
+        &self,
+        b: &OpenCLArray,
+        c: &mut OpenCLArray,
+        batch: usize,
+    ) -> Result<(), Error> {
+        assert!(batch > 0);
+        assert_eq!(c.rows % batch, 0);
+        let (n, m, k) = (c.rows / batch, self.cols, b.cols);
+        assert_eq!(c.cols, k);
+        let stride = |array: &OpenCLArray, rows: usize| {
+            if array.rows == rows {
+                0
+            } else {
+                assert_eq!(array.rows, batch * rows);
+                rows * array.cols
+            }
+        };
+
+        let layout = BatchLayout {
+            batch,
+            n,
+            m,
+            k,
+            stride_a: stride(self, n),
+            stride_b: stride(b, m),
+            stride_c: n * k,
+        };
+        self.batched_dot_strided(b, c, layout)
+    }
+
+    /// Multiplies a batch of matrices laid out as described by `layout`
+    pub fn batched_dot_strided(
+        &self,
+        b: &OpenCLArray,
+        c: &mut OpenCLArray,
+        layout: BatchLayout,
+    ) -> Result<(), Error> {
+        let BatchLayout { batch, n, m, k, .. } = layout;
+        let fits = |array: &OpenCLArray, stride: usize, len: usize| {
+            batch == 0 || (batch - 1) * stride + len <= array.len()
+        };
+        assert!(fits(self, layout.stride_a, n * m));
+        assert!(fits(b, layout.stride_b, m * k));
+        assert!(fits(c, layout.stride_c, n * k));
+        assert!(layout.stride_c >= n * k || batch <= 1);
+        assert!(!c.transposed);
+        let (a, b) = (self.contiguous()?, b.contiguous()?);
+
+        self.backend.enq_kernel(
+            "batched_dot",
+            &[
+                Arg::Buffer(&a.v),
+                Arg::Buffer(&b.v),
+                Arg::Buffer(&c.v),
+                Arg::Ulong(batch as u64),
+                Arg::Ulong(n as u64),
+                Arg::Ulong(m as u64),
+                Arg::Ulong(k as u64),
+                Arg::Ulong(layout.stride_a as u64),
+                Arg::Ulong(layout.stride_b as u64),
+                Arg::Ulong(layout.stride_c as u64),
+            ],
+            Three(batch, n, k),
+        )
+    }
+
+    /// Computes `a[i].dot(b[i])` for every pair in one launch, going through stacked copies of
+    /// the operands. `b` may hold a single matrix used for every product.
+    pub fn batched_dot_many(
+        a: &[OpenCLArray],
+        b: &[OpenCLArray],
+    ) -> Result<Vec<OpenCLArray>, Error> {
+        if a.is_empty() {
+            return Ok(Vec::new());
+        }
+        assert!(b.len() == a.len() || b.len() == 1);
+        let backend = a[0].backend.clone();
+        let (batch, n, m, k) = (a.len(), a[0].rows, a[0].cols, b[0].cols);
+        assert!(a.iter().all(|x| (x.rows, x.cols) == (n, m)));
+        assert!(b.iter().all(|x| (x.rows, x.cols) == (m, k)));
+
+        let stack = |arrays: &[OpenCLArray]| OpenCLArray::stack(&arrays.iter().collect::<Vec<_>>());
+        let (a_stack, b_stack) = (stack(a)?, stack(b)?);
+        let mut c_stack = OpenCLArray::uninitialized(backend, batch * n, k)?;
+        a_stack.batched_dot(&b_stack, &mut c_stack, batch)?;
+        c_stack.chunks(Axis(0), n)
+    }
+}
+
+/// How the operands of `batched_dot_strided` are laid out: `batch` products of `n x m` and
+/// `m x k` matrices, the `i`-th matrix of each operand starting `i * stride` elements into
+/// its buffer. A stride of zero reuses one matrix for the whole batch.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BatchLayout {
+    pub batch: usize,
+    pub n: usize,
+    pub m: usize,
+    pub k: usize,
+    pub stride_a: usize,
+    pub stride_b: usize,
+    pub stride_c: usize,
+}
+
+/// Copies `len` elements of `src`, starting at `src_offset`, into `dst` at `dst_offset`
+pub(crate) fn copy_region(
+    src: &OpenCLArray,
+    src_offset: usize,
+    dst: &OpenCLArray,
+    dst_offset: usize,
+    len: usize,
+) -> Result<(), Error> {
+    if len == 0 {
+        return Ok(());
+    }
+    let mut event = Event::empty();
+    src.v
+        .cmd()
+        .queue(src.backend.queue())
+        .offset(src_offset)
+        .copy(&dst.v, Some(dst_offset), Some(len))
+        .enew(&mut event)
+        .enq()?;
+    src.backend.profile("copy", CommandKind::Transfer, event);
+    Ok(())
+}
@@ -2,37 +2,51 @@
 // at https://github.com/tedsta/gpuarray-rs
 // and is licensed under the MIT License 
 
+// Every kernel takes the number of elements (or the extent of each dimension) it covers and
+// returns early for work-items past the end, since the host rounds the global work size up to
+// a multiple of the work-group size. Indices are 64-bit so arrays past 4G elements work.
 
 // square
 float square_op(float z){return (z*z);}
-__kernel void square(__global float *a) {
+__kernel void square(__global float *a, const ulong n) {
     ulong i = get_global_id(0);
-   
-    a[i] = square_op(a[i]);
+    if (i < n) {
+        a[i] = square_op(a[i]);
+    }
 }
 
 // ADD SCALAR
-__kernel void add_scalar(__global float* buffer, float scalar) {
-    buffer[get_global_id(0)] += scalar; 
+__kernel void add_scalar(__global float* buffer, float scalar, const ulong n) {
+    ulong i = get_global_id(0);
+    if (i < n) {
+        buffer[i] += scalar;
+    }
 }
 
 // HADAMARD/ARRAY ELEMENT-WISE MULTIPLICATION
 __kernel void hadamard(__global const float *a,
                        __global const float *b,
-                                  __global float *c) {
-    uint i = get_global_id(0);
-    c[i] = a[i] * b[i];
+                                  __global float *c,
+                       const ulong n) {
+    ulong i = get_global_id(0);
+    if (i < n) {
+        c[i] = a[i] * b[i];
+    }
 }
 
 // DOT PRODUCT
 __kernel void dot_product(__global const float* A, 
                           __global const float* B,
                           __global float* C,
-                          ulong M,    
-                          ulong K ) {
+                          const ulong N,
+                          const ulong M,    
+                          const ulong K ) {
   
   ulong row = get_global_id(0);
   ulong column = get_global_id(1);
+  if (row >= N || column >= K) {
+    return;
+  }
 
   float sum = 0.0;
   for (ulong i = 0; i < M; i++) {
@@ -41,31 +55,65 @@ __kernel void dot_product(__global const float* A,
   C[row * K + column] = sum;
 }
 
+// DOT PRODUCT OF OPERANDS WHICH MAY BE STORED TRANSPOSED
+// A transposed `A` holds the M x N matrix whose transpose is used, and likewise for `B`
+__kernel void dot_product_transposed(__global const float* A,
+                                     __global const float* B,
+                                     __global float* C,
+                                     const ulong N,
+                                     const ulong M,
+                                     const ulong K,
+                                     const ulong trans_a,
+                                     const ulong trans_b) {
+  ulong row = get_global_id(0);
+  ulong column = get_global_id(1);
+  if (row >= N || column >= K) {
+    return;
+  }
+
+  float sum = 0.0;
+  for (ulong i = 0; i < M; i++) {
+    float a = trans_a ? A[i * N + row] : A[row * M + i];
+    float b = trans_b ? B[column * M + i] : B[i * K + column];
+    sum += a * b;
+  }
+  C[row * K + column] = sum;
+}
+
 
 // MULTIPLY BY SCALAR
 __kernel void multiply_by_scalar(
             __global const float *a,
             __global float *b,
-            float coeff
+            float coeff,
+            const ulong n
             )
 {
     ulong const i = get_global_id(0);
-    b[i] = a[i] * coeff;
+    if (i < n) {
+        b[i] = a[i] * coeff;
+    }
 }
 
 // SIGMOID
 float sigmoid_op(float z){return 1.0/(1.0+exp(-z));}
 __kernel void sigmoid(__global const float *a,
-                                __global float *b) {
-    uintptr_t i = get_global_id(0);
-    b[i] = sigmoid_op(a[i]);
+                                __global float *b,
+                      const ulong n) {
+    ulong i = get_global_id(0);
+    if (i < n) {
+        b[i] = sigmoid_op(a[i]);
+    }
 }
 
 __kernel void sigmoid_prime(__global const float *a,
-                                __global float *b) {
-    uintptr_t i = get_global_id(0);
-    b[i] = sigmoid_op(a[i]);
-    b[i] = b[i]*(1.0 - b[i]);
+                                __global float *b,
+                            const ulong n) {
+    ulong i = get_global_id(0);
+    if (i < n) {
+        float s = sigmoid_op(a[i]);
+        b[i] = s*(1.0 - s);
+    }
 }
 
 __kernel void transpose(__global const float *a,
@@ -74,21 +122,824 @@ __kernel void transpose(__global const float *a,
                                    const ulong cols) {
     ulong i = get_global_id(0);
     ulong j = get_global_id(1);
-    b[j*rows + i] = a[i*cols + j]; // Flip the dimensions
+    if (i < rows && j < cols) {
+        b[j*rows + i] = a[i*cols + j]; // Flip the dimensions
+    }
+}
+
+// TILED TRANSPOSE
+// Each work-group stages a square tile in local memory so both the reads of `a` and the writes
+// of `b` are coalesced. The global size is (cols, rows) and the local size must be square,
+// with a side of at most TRANSPOSE_TILE.
+#define TRANSPOSE_TILE 16
+
+__kernel void transpose_tiled(__global const float *a,
+                              __global float *b,
+                              const ulong rows,
+                              const ulong cols) {
+    // The extra column keeps the column-wise reads of the tile free of bank conflicts
+    __local float tile[TRANSPOSE_TILE][TRANSPOSE_TILE + 1];
+    size_t lx = get_local_id(0);
+    size_t ly = get_local_id(1);
+    size_t side = get_local_size(0);
+
+    ulong j = get_global_id(0);
+    ulong i = get_global_id(1);
+    if (i < rows && j < cols) {
+        tile[ly][lx] = a[i*cols + j];
+    }
+    barrier(CLK_LOCAL_MEM_FENCE);
+
+    // The same tile, seen from `b`: row bi of `b` is column bi of `a`
+    ulong bi = get_group_id(0)*side + ly;
+    ulong bj = get_group_id(1)*side + lx;
+    if (bi < cols && bj < rows) {
+        b[bi*rows + bj] = tile[lx][ly];
+    }
+}
+
+// IN-PLACE TRANSPOSE OF A SQUARE MATRIX
+// Each pair of entries mirrored across the diagonal is swapped by the work-item above it
+__kernel void transpose_square(__global float *a, const ulong n) {
+    ulong i = get_global_id(0);
+    ulong j = get_global_id(1);
+    if (i < n && j < n && i < j) {
+        float x = a[i*n + j];
+        a[i*n + j] = a[j*n + i];
+        a[j*n + i] = x;
+    }
 }
 
 // ADDITION OF TWO SAME-SIZE VECTORS
 __kernel void add(__global const float *a,
                        __global const float *b,
-                                  __global float *c) {
-    uint i = get_global_id(0);
-    c[i] = a[i] + b[i];
+                                  __global float *c,
+                  const ulong n) {
+    ulong i = get_global_id(0);
+    if (i < n) {
+        c[i] = a[i] + b[i];
+    }
 }
 
 // SUBTRACTION OF TWO SAME-SIZE VECTORS
 __kernel void subtract(__global const float *a,
                        __global const float *b,
-                             __global float *c) {
-    uint i = get_global_id(0);
-    c[i] = a[i] - b[i];
-}
\ No newline at end of file
+                             __global float *c,
+                       const ulong n) {
+    ulong i = get_global_id(0);
+    if (i < n) {
+        c[i] = a[i] - b[i];
+    }
+}
+
+// LINEAR ALGEBRA
+// The factorizations and triangular solves below work in place, one column k at a time: the
+// host enqueues a pivot or reflector kernel for column k followed by kernels updating the
+// rows or columns after it. Arrays are square n x n unless noted.
+
+// Swaps row k with the row at or below it holding the largest |a[i][k]|, recording the swap
+// in perm and flipping sign. Runs as a single work-item.
+__kernel void lu_pivot(__global float *a,
+                       __global ulong *perm,
+                       __global float *sign,
+                       const ulong n,
+                       const ulong k) {
+    if (get_global_id(0) != 0) {
+        return;
+    }
+    ulong p = k;
+    float best = fabs(a[k*n + k]);
+    for (ulong i = k + 1; i < n; i++) {
+        float x = fabs(a[i*n + k]);
+        if (x > best) {
+            best = x;
+            p = i;
+        }
+    }
+    if (p != k) {
+        for (ulong j = 0; j < n; j++) {
+            float tmp = a[k*n + j];
+            a[k*n + j] = a[p*n + j];
+            a[p*n + j] = tmp;
+        }
+        ulong swap = perm[k];
+        perm[k] = perm[p];
+        perm[p] = swap;
+        sign[0] = -sign[0];
+    }
+}
+
+// Divides the entries of column k below the diagonal by the diagonal entry
+__kernel void scale_below_diagonal(__global float *a, const ulong n, const ulong k) {
+    ulong i = k + 1 + get_global_id(0);
+    if (i < n) {
+        a[i*n + k] /= a[k*n + k];
+    }
+}
+
+// Rank-one update of the trailing submatrix after column k of an LU factorization
+__kernel void lu_update(__global float *a, const ulong n, const ulong k) {
+    ulong i = k + 1 + get_global_id(0);
+    ulong j = k + 1 + get_global_id(1);
+    if (i < n && j < n) {
+        a[i*n + j] -= a[i*n + k] * a[k*n + j];
+    }
+}
+
+// The product of the diagonal of an LU factorization and the sign of its row permutation.
+// Runs as a single work-item.
+__kernel void lu_det(__global const float *a,
+                     __global const float *sign,
+                     __global float *det,
+                     const ulong n) {
+    if (get_global_id(0) != 0) {
+        return;
+    }
+    float d = sign[0];
+    for (ulong k = 0; k < n; k++) {
+        d *= a[k*n + k];
+    }
+    det[0] = d;
+}
+
+// Row i of the rows x cols array b is row perm[i] of a
+__kernel void permute_rows(__global const float *a,
+                           __global float *b,
+                           __global const ulong *perm,
+                           const ulong rows,
+                           const ulong cols) {
+    ulong i = get_global_id(0);
+    ulong j = get_global_id(1);
+    if (i < rows && j < cols) {
+        b[i*cols + j] = a[perm[i]*cols + j];
+    }
+}
+
+__kernel void cholesky_diagonal(__global float *a, const ulong n, const ulong k) {
+    if (get_global_id(0) == 0) {
+        a[k*n + k] = sqrt(a[k*n + k]);
+    }
+}
+
+// Updates the lower triangle of the trailing submatrix after column k of a Cholesky
+// factorization
+__kernel void cholesky_update(__global float *a, const ulong n, const ulong k) {
+    ulong i = k + 1 + get_global_id(0);
+    ulong j = k + 1 + get_global_id(1);
+    if (i < n && j <= i) {
+        a[i*n + j] -= a[i*n + k] * a[j*n + k];
+    }
+}
+
+// Zeroes the entries of a rows x cols array above the diagonal, setting the diagonal to one
+// if unit is non-zero
+__kernel void tril(__global float *a, const ulong rows, const ulong cols, const ulong unit) {
+    ulong i = get_global_id(0);
+    ulong j = get_global_id(1);
+    if (i < rows && j < cols) {
+        if (j > i) {
+            a[i*cols + j] = 0.0;
+        } else if (j == i && unit) {
+            a[i*cols + j] = 1.0;
+        }
+    }
+}
+
+// Zeroes the entries of a rows x cols array below the diagonal
+__kernel void triu(__global float *a, const ulong rows, const ulong cols) {
+    ulong i = get_global_id(0);
+    ulong j = get_global_id(1);
+    if (i < rows && j < cols && j < i) {
+        a[i*cols + j] = 0.0;
+    }
+}
+
+// Computes the unit Householder vector v (of length rows) which zeroes column k of the
+// rows x cols array a below the diagonal, or v = 0 if that column is already zero. Runs as a
+// single work-item.
+__kernel void householder_vector(__global const float *a,
+                                 __global float *v,
+                                 const ulong rows,
+                                 const ulong cols,
+                                 const ulong k) {
+    if (get_global_id(0) != 0) {
+        return;
+    }
+    float norm = 0.0;
+    for (ulong i = k; i < rows; i++) {
+        norm += a[i*cols + k] * a[i*cols + k];
+    }
+    norm = sqrt(norm);
+    float alpha = a[k*cols + k] >= 0.0 ? -norm : norm;
+
+    float v_norm = 0.0;
+    for (ulong i = 0; i < rows; i++) {
+        if (i < k) {
+            v[i] = 0.0;
+        } else {
+            v[i] = i == k ? a[k*cols + k] - alpha : a[i*cols + k];
+        }
+        v_norm += v[i] * v[i];
+    }
+    v_norm = sqrt(v_norm);
+    for (ulong i = k; i < rows; i++) {
+        v[i] = v_norm > 0.0 ? v[i] / v_norm : 0.0;
+    }
+}
+
+// a = (I - 2vv^T) a for columns k and after, one work-item per column
+__kernel void householder_left(__global float *a,
+                               __global const float *v,
+                               const ulong rows,
+                               const ulong cols,
+                               const ulong k) {
+    ulong j = k + get_global_id(0);
+    if (j >= cols) {
+        return;
+    }
+    float s = 0.0;
+    for (ulong i = k; i < rows; i++) {
+        s += v[i] * a[i*cols + j];
+    }
+    for (ulong i = k; i < rows; i++) {
+        a[i*cols + j] -= 2.0 * v[i] * s;
+    }
+}
+
+// q = q (I - 2vv^T) for the rows x rows array q, one work-item per row
+__kernel void householder_right(__global float *q,
+                                __global const float *v,
+                                const ulong rows,
+                                const ulong k) {
+    ulong r = get_global_id(0);
+    if (r >= rows) {
+        return;
+    }
+    float s = 0.0;
+    for (ulong i = k; i < rows; i++) {
+        s += q[r*rows + i] * v[i];
+    }
+    for (ulong i = k; i < rows; i++) {
+        q[r*rows + i] -= 2.0 * s * v[i];
+    }
+}
+
+// Divides row k of the n x r right-hand side x by the diagonal entry t[k][k]
+__kernel void trsm_divide(__global const float *t,
+                          __global float *x,
+                          const ulong n,
+                          const ulong r,
+                          const ulong k) {
+    ulong c = get_global_id(0);
+    if (c < r) {
+        x[k*r + c] /= t[k*n + k];
+    }
+}
+
+// Eliminates the now-solved row k of x from rows start..end
+__kernel void trsm_eliminate(__global const float *t,
+                             __global float *x,
+                             const ulong n,
+                             const ulong r,
+                             const ulong k,
+                             const ulong start,
+                             const ulong end) {
+    ulong i = start + get_global_id(0);
+    ulong c = get_global_id(1);
+    if (i < end && c < r) {
+        x[i*r + c] -= t[i*n + k] * x[k*r + c];
+    }
+}
+
+// BLAS
+// Each of the parts work-items accumulates a strided slice of the products a[k]*b[k] into
+// partial[i]; reduce_sum then adds the partials up in a single work-item
+__kernel void dot_partial(__global const float *a,
+                          __global const float *b,
+                          __global float *partial,
+                          const ulong n,
+                          const ulong parts) {
+    ulong i = get_global_id(0);
+    if (i >= parts) {
+        return;
+    }
+    float sum = 0.0;
+    for (ulong k = i; k < n; k += parts) {
+        sum += a[k] * b[k];
+    }
+    partial[i] = sum;
+}
+
+__kernel void reduce_sum(__global const float *partial,
+                         __global float *out,
+                         const ulong parts) {
+    if (get_global_id(0) != 0) {
+        return;
+    }
+    float sum = 0.0;
+    for (ulong i = 0; i < parts; i++) {
+        sum += partial[i];
+    }
+    out[0] = sum;
+}
+
+// y = alpha*x + beta*y, without reading y when beta is zero
+__kernel void axpby(__global const float *x,
+                    __global float *y,
+                    float alpha,
+                    float beta,
+                    const ulong n) {
+    ulong i = get_global_id(0);
+    if (i < n) {
+        y[i] = beta == 0.0 ? alpha * x[i] : alpha * x[i] + beta * y[i];
+    }
+}
+
+// d[i] = 1/a[i][i] for the n x n array a
+__kernel void inverse_diagonal(__global const float *a, __global float *d, const ulong n) {
+    ulong i = get_global_id(0);
+    if (i < n) {
+        d[i] = 1.0 / a[i*n + i];
+    }
+}
+
+// SPARSE
+// A rows x cols CSR matrix is given by row_ptr (rows + 1 offsets into col_idx and values),
+// col_idx and values
+
+// c = a b for the CSR matrix a and the dense cols x k array b
+__kernel void csr_spmm(__global const ulong *row_ptr,
+                       __global const ulong *col_idx,
+                       __global const float *values,
+                       __global const float *b,
+                       __global float *c,
+                       const ulong rows,
+                       const ulong k) {
+    ulong i = get_global_id(0);
+    ulong j = get_global_id(1);
+    if (i < rows && j < k) {
+        float sum = 0.0;
+        for (ulong p = row_ptr[i]; p < row_ptr[i + 1]; p++) {
+            sum += values[p] * b[col_idx[p]*k + j];
+        }
+        c[i*k + j] = sum;
+    }
+}
+
+// Scatters the CSR matrix into the zeroed rows x cols array dense, one work-item per row
+__kernel void csr_to_dense(__global const ulong *row_ptr,
+                           __global const ulong *col_idx,
+                           __global const float *values,
+                           __global float *dense,
+                           const ulong rows,
+                           const ulong cols) {
+    ulong i = get_global_id(0);
+    if (i < rows) {
+        for (ulong p = row_ptr[i]; p < row_ptr[i + 1]; p++) {
+            dense[i*cols + col_idx[p]] = values[p];
+        }
+    }
+}
+
+__kernel void asum_partial(__global const float *a,
+                           __global float *partial,
+                           const ulong n,
+                           const ulong parts) {
+    ulong i = get_global_id(0);
+    if (i >= parts) {
+        return;
+    }
+    float sum = 0.0;
+    for (ulong k = i; k < n; k += parts) {
+        sum += fabs(a[k]);
+    }
+    partial[i] = sum;
+}
+
+// Like dot_partial, but each part records the first index of its largest |a[k]|
+__kernel void iamax_partial(__global const float *a,
+                            __global float *partial,
+                            __global ulong *index,
+                            const ulong n,
+                            const ulong parts) {
+    ulong i = get_global_id(0);
+    if (i >= parts) {
+        return;
+    }
+    float best = -1.0;
+    ulong best_k = 0;
+    for (ulong k = i; k < n; k += parts) {
+        if (fabs(a[k]) > best) {
+            best = fabs(a[k]);
+            best_k = k;
+        }
+    }
+    partial[i] = best;
+    index[i] = best_k;
+}
+
+// Leaves the first index of the overall largest |a[k]| in index[0]. Runs as a single
+// work-item.
+__kernel void iamax_reduce(__global const float *partial,
+                           __global ulong *index,
+                           const ulong parts) {
+    if (get_global_id(0) != 0) {
+        return;
+    }
+    float best = partial[0];
+    ulong best_k = index[0];
+    for (ulong i = 1; i < parts; i++) {
+        if (partial[i] > best || (partial[i] == best && index[i] < best_k)) {
+            best = partial[i];
+            best_k = index[i];
+        }
+    }
+    index[0] = best_k;
+}
+
+// y = alpha*a*x + beta*y for the rows x cols array a, one work-item per row
+__kernel void gemv(__global const float *a,
+                   __global const float *x,
+                   __global float *y,
+                   float alpha,
+                   float beta,
+                   const ulong rows,
+                   const ulong cols) {
+    ulong i = get_global_id(0);
+    if (i < rows) {
+        float sum = 0.0;
+        for (ulong j = 0; j < cols; j++) {
+            sum += a[i*cols + j] * x[j];
+        }
+        // As in BLAS, y isn't read when beta is zero, so it may start out uninitialized
+        y[i] = beta == 0.0 ? alpha * sum : alpha * sum + beta * y[i];
+    }
+}
+
+// a += alpha*x*y^T for the rows x cols array a
+__kernel void ger(__global float *a,
+                  __global const float *x,
+                  __global const float *y,
+                  float alpha,
+                  const ulong rows,
+                  const ulong cols) {
+    ulong i = get_global_id(0);
+    ulong j = get_global_id(1);
+    if (i < rows && j < cols) {
+        a[i*cols + j] += alpha * x[i] * y[j];
+    }
+}
+
+// BATCHED DOT PRODUCT
+// C_i = A_i B_i for every i < batch, with A_i (N x M), B_i (M x K) and C_i (N x K) starting
+// i*stride elements into their buffers. A stride of zero broadcasts one matrix over the batch.
+__kernel void batched_dot(__global const float *A,
+                          __global const float *B,
+                          __global float *C,
+                          const ulong batch,
+                          const ulong N,
+                          const ulong M,
+                          const ulong K,
+                          const ulong stride_a,
+                          const ulong stride_b,
+                          const ulong stride_c) {
+    ulong b = get_global_id(0);
+    ulong row = get_global_id(1);
+    ulong column = get_global_id(2);
+    if (b >= batch || row >= N || column >= K) {
+        return;
+    }
+
+    __global const float *a = A + b*stride_a;
+    __global const float *b_mat = B + b*stride_b;
+    float sum = 0.0;
+    for (ulong i = 0; i < M; i++) {
+        sum += a[row*M + i] * b_mat[i*K + column];
+    }
+    C[b*stride_c + row*K + column] = sum;
+}
+
+// EIGENDECOMPOSITION
+// Parallel cyclic Jacobi: every round rotates m/2 disjoint index pairs at once, m being n
+// rounded up to an even number. Over the m - 1 rounds of a sweep each pair comes up once.
+
+// The pair handled by work-item i in the given round of a round-robin tournament over m
+// indices, with p < q. Pairs involving the padding index n (if m > n) are skipped.
+void jacobi_pair(ulong i, ulong m, ulong round, ulong *p, ulong *q) {
+    ulong a = i == 0 ? 0 : (i - 1 + round) % (m - 1) + 1;
+    ulong b = (m - 2 - i + round) % (m - 1) + 1;
+    *p = min(a, b);
+    *q = max(a, b);
+}
+
+// Stores the cosine and sine of the rotation zeroing a[p][q] for each pair of the round
+__kernel void jacobi_angles(__global const float *a,
+                            __global float *rot,
+                            const ulong n,
+                            const ulong m,
+                            const ulong round) {
+    ulong i = get_global_id(0);
+    if (i >= m / 2) {
+        return;
+    }
+    ulong p, q;
+    jacobi_pair(i, m, round, &p, &q);
+    float c = 1.0;
+    float s = 0.0;
+    if (q < n && a[p*n + q] != 0.0) {
+        float theta = (a[q*n + q] - a[p*n + p]) / (2.0 * a[p*n + q]);
+        float t = (theta >= 0.0 ? 1.0 : -1.0) / (fabs(theta) + sqrt(theta*theta + 1.0));
+        c = 1.0 / sqrt(t*t + 1.0);
+        s = t * c;
+    }
+    rot[2*i] = c;
+    rot[2*i + 1] = s;
+}
+
+// a = J^T a for the n x n array a, rotating rows p and q of every pair
+__kernel void jacobi_rotate_rows(__global float *a,
+                                 __global const float *rot,
+                                 const ulong n,
+                                 const ulong m,
+                                 const ulong round) {
+    ulong i = get_global_id(0);
+    ulong j = get_global_id(1);
+    if (i >= m / 2 || j >= n) {
+        return;
+    }
+    ulong p, q;
+    jacobi_pair(i, m, round, &p, &q);
+    if (q >= n) {
+        return;
+    }
+    float c = rot[2*i];
+    float s = rot[2*i + 1];
+    float ap = a[p*n + j];
+    float aq = a[q*n + j];
+    a[p*n + j] = c*ap - s*aq;
+    a[q*n + j] = s*ap + c*aq;
+}
+
+// a = a J for the n x n array a, rotating columns p and q of every pair
+__kernel void jacobi_rotate_cols(__global float *a,
+                                 __global const float *rot,
+                                 const ulong n,
+                                 const ulong m,
+                                 const ulong round) {
+    ulong i = get_global_id(0);
+    ulong j = get_global_id(1);
+    if (i >= m / 2 || j >= n) {
+        return;
+    }
+    ulong p, q;
+    jacobi_pair(i, m, round, &p, &q);
+    if (q >= n) {
+        return;
+    }
+    float c = rot[2*i];
+    float s = rot[2*i + 1];
+    float ap = a[j*n + p];
+    float aq = a[j*n + q];
+    a[j*n + p] = c*ap - s*aq;
+    a[j*n + q] = s*ap + c*aq;
+}
+
+// Partial sums of the squared off-diagonal entries of the cols-wide array a, as dot_partial
+__kernel void off_diagonal_partial(__global const float *a,
+                                   __global float *partial,
+                                   const ulong n,
+                                   const ulong parts,
+                                   const ulong cols) {
+    ulong i = get_global_id(0);
+    if (i >= parts) {
+        return;
+    }
+    float sum = 0.0;
+    for (ulong k = i; k < n; k += parts) {
+        if (k / cols != k % cols) {
+            sum += a[k] * a[k];
+        }
+    }
+    partial[i] = sum;
+}
+
+__kernel void diagonal(__global const float *a, __global float *d, const ulong n) {
+    ulong i = get_global_id(0);
+    if (i < n) {
+        d[i] = a[i*n + i];
+    }
+}
+
+// Multiplies column j of the rows x cols array a by s[j]
+__kernel void scale_cols(__global float *a,
+                         __global const float *s,
+                         const ulong rows,
+                         const ulong cols) {
+    ulong i = get_global_id(0);
+    ulong j = get_global_id(1);
+    if (i < rows && j < cols) {
+        a[i*cols + j] *= s[j];
+    }
+}
+
+// RANDOM NUMBERS
+// Counter-based, so every element is drawn independently from (seed, index)
+ulong splitmix64(ulong x) {
+    x += 0x9E3779B97F4A7C15UL;
+    x = (x ^ (x >> 30)) * 0xBF58476D1CE4E5B9UL;
+    x = (x ^ (x >> 27)) * 0x94D049BB133111EBUL;
+    return x ^ (x >> 31);
+}
+
+// Uniform on (0, 1) from the top 24 bits of x
+float unit_float(ulong x) {
+    return ((float)(x >> 40) + 0.5f) / 16777216.0f;
+}
+
+// Standard normal samples, by the Box-Muller transform
+__kernel void random_normal(__global float *a, const ulong seed, const ulong n) {
+    ulong i = get_global_id(0);
+    if (i < n) {
+        ulong h = splitmix64(seed ^ splitmix64(i));
+        float u1 = unit_float(h);
+        float u2 = unit_float(splitmix64(h));
+        a[i] = sqrt(-2.0f * log(u1)) * cos(6.28318531f * u2);
+    }
+}
+
+// CONSTRUCTION
+__kernel void eye(__global float *a, const ulong rows, const ulong cols) {
+    ulong i = get_global_id(0);
+    ulong j = get_global_id(1);
+    if (i < rows && j < cols) {
+        a[i*cols + j] = i == j ? 1.0f : 0.0f;
+    }
+}
+
+// a[i] = start + i * step
+__kernel void arange(__global float *a, const float start, const float step, const ulong n) {
+    ulong i = get_global_id(0);
+    if (i < n) {
+        a[i] = start + (float)i * step;
+    }
+}
+
+// n evenly spaced values from start to end, both included exactly
+__kernel void linspace(__global float *a, const float start, const float end, const ulong n) {
+    ulong i = get_global_id(0);
+    if (i < n) {
+        float t = n > 1 ? (float)i / (float)(n - 1) : 0.0f;
+        a[i] = i == n - 1 ? end : start + t * (end - start);
+    }
+}
+
+// The n x n array a, zeroed beforehand, gets d on its diagonal
+__kernel void set_diagonal(__global float *a, __global const float *d, const ulong n) {
+    ulong i = get_global_id(0);
+    if (i < n) {
+        a[i*n + i] = d[i];
+    }
+}
+
+// Fills a with a function of the row and column index, selected by kind; see `IndexFn`
+__kernel void index_fn(__global float *a,
+                       const ulong rows,
+                       const ulong cols,
+                       const ulong kind,
+                       const float p0,
+                       const float p1,
+                       const float p2) {
+    ulong i = get_global_id(0);
+    ulong j = get_global_id(1);
+    if (i < rows && j < cols) {
+        float fi = (float)i;
+        float fj = (float)j;
+        float x;
+        switch (kind) {
+            case 0: x = p0*fi + p1*fj + p2; break;
+            case 1: x = i >= j ? 1.0f : 0.0f; break;
+            case 2: x = i <= j ? 1.0f : 0.0f; break;
+            case 3: x = fabs(fi - fj) <= p0 ? 1.0f : 0.0f; break;
+            case 4: x = pow(p0, fabs(fi - fj)); break;
+            default: x = 1.0f / (fi + fj + 1.0f); break;
+        }
+        a[i*cols + j] = x;
+    }
+}
+
+// CONCATENATION AND TILING
+// Copies a rows x cols block between arrays with row strides src_stride and dst_stride,
+// starting src_offset and dst_offset elements into them
+__kernel void copy_block(__global const float *src,
+                         __global float *dst,
+                         const ulong rows,
+                         const ulong cols,
+                         const ulong src_offset,
+                         const ulong src_stride,
+                         const ulong dst_offset,
+                         const ulong dst_stride) {
+    ulong i = get_global_id(0);
+    ulong j = get_global_id(1);
+    if (i < rows && j < cols) {
+        dst[dst_offset + i*dst_stride + j] = src[src_offset + i*src_stride + j];
+    }
+}
+
+// The dst_rows x dst_cols array dst repeats the rows x cols array src in both directions
+__kernel void tile(__global const float *src,
+                   __global float *dst,
+                   const ulong rows,
+                   const ulong cols,
+                   const ulong dst_rows,
+                   const ulong dst_cols) {
+    ulong i = get_global_id(0);
+    ulong j = get_global_id(1);
+    if (i < dst_rows && j < dst_cols) {
+        dst[i*dst_cols + j] = src[(i % rows)*cols + j % cols];
+    }
+}
+
+// Every row of the rows x cols array src appears row_reps times in a row in dst, and every
+// column col_reps times
+__kernel void repeat(__global const float *src,
+                     __global float *dst,
+                     const ulong cols,
+                     const ulong dst_rows,
+                     const ulong dst_cols,
+                     const ulong row_reps,
+                     const ulong col_reps) {
+    ulong i = get_global_id(0);
+    ulong j = get_global_id(1);
+    if (i < dst_rows && j < dst_cols) {
+        dst[i*dst_cols + j] = src[(i / row_reps)*cols + j / col_reps];
+    }
+}
+
+// GATHER AND SCATTER
+// Element (i, j) of the rows x cols array dst comes from row k (axis 0) or column k (axis 1)
+// of src, where k = idx[i*idx_si + j*idx_sj]; zero strides reuse an index along a dimension
+__kernel void gather(__global const float *src,
+                     __global const ulong *idx,
+                     __global float *dst,
+                     const ulong rows,
+                     const ulong cols,
+                     const ulong src_cols,
+                     const ulong axis,
+                     const ulong idx_si,
+                     const ulong idx_sj) {
+    ulong i = get_global_id(0);
+    ulong j = get_global_id(1);
+    if (i < rows && j < cols) {
+        ulong k = idx[i*idx_si + j*idx_sj];
+        dst[i*cols + j] = axis == 0 ? src[k*src_cols + j] : src[i*src_cols + k];
+    }
+}
+
+// Adds element (i, j) of the rows x cols array src into row (axis 0) or column (axis 1)
+// k = idx[i*idx_si + j*idx_sj] of dst. Each work-item owns one column (axis 0) or row
+// (axis 1) of src and adds it in index order, so repeated indices need no atomics and the
+// sums are deterministic.
+__kernel void scatter_add(__global float *dst,
+                          __global const ulong *idx,
+                          __global const float *src,
+                          const ulong rows,
+                          const ulong cols,
+                          const ulong dst_cols,
+                          const ulong axis,
+                          const ulong idx_si,
+                          const ulong idx_sj) {
+    ulong line = get_global_id(0);
+    if (axis == 0 && line < cols) {
+        for (ulong i = 0; i < rows; i++) {
+            ulong k = idx[i*idx_si + line*idx_sj];
+            dst[k*dst_cols + line] += src[i*cols + line];
+        }
+    } else if (axis == 1 && line < rows) {
+        for (ulong j = 0; j < cols; j++) {
+            ulong k = idx[line*idx_si + j*idx_sj];
+            dst[line*dst_cols + k] += src[line*cols + j];
+        }
+    }
+}
+
+// Row i of the n x classes array dst is 1 in column labels[i] and 0 elsewhere
+__kernel void one_hot(__global const ulong *labels,
+                      __global float *dst,
+                      const ulong n,
+                      const ulong classes) {
+    ulong i = get_global_id(0);
+    ulong j = get_global_id(1);
+    if (i < n && j < classes) {
+        dst[i*classes + j] = labels[i] == j ? 1.0f : 0.0f;
+    }
+}
+
+__kernel void masked_fill(__global float *a,
+                          __global const float *mask,
+                          const float value,
+                          const ulong n) {
+    ulong i = get_global_id(0);
+    if (i < n && mask[i] != 0.0f) {
+        a[i] = value;
+    }
+}
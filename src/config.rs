@@ -0,0 +1,63 @@
+use ocl::flags::CommandQueueProperties;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Everything needed to rebuild a `CLBackEnd` on the same device with the same options. With
+/// the `serde` feature it can be stored alongside an experiment's other settings.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct BackendConfig {
+    /// Substring of the device name to select, e.g. "GeForce". If several devices match, the
+    /// last one found is used.
+    pub device: String,
+    /// Only consider devices on platforms whose name contains this substring
+    pub platform: Option<String>,
+    /// Options passed to the OpenCL compiler when building the kernels, e.g. "-cl-fast-relaxed-math"
+    pub build_options: Vec<String>,
+    /// Number of command queues; see `CLBackEnd::with_queues`
+    pub queues: usize,
+    /// Enables profiling on every queue and attaches a `Profiler`
+    pub profiling: bool,
+    /// Tunes the local size of `dot`, transposes and reductions on first use and remembers
+    /// the result per device; see `Autotuner`. The first large launch of each then waits for
+    /// the queue and runs the kernel several times, and the results are saved to `tuning.tsv`
+    /// in the user's cache directory. Off by default, every kernel using `default_local_size`.
+    pub autotune: bool,
+}
+
+impl Default for BackendConfig {
+    fn default() -> Self {
+        BackendConfig {
+            device: String::new(),
+            platform: None,
+            build_options: Vec::new(),
+            queues: 1,
+            profiling: false,
+            autotune: false,
+        }
+    }
+}
+
+impl BackendConfig {
+    pub fn new(device: &str) -> Self {
+        BackendConfig {
+            device: device.to_string(),
+            ..BackendConfig::default()
+        }
+    }
+
+    /// The compiler options as the single string OpenCL expects
+    pub fn build_options(&self) -> String {
+        self.build_options.join(" ")
+    }
+
+    pub fn queue_properties(&self) -> Option<CommandQueueProperties> {
+        if self.profiling {
+            Some(CommandQueueProperties::new().profiling())
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use ocl::enums::{DeviceInfo as Info, DeviceInfoResult};
+use ocl::error::Error;
+use ocl::{Device, Platform};
+
+use std::fmt;
+
+/// A snapshot of what an OpenCL device reports about itself, for callers that want to show
+/// or log the available hardware themselves
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceInfo {
+    pub name: String,
+    pub vendor: String,
+    pub platform: String,
+    /// e.g. "GPU", "CPU" or "ACCELERATOR"
+    pub device_type: String,
+    /// The OpenCL version string, e.g. "OpenCL 1.2 CUDA"
+    pub version: String,
+    pub driver_version: String,
+    pub max_compute_units: u32,
+    pub max_work_group_size: usize,
+    pub global_mem_bytes: u64,
+    pub local_mem_bytes: u64,
+    pub max_alloc_bytes: u64,
+    /// Whether the device shares memory with the host, as integrated GPUs and CPU devices do
+    pub host_unified_memory: bool,
+    pub available: bool,
+}
+
+impl DeviceInfo {
+    pub fn query(platform: &Platform, device: &Device) -> Result<Self, Error> {
+        let mut info = DeviceInfo {
+            name: device.name()?,
+            vendor: device.vendor()?,
+            platform: platform.name()?,
+            device_type: String::new(),
+            version: device.info(Info::Version)?.to_string(),
+            driver_version: device.info(Info::DriverVersion)?.to_string(),
+            max_compute_units: 0,
+            max_work_group_size: device.max_wg_size()?,
+            global_mem_bytes: 0,
+            local_mem_bytes: 0,
+            max_alloc_bytes: 0,
+            host_unified_memory: false,
+            available: device.is_available()?,
+        };
+        if let DeviceInfoResult::Type(device_type) = device.info(Info::Type)? {
+            info.device_type = format!("{:?}", device_type)
+                .trim_start_matches("DeviceType(")
+                .trim_end_matches(')')
+                .to_string();
+        }
+        if let DeviceInfoResult::MaxComputeUnits(units) = device.info(Info::MaxComputeUnits)? {
+            info.max_compute_units = units;
+        }
+        if let DeviceInfoResult::GlobalMemSize(bytes) = device.info(Info::GlobalMemSize)? {
+            info.global_mem_bytes = bytes;
+        }
+        if let DeviceInfoResult::LocalMemSize(bytes) = device.info(Info::LocalMemSize)? {
+            info.local_mem_bytes = bytes;
+        }
+        if let DeviceInfoResult::MaxMemAllocSize(bytes) = device.info(Info::MaxMemAllocSize)? {
+            info.max_alloc_bytes = bytes;
+        }
+        if let DeviceInfoResult::HostUnifiedMemory(unified) =
+            device.info(Info::HostUnifiedMemory)?
+        {
+            info.host_unified_memory = unified;
+        }
+        Ok(info)
+    }
+}
+
+impl fmt::Display for DeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}, {}) on {}: {} compute units, max work-group size {}, {} MiB global memory",
+            self.name,
+            self.device_type,
+            self.version,
+            self.platform,
+            self.max_compute_units,
+            self.max_work_group_size,
+            self.global_mem_bytes / (1 << 20)
+        )
+    }
+}
+
+/// Every device on every OpenCL platform, in platform order
+pub fn list_devices() -> Result<Vec<(Platform, Device)>, Error> {
+    let mut devices = Vec::new();
+    for platform in Platform::list() {
+        for device in Device::list_all(platform)? {
+            devices.push((platform, device));
+        }
+    }
+    Ok(devices)
+}
+
+/// `DeviceInfo` for every device on every OpenCL platform
+pub fn list_device_info() -> Result<Vec<DeviceInfo>, Error> {
+    list_devices()?
+        .iter()
+        .map(|(platform, device)| DeviceInfo::query(platform, device))
+        .collect()
+}
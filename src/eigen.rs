@@ -0,0 +1,203 @@
+use crate::indexing::*;
+use crate::kernels::*;
+use crate::opencl::*;
+
+use ocl::error::Error;
+use ocl::SpatialDims::*;
+
+// Stop sweeping once the off-diagonal part is this small relative to the whole array
+const JACOBI_TOL: f32 = 1e-6;
+const JACOBI_MAX_SWEEPS: usize = 30;
+
+/// Eigenvalues of a symmetric array in descending order, and the matching orthonormal
+/// eigenvectors as the columns of `vectors`
+#[derive(Debug, Clone)]
+pub struct SymmetricEigen {
+    pub values: Vec<f32>,
+    pub vectors: OpenCLArray,
+}
+
+/// The `k` largest singular triplets, `A ~ u diag(s) vt`, with `s` in descending order
+#[derive(Debug, Clone)]
+pub struct TruncatedSvd {
+    /// `rows x k`, with orthonormal columns
+    pub u: OpenCLArray,
+    pub s: Vec<f32>,
+    /// `k x cols`, with orthonormal rows
+    pub vt: OpenCLArray,
+}
+
+/// Settings for the randomized range finder behind `truncated_svd`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SvdOptions {
+    /// Extra sample directions beyond `k`, which make the leading triplets more accurate
+    pub oversample: usize,
+    /// Power iterations, which help when the singular values decay slowly
+    pub power_iterations: usize,
+    /// Seed of the random test matrix, so results are reproducible
+    pub seed: u64,
+}
+
+impl Default for SvdOptions {
+    fn default() -> Self {
+        SvdOptions {
+            oversample: 10,
+            power_iterations: 2,
+            seed: 0,
+        }
+    }
+}
+
+// c = a b into a new array
+fn matmul(a: &OpenCLArray, b: &OpenCLArray) -> Result<OpenCLArray, Error> {
+    let mut c = OpenCLArray::uninitialized(a.backend.clone(), a.rows, b.cols)?;
+    a.dot(b, &mut c)?;
+    Ok(c)
+}
+
+impl OpenCLArray {
+    /// An array of standard normal samples drawn on the device
+    pub fn random_normal(
+        backend: CLBackEnd,
+        rows: usize,
+        cols: usize,
+        seed: u64,
+    ) -> Result<Self, Error> {
+        let a = OpenCLArray::uninitialized(backend, rows, cols)?;
+        a.backend.enq_kernel(
+            "random_normal",
+            &[
+                Arg::Buffer(&a.v),
+                Arg::Ulong(seed),
+                Arg::Ulong(a.len() as u64),
+            ],
+            One(a.len()),
+        )?;
+        Ok(a)
+    }
+
+    /// The diagonal of a square array, as an `n x 1` array
+    pub fn diagonal(&self) -> Result<OpenCLArray, Error> {
+        assert_eq!(self.rows, self.cols);
+        let d = OpenCLArray::uninitialized(self.backend.clone(), self.rows, 1)?;
+        self.backend.enq_kernel(
+            "diagonal",
+            &[
+                Arg::Buffer(&self.v),
+                Arg::Buffer(&d.v),
+                Arg::Ulong(self.rows as u64),
+            ],
+            One(self.rows),
+        )?;
+        Ok(d)
+    }
+
+    /// Eigendecomposition of a symmetric array by parallel cyclic Jacobi rotations. Only the
+    /// eigenvalues are read back to the host.
+    pub fn eigh(&self) -> Result<SymmetricEigen, Error> {
+        assert_eq!(self.rows, self.cols);
+        let n = self.rows;
+        let a = self.contiguous_copy()?;
+        let vectors = OpenCLArray::eye(self.backend.clone(), n)?;
+
+        if n > 1 {
+            let m = n + n % 2;
+            let rot = OpenCLArray::uninitialized(self.backend.clone(), 1, m)?;
+            let total = a.norm()?;
+            let cols = Arg::Ulong(n as u64);
+
+            for _ in 0..JACOBI_MAX_SWEEPS {
+                if a.reduce("off_diagonal_partial", &[&a], &[cols])?.sqrt() <= JACOBI_TOL * total {
+                    break;
+                }
+                for round in 0..m - 1 {
+                    let (n_arg, m_arg, round) = (
+                        Arg::Ulong(n as u64),
+                        Arg::Ulong(m as u64),
+                        Arg::Ulong(round as u64),
+                    );
+                    let (a_arg, rot_arg) = (Arg::Buffer(&a.v), Arg::Buffer(&rot.v));
+                    self.backend.enq_kernel(
+                        "jacobi_angles",
+                        &[a_arg, rot_arg, n_arg, m_arg, round],
+                        One(m / 2),
+                    )?;
+                    self.backend.enq_kernel(
+                        "jacobi_rotate_rows",
+                        &[a_arg, rot_arg, n_arg, m_arg, round],
+                        Two(m / 2, n),
+                    )?;
+                    for target in &[&a, &vectors] {
+                        self.backend.enq_kernel(
+                            "jacobi_rotate_cols",
+                            &[Arg::Buffer(&target.v), rot_arg, n_arg, m_arg, round],
+                            Two(m / 2, n),
+                        )?;
+                    }
+                }
+            }
+        }
+
+        let values = a.diagonal()?.to_vec()?;
+        let mut order: Vec<usize> = (0..n).collect();
+        order.sort_by(|&i, &j| {
+            values[j]
+                .partial_cmp(&values[i])
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        Ok(SymmetricEigen {
+            values: order.iter().map(|&i| values[i]).collect(),
+            vectors: vectors.select_cols(&OpenCLIndices::vector(self.backend.clone(), &order)?)?,
+        })
+    }
+
+    /// The `k` largest singular values and vectors, by a randomized range finder followed by
+    /// an eigendecomposition of the small projected problem. Singular values far below the
+    /// largest one (by more than about `1e-3`) are only approximate.
+    pub fn truncated_svd(&self, k: usize, options: &SvdOptions) -> Result<TruncatedSvd, Error> {
+        let (m, n) = (self.rows, self.cols);
+        assert!(k <= m.min(n));
+        let l = (k + options.oversample).min(m.min(n));
+
+        // An orthonormal basis Q of the range of A Omega, refined by power iterations
+        let omega = OpenCLArray::random_normal(self.backend.clone(), n, l, options.seed)?;
+        let mut q = matmul(self, &omega)?.qr_reduced()?.0;
+        let a_t = self.t_view();
+        for _ in 0..options.power_iterations {
+            let z = matmul(&a_t, &q)?.qr_reduced()?.0;
+            q = matmul(self, &z)?.qr_reduced()?.0;
+        }
+
+        // A ~ Q B with the small l x n array B = Q^T A, and B B^T = U_b S^2 U_b^T
+        let b = matmul(&q.t_view(), self)?;
+        let b_t = b.t_view();
+        let eigen = matmul(&b, &b_t)?.eigh()?;
+        let leading: Vec<usize> = (0..k).collect();
+        let leading = OpenCLIndices::vector(self.backend.clone(), &leading)?;
+        let u_b = eigen.vectors.select_cols(&leading)?;
+        let s: Vec<f32> = eigen.values[..k].iter().map(|x| x.max(0.).sqrt()).collect();
+
+        // V = B^T U_b S^-1
+        let v = matmul(&b_t, &u_b)?;
+        let inverse = s
+            .iter()
+            .map(|&x| if x > 0. { 1. / x } else { 0. })
+            .collect();
+        let inverse = OpenCLArray::from_vec(self.backend.clone(), 1, k, inverse)?;
+        self.backend.enq_kernel(
+            "scale_cols",
+            &[
+                Arg::Buffer(&v.v),
+                Arg::Buffer(&inverse.v),
+                Arg::Ulong(n as u64),
+                Arg::Ulong(k as u64),
+            ],
+            Two(n, k),
+        )?;
+        Ok(TruncatedSvd {
+            u: matmul(&q, &u_b)?,
+            s,
+            vt: v.t()?,
+        })
+    }
+}
@@ -0,0 +1,162 @@
+use crate::kernels::*;
+use crate::opencl::*;
+use crate::profiler::*;
+
+use ocl::error::Error;
+use ocl::{Event, SpatialDims::*};
+
+/// A function of the row index `i` and column index `j` which `OpenCLArray::from_fn` fills an
+/// array with, on the device
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IndexFn {
+    /// `row * i + col * j + offset`; e.g. `col: rows as f32, row: 1.` numbers the elements in
+    /// column-major order
+    Affine { row: f32, col: f32, offset: f32 },
+    /// 1 on and below the diagonal, 0 above it
+    LowerTriangle,
+    /// 1 on and above the diagonal, 0 below it
+    UpperTriangle,
+    /// 1 within `half_width` of the diagonal, 0 elsewhere
+    Band(usize),
+    /// `rho^|i - j|`, the correlation matrix of an AR(1) process
+    Decay(f32),
+    /// `1 / (i + j + 1)`, the badly conditioned Hilbert matrix
+    Hilbert,
+}
+
+impl IndexFn {
+    // The `kind` of the `index_fn` kernel and its parameters
+    fn kernel_args(&self) -> (u64, [f32; 3]) {
+        match *self {
+            IndexFn::Affine { row, col, offset } => (0, [row, col, offset]),
+            IndexFn::LowerTriangle => (1, [0.; 3]),
+            IndexFn::UpperTriangle => (2, [0.; 3]),
+            IndexFn::Band(half_width) => (3, [half_width as f32, 0., 0.]),
+            IndexFn::Decay(rho) => (4, [rho, 0., 0.]),
+            IndexFn::Hilbert => (5, [0.; 3]),
+        }
+    }
+}
+
+impl OpenCLArray {
+    /// The same as `new`
+    pub fn zeros(backend: CLBackEnd, rows: usize, cols: usize) -> Result<Self, Error> {
+        OpenCLArray::new(backend, rows, cols)
+    }
+
+    pub fn ones(backend: CLBackEnd, rows: usize, cols: usize) -> Result<Self, Error> {
+        OpenCLArray::full(backend, rows, cols, 1.)
+    }
+
+    /// An array with every element set to `value`, filled on the device
+    pub fn full(backend: CLBackEnd, rows: usize, cols: usize, value: f32) -> Result<Self, Error> {
+        let a = OpenCLArray::uninitialized(backend, rows, cols)?;
+        if !a.is_empty() {
+            let mut event = Event::empty();
+            a.v.cmd()
+                .queue(a.backend.queue())
+                .fill(value, Some(a.len()))
+                .enew(&mut event)
+                .enq()?;
+            a.backend.profile("fill", CommandKind::Transfer, event);
+        }
+        Ok(a)
+    }
+
+    /// The `n x n` identity
+    pub fn eye(backend: CLBackEnd, n: usize) -> Result<Self, Error> {
+        OpenCLArray::identity(backend, n, n)
+    }
+
+    /// A `rows x cols` array of ones on the main diagonal and zeros elsewhere
+    pub fn identity(backend: CLBackEnd, rows: usize, cols: usize) -> Result<Self, Error> {
+        let a = OpenCLArray::uninitialized(backend, rows, cols)?;
+        a.backend.enq_kernel(
+            "eye",
+            &[
+                Arg::Buffer(&a.v),
+                Arg::Ulong(rows as u64),
+                Arg::Ulong(cols as u64),
+            ],
+            Two(rows, cols),
+        )?;
+        Ok(a)
+    }
+
+    /// A row vector of `start`, `start + step`, ... up to but excluding `stop`
+    pub fn arange(backend: CLBackEnd, start: f32, stop: f32, step: f32) -> Result<Self, Error> {
+        assert!(step != 0.);
+        let n = ((stop - start) / step).ceil().max(0.) as usize;
+        let a = OpenCLArray::uninitialized(backend, 1, n)?;
+        a.backend.enq_kernel(
+            "arange",
+            &[
+                Arg::Buffer(&a.v),
+                Arg::Float(start),
+                Arg::Float(step),
+                Arg::Ulong(n as u64),
+            ],
+            One(n),
+        )?;
+        Ok(a)
+    }
+
+    /// A row vector of `n` evenly spaced values from `start` to `end`, both included
+    pub fn linspace(backend: CLBackEnd, start: f32, end: f32, n: usize) -> Result<Self, Error> {
+        let a = OpenCLArray::uninitialized(backend, 1, n)?;
+        a.backend.enq_kernel(
+            "linspace",
+            &[
+                Arg::Buffer(&a.v),
+                Arg::Float(start),
+                Arg::Float(end),
+                Arg::Ulong(n as u64),
+            ],
+            One(n),
+        )?;
+        Ok(a)
+    }
+
+    /// The square array with the row or column vector `values` on its diagonal and zeros
+    /// elsewhere; the inverse of `diagonal`
+    pub fn diag(values: &OpenCLArray) -> Result<Self, Error> {
+        assert!(values.rows == 1 || values.cols == 1);
+        let n = values.len();
+        let a = OpenCLArray::new(values.backend.clone(), n, n)?;
+        a.backend.enq_kernel(
+            "set_diagonal",
+            &[
+                Arg::Buffer(&a.v),
+                Arg::Buffer(&values.v),
+                Arg::Ulong(n as u64),
+            ],
+            One(n),
+        )?;
+        Ok(a)
+    }
+
+    /// An array whose element at row `i`, column `j` is `f(i, j)`, computed on the device
+    pub fn from_fn(
+        backend: CLBackEnd,
+        rows: usize,
+        cols: usize,
+        f: IndexFn,
+    ) -> Result<Self, Error> {
+        let a = OpenCLArray::uninitialized(backend, rows, cols)?;
+        let (kind, [p0, p1, p2]) = f.kernel_args();
+        a.backend.enq_kernel(
+            "index_fn",
+            &[
+                Arg::Buffer(&a.v),
+                Arg::Ulong(rows as u64),
+                Arg::Ulong(cols as u64),
+                Arg::Ulong(kind),
+                Arg::Float(p0),
+                Arg::Float(p1),
+                Arg::Float(p2),
+            ],
+            Two(rows, cols),
+        )?;
+        Ok(a)
+    }
+}
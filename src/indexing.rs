@@ -0,0 +1,288 @@
+use crate::kernels::*;
+use crate::opencl::*;
+use crate::sparse::*;
+
+use ndarray::Axis;
+use ocl::error::Error;
+use ocl::{Buffer, SpatialDims::*};
+
+/// A `rows x cols` array of indices on the device, e.g. the rows of a shuffled mini-batch or
+/// the class labels of a batch. The largest index is kept so every op can check its bounds
+/// without reading the buffer back, which is why the indices can't be changed once built.
+#[derive(Debug, Clone)]
+pub struct OpenCLIndices {
+    pub backend: CLBackEnd,
+    v: Buffer<u64>,
+    rows: usize,
+    cols: usize,
+    max: Option<u64>,
+}
+
+impl OpenCLIndices {
+    pub fn from_vec(
+        backend: CLBackEnd,
+        rows: usize,
+        cols: usize,
+        data: Vec<u64>,
+    ) -> Result<Self, Error> {
+        assert_eq!(data.len(), rows * cols);
+        Ok(OpenCLIndices {
+            v: index_buffer(backend.queue(), &data)?,
+            backend,
+            rows,
+            cols,
+            max: data.iter().copied().max(),
+        })
+    }
+
+    /// A `1 x n` vector of indices
+    pub fn vector(backend: CLBackEnd, indices: &[usize]) -> Result<Self, Error> {
+        let data = indices.iter().map(|&i| i as u64).collect();
+        OpenCLIndices::from_vec(backend, 1, indices.len(), data)
+    }
+
+    /// The positions of the non-zero elements of `mask` in row-major order, as a `1 x n`
+    /// vector. Sizing the result takes reading the mask back to the host.
+    pub fn nonzero(mask: &OpenCLArray) -> Result<Self, Error> {
+        let positions = mask
+            .clone()
+            .to_vec()?
+            .iter()
+            .enumerate()
+            .filter(|(_, &x)| x != 0.)
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        OpenCLIndices::vector(mask.backend.clone(), &positions)
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    /// The device buffer, for passing to kernels as `Arg::Indices`. Writing to it would
+    /// invalidate the bounds checks.
+    pub fn buffer(&self) -> &Buffer<u64> {
+        &self.v
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows * self.cols
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_vector(&self) -> bool {
+        self.rows == 1 || self.cols == 1
+    }
+
+    pub fn to_vec(&self) -> Result<Vec<u64>, Error> {
+        read_indices(&self.v, self.backend.queue(), self.len())
+    }
+
+    fn assert_below(&self, bound: usize) {
+        assert!(
+            self.max.is_none_or(|max| (max as usize) < bound),
+            "index {} out of bounds for length {}",
+            self.max.unwrap_or_default(),
+            bound
+        );
+    }
+}
+
+// Strides into an index array of the given shape for every element of a `rows x cols` array:
+// one index per element, or a vector of one index per row (`Axis(0)`) or column (`Axis(1)`)
+fn index_strides(indices: &OpenCLIndices, axis: Axis, per_line: bool) -> (u64, u64) {
+    if !per_line {
+        (indices.cols as u64, 1)
+    } else if axis == Axis(0) {
+        (1, 0)
+    } else {
+        (0, 1)
+    }
+}
+
+impl OpenCLArray {
+    /// The rows listed in `indices`, a vector, in that order; rows may repeat
+    pub fn select_rows(&self, indices: &OpenCLIndices) -> Result<OpenCLArray, Error> {
+        assert!(indices.is_vector());
+        self.gather_lines(Axis(0), indices, (indices.len(), self.cols), true)
+    }
+
+    /// The columns listed in `indices`, a vector, in that order
+    pub fn select_cols(&self, indices: &OpenCLIndices) -> Result<OpenCLArray, Error> {
+        assert!(indices.is_vector());
+        self.gather_lines(Axis(1), indices, (self.rows, indices.len()), true)
+    }
+
+    /// An array shaped like `indices` whose element `(i, j)` is `self[indices[i][j]][j]` along
+    /// `Axis(0)` or `self[i][indices[i][j]]` along `Axis(1)`; e.g. the score of each sample's
+    /// label is `scores.gather(Axis(1), &labels)` with one label per row
+    pub fn gather(&self, axis: Axis, indices: &OpenCLIndices) -> Result<OpenCLArray, Error> {
+        if axis == Axis(0) {
+            assert_eq!(indices.cols, self.cols);
+        } else {
+            assert_eq!(indices.rows, self.rows);
+        }
+        self.gather_lines(axis, indices, (indices.rows, indices.cols), false)
+    }
+
+    /// The reverse of `gather`: adds `src(i, j)` into `self[indices[i][j]][j]` along `Axis(0)`
+    /// or `self[i][indices[i][j]]` along `Axis(1)`, where `indices` and `src` have the same
+    /// shape. Repeated indices accumulate, in index order.
+    pub fn scatter_add(
+        &mut self,
+        axis: Axis,
+        indices: &OpenCLIndices,
+        src: &OpenCLArray,
+    ) -> Result<(), Error> {
+        assert_eq!((indices.rows, indices.cols), (src.rows, src.cols));
+        if axis == Axis(0) {
+            assert_eq!(src.cols, self.cols);
+        } else {
+            assert_eq!(src.rows, self.rows);
+        }
+        self.scatter_lines(axis, indices, src, false)
+    }
+
+    /// The reverse of `select_rows`: adds row `k` of `src` into row `indices[k]`, e.g. the
+    /// gradient of an embedding lookup into the embedding table
+    pub fn scatter_add_rows(
+        &mut self,
+        indices: &OpenCLIndices,
+        src: &OpenCLArray,
+    ) -> Result<(), Error> {
+        assert!(indices.is_vector());
+        assert_eq!(indices.len(), src.rows);
+        assert_eq!(src.cols, self.cols);
+        self.scatter_lines(Axis(0), indices, src, true)
+    }
+
+    /// An `n x classes` array with a one in column `labels[i]` of row `i`, for a vector of `n`
+    /// labels
+    pub fn one_hot(labels: &OpenCLIndices, classes: usize) -> Result<OpenCLArray, Error> {
+        assert!(labels.is_vector());
+        labels.assert_below(classes);
+        let n = labels.len();
+        let a = OpenCLArray::uninitialized(labels.backend.clone(), n, classes)?;
+        a.backend.enq_kernel(
+            "one_hot",
+            &[
+                Arg::Indices(&labels.v),
+                Arg::Buffer(&a.v),
+                Arg::Ulong(n as u64),
+                Arg::Ulong(classes as u64),
+            ],
+            Two(n, classes),
+        )?;
+        Ok(a)
+    }
+
+    /// The elements where `mask`, of the same shape, is non-zero, in row-major order as a
+    /// `1 x n` vector
+    pub fn masked_select(&self, mask: &OpenCLArray) -> Result<OpenCLArray, Error> {
+        assert_eq!((mask.rows, mask.cols), (self.rows, self.cols));
+        let mut flat = self.contiguous()?;
+        flat.rows = 1;
+        flat.cols = self.len();
+        flat.select_cols(&OpenCLIndices::nonzero(mask)?)
+    }
+
+    /// The rows whose entry in the vector `mask` is non-zero
+    pub fn select_rows_where(&self, mask: &OpenCLArray) -> Result<OpenCLArray, Error> {
+        assert!(mask.is_vector());
+        assert_eq!(mask.len(), self.rows);
+        self.select_rows(&OpenCLIndices::nonzero(mask)?)
+    }
+
+    /// Sets the elements where `mask`, of the same shape, is non-zero to `value`
+    pub fn masked_fill(&mut self, mask: &OpenCLArray, value: f32) -> Result<(), Error> {
+        assert_eq!((mask.rows, mask.cols), (self.rows, self.cols));
+        assert!(!self.transposed, "fill a contiguous array");
+        let mask = mask.contiguous()?;
+        self.backend.enq_kernel(
+            "masked_fill",
+            &[
+                Arg::Buffer(&self.v),
+                Arg::Buffer(&mask.v),
+                Arg::Float(value),
+                Arg::Ulong(self.len() as u64),
+            ],
+            One(self.len()),
+        )
+    }
+
+    // Gathers whole rows or columns (`per_line`) or single elements into a new `shape` array
+    fn gather_lines(
+        &self,
+        axis: Axis,
+        indices: &OpenCLIndices,
+        shape: (usize, usize),
+        per_line: bool,
+    ) -> Result<OpenCLArray, Error> {
+        assert!(axis.index() < 2);
+        indices.assert_below(if axis == Axis(0) {
+            self.rows
+        } else {
+            self.cols
+        });
+        let src = self.contiguous()?;
+        let dst = OpenCLArray::uninitialized(self.backend.clone(), shape.0, shape.1)?;
+        let (si, sj) = index_strides(indices, axis, per_line);
+        self.backend.enq_kernel(
+            "gather",
+            &[
+                Arg::Buffer(&src.v),
+                Arg::Indices(&indices.v),
+                Arg::Buffer(&dst.v),
+                Arg::Ulong(shape.0 as u64),
+                Arg::Ulong(shape.1 as u64),
+                Arg::Ulong(src.cols as u64),
+                Arg::Ulong(axis.index() as u64),
+                Arg::Ulong(si),
+                Arg::Ulong(sj),
+            ],
+            Two(shape.0, shape.1),
+        )?;
+        Ok(dst)
+    }
+
+    fn scatter_lines(
+        &mut self,
+        axis: Axis,
+        indices: &OpenCLIndices,
+        src: &OpenCLArray,
+        per_line: bool,
+    ) -> Result<(), Error> {
+        assert!(axis.index() < 2);
+        assert!(!self.transposed, "scatter into a contiguous array");
+        indices.assert_below(if axis == Axis(0) {
+            self.rows
+        } else {
+            self.cols
+        });
+        let src = src.contiguous()?;
+        let (si, sj) = index_strides(indices, axis, per_line);
+        let lines = if axis == Axis(0) { src.cols } else { src.rows };
+        self.backend.enq_kernel(
+            "scatter_add",
+            &[
+                Arg::Buffer(&self.v),
+                Arg::Indices(&indices.v),
+                Arg::Buffer(&src.v),
+                Arg::Ulong(src.rows as u64),
+                Arg::Ulong(src.cols as u64),
+                Arg::Ulong(self.cols as u64),
+                Arg::Ulong(axis.index() as u64),
+                Arg::Ulong(si),
+                Arg::Ulong(sj),
+            ],
+            One(lines),
+        )
+    }
+}
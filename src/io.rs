@@ -0,0 +1,373 @@
+use crate::opencl::*;
+
+use ndarray::prelude::*;
+use ocl::error::Error;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize, Serializer};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, Write};
+use std::path::Path;
+
+// Reading and writing arrays in NumPy's `.npy`/`.npz` formats and in carya's own minimal
+// binary format. The free functions work on host-side `Array2<f32>`s; `OpenCLArray` gets
+// matching methods which go through them.
+
+const NPY_MAGIC: &[u8] = b"\x93NUMPY";
+const NATIVE_MAGIC: &[u8] = b"CARYA\x00";
+const NATIVE_VERSION: u16 = 1;
+
+fn invalid(msg: String) -> Error {
+    Error::from(msg)
+}
+
+// The `rows * cols` little- or big-endian `f32`s following a header. The buffer grows as the
+// data is read rather than being sized from the header up front, so a corrupt header can't
+// make it allocate more than the file holds.
+fn read_data<R: Read>(reader: R, rows: usize, cols: usize) -> Result<Vec<u8>, Error> {
+    let len = rows
+        .checked_mul(cols)
+        .and_then(|n| n.checked_mul(4))
+        .ok_or_else(|| invalid(format!("array shape ({}, {}) is too large", rows, cols)))?;
+    let mut bytes = Vec::new();
+    reader.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() < len {
+        return Err(invalid(format!(
+            "expected {} bytes of data for shape ({}, {}), found {}",
+            len,
+            rows,
+            cols,
+            bytes.len()
+        )));
+    }
+    Ok(bytes)
+}
+
+/// Writes `array` in the `.npy` format (version 1.0, little-endian `float32`, C order)
+pub fn write_npy<W: Write>(mut writer: W, array: &Array2<f32>) -> Result<(), Error> {
+    let mut header = format!(
+        "{{'descr': '<f4', 'fortran_order': False, 'shape': ({}, {}), }}",
+        array.nrows(),
+        array.ncols()
+    );
+    // Pad with spaces so the data starts on a 64-byte boundary, ending in a newline
+    let unpadded = NPY_MAGIC.len() + 2 + 2 + header.len() + 1;
+    header.push_str(&" ".repeat((64 - unpadded % 64) % 64));
+    header.push('\n');
+
+    writer.write_all(NPY_MAGIC)?;
+    writer.write_all(&[1, 0])?;
+    writer.write_all(&(header.len() as u16).to_le_bytes())?;
+    writer.write_all(header.as_bytes())?;
+    for x in array.iter() {
+        writer.write_all(&x.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+// Finds `'key':` in an `.npy` header dict and returns the text after it
+fn header_value<'h>(header: &'h str, key: &str) -> Result<&'h str, Error> {
+    for quote in &["'", "\""] {
+        let pattern = format!("{}{}{}", quote, key, quote);
+        if let Some(start) = header.find(&pattern) {
+            let rest = header[start + pattern.len()..].trim_start();
+            if let Some(rest) = rest.strip_prefix(':') {
+                return Ok(rest.trim_start());
+            }
+        }
+    }
+    Err(invalid(format!(
+        "npy header is missing '{}': {}",
+        key, header
+    )))
+}
+
+/// Reads a two-dimensional `float32` array in the `.npy` format. Both C and Fortran order
+/// and either byte order are accepted; any other dtype or number of dimensions is an error.
+pub fn read_npy<R: Read>(mut reader: R) -> Result<Array2<f32>, Error> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic[..6] != NPY_MAGIC {
+        return Err(invalid("not an npy file".to_string()));
+    }
+    let header_len = match magic[6] {
+        1 => {
+            let mut len = [0u8; 2];
+            reader.read_exact(&mut len)?;
+            u16::from_le_bytes(len) as usize
+        }
+        2 | 3 => {
+            let mut len = [0u8; 4];
+            reader.read_exact(&mut len)?;
+            u32::from_le_bytes(len) as usize
+        }
+        version => return Err(invalid(format!("unsupported npy version {}", version))),
+    };
+    // Read like the data, so a corrupt length can't make it allocate more than the file holds
+    let mut header = Vec::new();
+    reader
+        .by_ref()
+        .take(header_len as u64)
+        .read_to_end(&mut header)?;
+    if header.len() < header_len {
+        return Err(invalid(format!(
+            "expected an npy header of {} bytes, found {}",
+            header_len,
+            header.len()
+        )));
+    }
+    let header = String::from_utf8_lossy(&header);
+
+    let descr = header_value(&header, "descr")?;
+    let big_endian = if descr.starts_with("'<f4'") || descr.starts_with("\"<f4\"") {
+        false
+    } else if descr.starts_with("'>f4'") || descr.starts_with("\">f4\"") {
+        true
+    } else {
+        let dtype = descr.split(',').next().unwrap_or(descr);
+        return Err(invalid(format!(
+            "expected dtype float32 ('<f4'), found {}",
+            dtype
+        )));
+    };
+
+    let fortran_order = header_value(&header, "fortran_order")?.starts_with("True");
+
+    let shape = header_value(&header, "shape")?;
+    let shape = shape
+        .strip_prefix('(')
+        .and_then(|s| s.split(')').next())
+        .ok_or_else(|| invalid(format!("malformed npy shape in {}", header)))?;
+    let dims = shape
+        .split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+        .map(|d| d.parse::<usize>())
+        .collect::<Result<Vec<usize>, _>>()
+        .map_err(|e| invalid(format!("malformed npy shape ({}): {}", shape, e)))?;
+    let (rows, cols) = match dims[..] {
+        [rows, cols] => (rows, cols),
+        _ => {
+            return Err(invalid(format!(
+                "expected a two-dimensional array, found shape ({})",
+                shape
+            )))
+        }
+    };
+
+    let bytes = read_data(reader, rows, cols)?;
+    let data: Vec<f32> = bytes
+        .chunks_exact(4)
+        .map(|b| {
+            let b = [b[0], b[1], b[2], b[3]];
+            if big_endian {
+                f32::from_be_bytes(b)
+            } else {
+                f32::from_le_bytes(b)
+            }
+        })
+        .collect();
+
+    let array = if fortran_order {
+        Array::from_shape_vec((rows, cols).f(), data)
+    } else {
+        Array::from_shape_vec((rows, cols), data)
+    };
+    array.map_err(|e| invalid(e.to_string()))
+}
+
+pub fn save_npy<P: AsRef<Path>>(path: P, array: &Array2<f32>) -> Result<(), Error> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_npy(&mut writer, array)?;
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn load_npy<P: AsRef<Path>>(path: P) -> Result<Array2<f32>, Error> {
+    read_npy(BufReader::new(File::open(path)?))
+}
+
+/// Writes named arrays as an uncompressed `.npz` archive, loadable with `numpy.load`
+pub fn write_npz<'a, W, I>(writer: W, arrays: I) -> Result<(), Error>
+where
+    W: Write + Seek,
+    I: IntoIterator<Item = (&'a str, &'a Array2<f32>)>,
+{
+    let mut zip = ZipWriter::new(writer);
+    let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+    for (name, array) in arrays {
+        zip.start_file(format!("{}.npy", name), options)
+            .map_err(|e| invalid(e.to_string()))?;
+        write_npy(&mut zip, array)?;
+    }
+    zip.finish().map_err(|e| invalid(e.to_string()))?;
+    Ok(())
+}
+
+/// Reads every array in an `.npz` archive (compressed or not), keyed by name without the
+/// `.npy` extension
+pub fn read_npz<R: Read + Seek>(reader: R) -> Result<BTreeMap<String, Array2<f32>>, Error> {
+    let mut zip = ZipArchive::new(reader).map_err(|e| invalid(e.to_string()))?;
+    let mut arrays = BTreeMap::new();
+    for idx in 0..zip.len() {
+        let file = zip.by_index(idx).map_err(|e| invalid(e.to_string()))?;
+        let name = file.name().trim_end_matches(".npy").to_string();
+        let array = read_npy(file).map_err(|e| invalid(format!("{}: {}", name, e)))?;
+        arrays.insert(name, array);
+    }
+    Ok(arrays)
+}
+
+pub fn save_npz<'a, P, I>(path: P, arrays: I) -> Result<(), Error>
+where
+    P: AsRef<Path>,
+    I: IntoIterator<Item = (&'a str, &'a Array2<f32>)>,
+{
+    write_npz(BufWriter::new(File::create(path)?), arrays)
+}
+
+pub fn load_npz<P: AsRef<Path>>(path: P) -> Result<BTreeMap<String, Array2<f32>>, Error> {
+    read_npz(BufReader::new(File::open(path)?))
+}
+
+/// Writes `array` in carya's native format: the magic bytes `CARYA\0`, a little-endian `u16`
+/// format version, `u64` rows and cols, then the elements as little-endian `f32`s in row-major
+/// order
+pub fn write_native<W: Write>(mut writer: W, array: &Array2<f32>) -> Result<(), Error> {
+    writer.write_all(NATIVE_MAGIC)?;
+    writer.write_all(&NATIVE_VERSION.to_le_bytes())?;
+    writer.write_all(&(array.nrows() as u64).to_le_bytes())?;
+    writer.write_all(&(array.ncols() as u64).to_le_bytes())?;
+    for x in array.iter() {
+        writer.write_all(&x.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+pub fn read_native<R: Read>(mut reader: R) -> Result<Array2<f32>, Error> {
+    let mut magic = [0u8; 6];
+    reader.read_exact(&mut magic)?;
+    if magic != NATIVE_MAGIC {
+        return Err(invalid("not a carya array file".to_string()));
+    }
+    let mut version = [0u8; 2];
+    reader.read_exact(&mut version)?;
+    let version = u16::from_le_bytes(version);
+    if version != NATIVE_VERSION {
+        return Err(invalid(format!(
+            "unsupported carya format version {}",
+            version
+        )));
+    }
+    let mut dims = [0u8; 16];
+    reader.read_exact(&mut dims)?;
+    let rows = u64::from_le_bytes([
+        dims[0], dims[1], dims[2], dims[3], dims[4], dims[5], dims[6], dims[7],
+    ]) as usize;
+    let cols = u64::from_le_bytes([
+        dims[8], dims[9], dims[10], dims[11], dims[12], dims[13], dims[14], dims[15],
+    ]) as usize;
+
+    let bytes = read_data(reader, rows, cols)?;
+    let data = bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect();
+    Array::from_shape_vec((rows, cols), data).map_err(|e| invalid(e.to_string()))
+}
+
+impl OpenCLArray {
+    pub fn save_npy<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        save_npy(path, &self.clone().to_array()?)
+    }
+
+    pub fn load_npy<P: AsRef<Path>>(backend: CLBackEnd, path: P) -> Result<Self, Error> {
+        OpenCLArray::from_array(backend, &load_npy(path)?)
+    }
+
+    /// Saves named device arrays, e.g. a model's weights, as one `.npz` archive
+    pub fn save_npz<P: AsRef<Path>>(path: P, arrays: &[(&str, &OpenCLArray)]) -> Result<(), Error> {
+        let host = arrays
+            .iter()
+            .map(|&(name, array)| Ok((name, array.clone().to_array()?)))
+            .collect::<Result<Vec<_>, Error>>()?;
+        save_npz(path, host.iter().map(|(name, array)| (*name, array)))
+    }
+
+    pub fn load_npz<P: AsRef<Path>>(
+        backend: CLBackEnd,
+        path: P,
+    ) -> Result<BTreeMap<String, OpenCLArray>, Error> {
+        load_npz(path)?
+            .into_iter()
+            .map(|(name, array)| Ok((name, OpenCLArray::from_array(backend.clone(), &array)?)))
+            .collect()
+    }
+
+    /// Saves the array in carya's native format (see `write_native`)
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        write_native(&mut writer, &self.clone().to_array()?)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(backend: CLBackEnd, path: P) -> Result<Self, Error> {
+        let array = read_native(BufReader::new(File::open(path)?))?;
+        OpenCLArray::from_array(backend, &array)
+    }
+}
+
+/// A host-side copy of an array's shape and contents. With the `serde` feature it is what an
+/// `OpenCLArray` serializes as, and what to deserialize into before moving the data back onto
+/// a backend with `to_device`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ArraySnapshot {
+    pub rows: usize,
+    pub cols: usize,
+    /// The elements in row-major order
+    pub data: Vec<f32>,
+}
+
+impl ArraySnapshot {
+    pub fn to_array(&self) -> Result<Array2<f32>, Error> {
+        Array::from_shape_vec((self.rows, self.cols), self.data.clone())
+            .map_err(|e| invalid(e.to_string()))
+    }
+
+    pub fn to_device(&self, backend: CLBackEnd) -> Result<OpenCLArray, Error> {
+        if self.data.len() != self.rows * self.cols {
+            return Err(invalid(format!(
+                "snapshot of shape ({}, {}) holds {} elements",
+                self.rows,
+                self.cols,
+                self.data.len()
+            )));
+        }
+        OpenCLArray::from_vec(backend, self.rows, self.cols, self.data.clone())
+    }
+}
+
+impl OpenCLArray {
+    /// Copies the array back to the host, blocking until its pending work has completed
+    pub fn snapshot(&self) -> Result<ArraySnapshot, Error> {
+        Ok(ArraySnapshot {
+            rows: self.rows,
+            cols: self.cols,
+            data: self.clone().to_vec()?,
+        })
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for OpenCLArray {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.snapshot()
+            .map_err(serde::ser::Error::custom)?
+            .serialize(serializer)
+    }
+}
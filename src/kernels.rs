@@ -0,0 +1,304 @@
+use log::{debug, warn};
+use ocl::builders::ProQueBuilder;
+use ocl::enums::{
+    DeviceInfo, KernelWorkGroupInfo, KernelWorkGroupInfoResult, ProgramInfo, ProgramInfoResult,
+};
+use ocl::error::Error;
+use ocl::flags::CommandQueueProperties;
+use ocl::{Buffer, Device, Event, Kernel, ProQue, Program, Queue, SpatialDims};
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// A kernel argument, given in the order it appears in the kernel's signature
+#[derive(Debug, Clone, Copy)]
+pub enum Arg<'a> {
+    Buffer(&'a Buffer<f32>),
+    /// Index data, e.g. the row pointers of a sparse matrix
+    Indices(&'a Buffer<u64>),
+    Float(f32),
+    Ulong(u64),
+}
+
+impl<'a> Arg<'a> {
+    fn layout(&self) -> char {
+        match self {
+            Arg::Buffer(_) => 'b',
+            Arg::Indices(_) => 'i',
+            Arg::Float(_) => 'f',
+            Arg::Ulong(_) => 'u',
+        }
+    }
+}
+
+/// Rounds `n` up to the next multiple of `multiple`
+pub fn round_up(n: usize, multiple: usize) -> usize {
+    n.div_ceil(multiple) * multiple
+}
+
+/// The local size used when an op doesn't ask for one: 256 work-items in 1-D and square
+/// (or cubic) tiles in 2-D and 3-D, shrunk by powers of two to fit `max_wg_size`
+pub fn default_local_size(gws: SpatialDims, max_wg_size: usize) -> SpatialDims {
+    let limit = max_wg_size.clamp(1, 256);
+    let mut side = 1;
+    match gws {
+        SpatialDims::One(..) => {
+            while side * 2 <= limit {
+                side *= 2;
+            }
+            SpatialDims::One(side)
+        }
+        SpatialDims::Two(..) => {
+            while (side * 2) * (side * 2) <= limit {
+                side *= 2;
+            }
+            SpatialDims::Two(side, side)
+        }
+        SpatialDims::Three(..) => {
+            while (side * 2) * (side * 2) * (side * 2) <= limit {
+                side *= 2;
+            }
+            SpatialDims::Three(side, side, side)
+        }
+        SpatialDims::Unspecified => SpatialDims::Unspecified,
+    }
+}
+
+/// Rounds every dimension of `gws` up to a multiple of the matching dimension of `lws`. The
+/// extra work-items are discarded by the bounds guard every kernel starts with.
+pub fn round_global_size(gws: SpatialDims, lws: SpatialDims) -> SpatialDims {
+    match (gws, lws) {
+        (SpatialDims::One(x), SpatialDims::One(lx)) => SpatialDims::One(round_up(x, lx)),
+        (SpatialDims::Two(x, y), SpatialDims::Two(lx, ly)) => {
+            SpatialDims::Two(round_up(x, lx), round_up(y, ly))
+        }
+        (SpatialDims::Three(x, y, z), SpatialDims::Three(lx, ly, lz)) => {
+            SpatialDims::Three(round_up(x, lx), round_up(y, ly), round_up(z, lz))
+        }
+        _ => gws,
+    }
+}
+
+#[derive(Debug)]
+struct CachedKernel {
+    kernel: Kernel,
+    // The largest work-group this kernel can be launched with on the backend's device
+    max_wg_size: usize,
+}
+
+/// Every `Kernel` built so far by a backend, keyed by kernel name and argument layout. Kernels
+/// are only built the first time an op is called; afterwards the cached object just has its
+/// arguments rebound before being enqueued.
+#[derive(Debug, Default)]
+pub struct KernelCache {
+    kernels: Mutex<HashMap<String, CachedKernel>>,
+}
+
+impl KernelCache {
+    pub fn new() -> Self {
+        KernelCache::default()
+    }
+
+    /// Binds `args` to the kernel `name` and enqueues it on `queue` over `gws`, storing the
+    /// launch's event in `event`. The global size is rounded up to a multiple of `lws`, or of
+    /// `default_local_size` if `None`. Nothing is enqueued (and `event` stays empty) if `gws`
+    /// is empty.
+    #[allow(clippy::too_many_arguments)]
+    pub fn enq(
+        &self,
+        proque: &ProQue,
+        queue: &Queue,
+        name: &str,
+        args: &[Arg],
+        gws: SpatialDims,
+        lws: Option<SpatialDims>,
+        event: &mut Event,
+    ) -> Result<(), Error> {
+        if gws.to_len() == 0 {
+            return Ok(());
+        }
+
+        let key: String = name
+            .chars()
+            .chain(std::iter::once(':'))
+            .chain(args.iter().map(Arg::layout))
+            .collect();
+
+        let mut kernels = self.kernels.lock().unwrap();
+        let cached = match kernels.entry(key) {
+            Entry::Occupied(entry) => {
+                let cached = entry.into_mut();
+                for (idx, arg) in args.iter().enumerate() {
+                    match *arg {
+                        Arg::Buffer(buffer) => cached.kernel.set_arg(idx as u32, buffer)?,
+                        Arg::Indices(buffer) => cached.kernel.set_arg(idx as u32, buffer)?,
+                        Arg::Float(x) => cached.kernel.set_arg(idx as u32, x)?,
+                        Arg::Ulong(x) => cached.kernel.set_arg(idx as u32, x)?,
+                    }
+                }
+                cached
+            }
+            Entry::Vacant(entry) => {
+                let mut builder = proque.kernel_builder(name);
+                for arg in args {
+                    match *arg {
+                        Arg::Buffer(buffer) => builder.arg(buffer),
+                        Arg::Indices(buffer) => builder.arg(buffer),
+                        Arg::Float(x) => builder.arg(x),
+                        Arg::Ulong(x) => builder.arg(x),
+                    };
+                }
+                let kernel = builder.build()?;
+                let max_wg_size =
+                    match kernel.wg_info(proque.device(), KernelWorkGroupInfo::WorkGroupSize)? {
+                        KernelWorkGroupInfoResult::WorkGroupSize(size) => size,
+                        _ => proque.device().max_wg_size()?,
+                    };
+                entry.insert(CachedKernel {
+                    kernel,
+                    max_wg_size,
+                })
+            }
+        };
+
+        let lws = lws.unwrap_or_else(|| default_local_size(gws, cached.max_wg_size));
+        unsafe {
+            cached
+                .kernel
+                .cmd()
+                .queue(queue)
+                .global_work_size(round_global_size(gws, lws))
+                .local_work_size(lws)
+                .enew(event)
+                .enq()?;
+        }
+        Ok(())
+    }
+
+    /// The number of distinct kernels built so far
+    pub fn len(&self) -> usize {
+        self.kernels.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Directory holding compiled program binaries: `$CARYA_CACHE_DIR`, or `carya/` under
+/// `$XDG_CACHE_HOME` or `~/.cache`. Setting `CARYA_CACHE_DIR` to an empty string disables
+/// the on-disk cache.
+pub fn program_cache_dir() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os("CARYA_CACHE_DIR") {
+        return if dir.is_empty() {
+            None
+        } else {
+            Some(PathBuf::from(dir))
+        };
+    }
+    env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .map(|dir| dir.join("carya"))
+}
+
+// FNV-1a, used instead of `DefaultHasher` so cache keys are stable across Rust releases
+fn fnv1a(bytes: &[u8], mut hash: u64) -> u64 {
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// Cache key for `src` compiled with `build_options` for `device`; a driver update
+/// invalidates it
+pub fn program_cache_key(device: &Device, src: &str, build_options: &str) -> Result<u64, Error> {
+    let mut hash = 0xcbf2_9ce4_8422_2325;
+    for part in &[
+        device.name()?,
+        device.vendor()?,
+        device.info(DeviceInfo::Version)?.to_string(),
+        device.info(DeviceInfo::DriverVersion)?.to_string(),
+        build_options.to_string(),
+    ] {
+        hash = fnv1a(part.as_bytes(), hash);
+        hash = fnv1a(&[0], hash);
+    }
+    Ok(fnv1a(src.as_bytes(), hash))
+}
+
+/// Builds a `ProQue` for `src` on `device` with the compiler options `build_options`, loading
+/// the program binary from the on-disk cache when one exists and storing it after compiling
+/// from source otherwise. A stale or corrupt cache entry just falls back to compiling from
+/// source.
+pub fn build_proque_cached(
+    device: Device,
+    src: &str,
+    build_options: &str,
+    queue_properties: Option<CommandQueueProperties>,
+) -> Result<ProQue, Error> {
+    let mut from_src = Program::builder();
+    from_src.src(src).cmplr_opt(build_options);
+
+    let path = match program_cache_dir() {
+        Some(dir) => dir.join(format!(
+            "{:016x}.bin",
+            program_cache_key(&device, src, build_options)?
+        )),
+        None => {
+            return proque_builder(device, queue_properties)
+                .prog_bldr(from_src)
+                .build()
+        }
+    };
+
+    if let Ok(binary) = fs::read(&path) {
+        let binaries = [&binary[..]];
+        let mut program = Program::builder();
+        program.binaries(&binaries).cmplr_opt(build_options);
+        if let Ok(proque) = proque_builder(device, queue_properties)
+            .prog_bldr(program)
+            .build()
+        {
+            debug!("Loaded program binary from {}", path.display());
+            return Ok(proque);
+        }
+        warn!("Ignoring unusable program binary at {}", path.display());
+    }
+
+    let proque = proque_builder(device, queue_properties)
+        .prog_bldr(from_src)
+        .build()?;
+    if let Ok(ProgramInfoResult::Binaries(binaries)) = proque.program().info(ProgramInfo::Binaries)
+    {
+        if let Some(binary) = binaries.first().filter(|binary| !binary.is_empty()) {
+            // Failing to write the cache only costs a recompile next time
+            let written = path
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| fs::write(&path, binary));
+            match written {
+                Ok(()) => debug!("Cached program binary at {}", path.display()),
+                Err(e) => warn!("Couldn't cache program binary at {}: {}", path.display(), e),
+            }
+        }
+    }
+    Ok(proque)
+}
+
+fn proque_builder<'b>(
+    device: Device,
+    queue_properties: Option<CommandQueueProperties>,
+) -> ProQueBuilder<'b> {
+    let mut builder = ProQue::builder();
+    builder.device(device);
+    if let Some(properties) = queue_properties {
+        builder.queue_properties(properties);
+    }
+    builder
+}
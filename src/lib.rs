@@ -2,16 +2,48 @@
 #[macro_use]
 extern crate serial_test;
 
+pub mod blas;
+pub mod config;
+pub mod device;
+pub mod eigen;
+pub mod fill;
+pub mod indexing;
+pub mod io;
+pub mod kernels;
+pub mod linalg;
+pub mod multi;
 pub mod opencl;
+pub mod pinned;
+pub mod pool;
+pub mod profiler;
+pub mod shape;
+pub mod solvers;
+pub mod sparse;
+#[cfg(test)]
+mod test_harness;
+#[cfg(test)]
 mod test_opencl;
-use crate::opencl::*;
-
-use ndarray::prelude::*;
-use ocl::Error;
+pub mod tuning;
 
 pub mod prelude {
     #[cfg(feature = "cuda_through_accel")]
-    pub use carya_accel::*;    
+    pub use carya_accel::*;
 
+    pub use crate::blas::*;
+    pub use crate::config::*;
+    pub use crate::device::*;
+    pub use crate::eigen::*;
+    pub use crate::fill::*;
+    pub use crate::indexing::*;
+    pub use crate::io::*;
+    pub use crate::kernels::*;
+    pub use crate::linalg::*;
+    pub use crate::multi::*;
     pub use crate::opencl::*;
+    pub use crate::pinned::*;
+    pub use crate::pool::*;
+    pub use crate::profiler::*;
+    pub use crate::solvers::*;
+    pub use crate::sparse::*;
+    pub use crate::tuning::*;
 }
@@ -0,0 +1,295 @@
+use crate::blas::*;
+use crate::kernels::*;
+use crate::opencl::*;
+use crate::sparse::*;
+
+use ocl::error::Error;
+use ocl::{Buffer, SpatialDims::*};
+
+// Dense factorizations and solvers. Everything runs on the device, one column at a time, so
+// no call blocks until a result is read back (`det` being the exception). None of them check
+// for singular or indefinite input: the affected entries just come out as inf or NaN.
+
+/// An LU factorization with partial pivoting, `P A = L U`
+#[derive(Debug, Clone)]
+pub struct LuDecomposition {
+    /// `L` below the diagonal, its unit diagonal implied, and `U` on and above it
+    pub lu: OpenCLArray,
+    /// `n` indices, entry `i` being the row of `A` which ended up in row `i` of `lu`
+    pub perm: Buffer<u64>,
+    // `1 x 1` array holding the sign of the row permutation
+    sign: OpenCLArray,
+}
+
+impl LuDecomposition {
+    /// The unit lower triangular factor
+    pub fn l(&self) -> Result<OpenCLArray, Error> {
+        let l = self.lu.deep_clone()?;
+        l.mask_triangle(true, true)?;
+        Ok(l)
+    }
+
+    /// The upper triangular factor
+    pub fn u(&self) -> Result<OpenCLArray, Error> {
+        let u = self.lu.deep_clone()?;
+        u.mask_triangle(false, false)?;
+        Ok(u)
+    }
+
+    /// The row permutation, read back from the device
+    pub fn permutation(&self) -> Result<Vec<u64>, Error> {
+        read_indices(&self.perm, self.lu.backend.queue(), self.lu.rows)
+    }
+
+    /// Applies the row permutation to `b`, giving `P b`
+    pub fn permute(&self, b: &OpenCLArray) -> Result<OpenCLArray, Error> {
+        assert_eq!(b.rows, self.lu.rows);
+        let b = b.contiguous()?;
+        let pb = OpenCLArray::uninitialized(b.backend.clone(), b.rows, b.cols)?;
+        b.backend.enq_kernel(
+            "permute_rows",
+            &[
+                Arg::Buffer(&b.v),
+                Arg::Buffer(&pb.v),
+                Arg::Indices(&self.perm),
+                Arg::Ulong(b.rows as u64),
+                Arg::Ulong(b.cols as u64),
+            ],
+            Two(b.rows, b.cols),
+        )?;
+        Ok(pb)
+    }
+
+    /// Solves `A x = b` for every column of `b`
+    pub fn solve(&self, b: &OpenCLArray) -> Result<OpenCLArray, Error> {
+        let y = self.lu.solve_triangular(&self.permute(b)?, true, true)?;
+        self.lu.solve_triangular(&y, false, false)
+    }
+
+    pub fn det(&self) -> Result<f32, Error> {
+        let det = OpenCLArray::uninitialized(self.lu.backend.clone(), 1, 1)?;
+        self.lu.backend.enq_kernel(
+            "lu_det",
+            &[
+                Arg::Buffer(&self.lu.v),
+                Arg::Buffer(&self.sign.v),
+                Arg::Buffer(&det.v),
+                Arg::Ulong(self.lu.rows as u64),
+            ],
+            One(1),
+        )?;
+        Ok(det.to_vec()?[0])
+    }
+}
+
+impl OpenCLArray {
+    // Zeroes the entries above (`lower`) or below the diagonal, optionally setting the
+    // diagonal to one
+    fn mask_triangle(&self, lower: bool, unit_diagonal: bool) -> Result<(), Error> {
+        let dims = Two(self.rows, self.cols);
+        let (rows, cols) = (Arg::Ulong(self.rows as u64), Arg::Ulong(self.cols as u64));
+        if lower {
+            let unit = Arg::Ulong(unit_diagonal as u64);
+            self.backend
+                .enq_kernel("tril", &[Arg::Buffer(&self.v), rows, cols, unit], dims)
+        } else {
+            assert!(!unit_diagonal);
+            self.backend
+                .enq_kernel("triu", &[Arg::Buffer(&self.v), rows, cols], dims)
+        }
+    }
+
+    /// LU factorization of a square array with partial pivoting
+    pub fn lu(&self) -> Result<LuDecomposition, Error> {
+        assert_eq!(self.rows, self.cols);
+        let n = self.rows;
+        let lu = self.contiguous_copy()?;
+        let perm = Buffer::<u64>::builder()
+            .queue(self.backend.queue().clone())
+            .len(n.max(1))
+            .copy_host_slice(&(0..n.max(1) as u64).collect::<Vec<_>>())
+            .build()?;
+        let sign = OpenCLArray::from_vec(self.backend.clone(), 1, 1, vec![1.])?;
+
+        for k in 0..n {
+            let (a, n_arg, k_arg) = (
+                Arg::Buffer(&lu.v),
+                Arg::Ulong(n as u64),
+                Arg::Ulong(k as u64),
+            );
+            self.backend.enq_kernel(
+                "lu_pivot",
+                &[a, Arg::Indices(&perm), Arg::Buffer(&sign.v), n_arg, k_arg],
+                One(1),
+            )?;
+            let rest = n - k - 1;
+            self.backend
+                .enq_kernel("scale_below_diagonal", &[a, n_arg, k_arg], One(rest))?;
+            self.backend
+                .enq_kernel("lu_update", &[a, n_arg, k_arg], Two(rest, rest))?;
+        }
+
+        Ok(LuDecomposition { lu, perm, sign })
+    }
+
+    /// The lower triangular `L` with `A = L L^T`, for a symmetric positive definite array
+    pub fn cholesky(&self) -> Result<OpenCLArray, Error> {
+        assert_eq!(self.rows, self.cols);
+        let n = self.rows;
+        let l = self.contiguous_copy()?;
+
+        for k in 0..n {
+            let (a, n_arg, k_arg) = (
+                Arg::Buffer(&l.v),
+                Arg::Ulong(n as u64),
+                Arg::Ulong(k as u64),
+            );
+            let rest = n - k - 1;
+            self.backend
+                .enq_kernel("cholesky_diagonal", &[a, n_arg, k_arg], One(1))?;
+            self.backend
+                .enq_kernel("scale_below_diagonal", &[a, n_arg, k_arg], One(rest))?;
+            self.backend
+                .enq_kernel("cholesky_update", &[a, n_arg, k_arg], Two(rest, rest))?;
+        }
+        l.mask_triangle(true, false)?;
+        Ok(l)
+    }
+
+    // Reduces a copy of the array to upper triangular R with Householder reflections,
+    // returning it alongside the reflection vector used for each column
+    fn householder(&self) -> Result<(OpenCLArray, Vec<OpenCLArray>), Error> {
+        let (m, n) = (self.rows, self.cols);
+        let r = self.contiguous_copy()?;
+        let mut reflectors = Vec::new();
+
+        for k in 0..n.min(m.saturating_sub(1)) {
+            let v = OpenCLArray::uninitialized(self.backend.clone(), 1, m)?;
+            let (rows, cols, k_arg) = (
+                Arg::Ulong(m as u64),
+                Arg::Ulong(n as u64),
+                Arg::Ulong(k as u64),
+            );
+            self.backend.enq_kernel(
+                "householder_vector",
+                &[Arg::Buffer(&r.v), Arg::Buffer(&v.v), rows, cols, k_arg],
+                One(1),
+            )?;
+            self.backend.enq_kernel(
+                "householder_left",
+                &[Arg::Buffer(&r.v), Arg::Buffer(&v.v), rows, cols, k_arg],
+                One(n - k),
+            )?;
+            reflectors.push(v);
+        }
+        r.mask_triangle(false, false)?;
+        Ok((r, reflectors))
+    }
+
+    /// Householder QR factorization, returning the orthogonal `rows x rows` array `Q` and the
+    /// upper triangular `rows x cols` array `R` with `A = Q R`
+    pub fn qr(&self) -> Result<(OpenCLArray, OpenCLArray), Error> {
+        let m = self.rows;
+        let (r, reflectors) = self.householder()?;
+        let q = OpenCLArray::eye(self.backend.clone(), m)?;
+        for (k, v) in reflectors.iter().enumerate() {
+            self.backend.enq_kernel(
+                "householder_right",
+                &[
+                    Arg::Buffer(&q.v),
+                    Arg::Buffer(&v.v),
+                    Arg::Ulong(m as u64),
+                    Arg::Ulong(k as u64),
+                ],
+                One(m),
+            )?;
+        }
+        Ok((q, r))
+    }
+
+    /// The reduced QR factorization: `Q` is `rows x l` with orthonormal columns and `R` is
+    /// `l x cols`, where `l = min(rows, cols)`
+    pub fn qr_reduced(&self) -> Result<(OpenCLArray, OpenCLArray), Error> {
+        let (m, n) = (self.rows, self.cols);
+        let l = m.min(n);
+        let (r, reflectors) = self.householder()?;
+
+        // Q = H_0 H_1 ... applied to the first l columns of the identity, last reflector first
+        let q = OpenCLArray::identity(self.backend.clone(), m, l)?;
+        for (k, v) in reflectors.iter().enumerate().rev() {
+            self.backend.enq_kernel(
+                "householder_left",
+                &[
+                    Arg::Buffer(&q.v),
+                    Arg::Buffer(&v.v),
+                    Arg::Ulong(m as u64),
+                    Arg::Ulong(l as u64),
+                    Arg::Ulong(k as u64),
+                ],
+                One(l.saturating_sub(k)),
+            )?;
+        }
+
+        if l == m {
+            return Ok((q, r));
+        }
+        let top = OpenCLArray::uninitialized(self.backend.clone(), l, n)?;
+        copy_region(&r, 0, &top, 0, l * n)?;
+        Ok((q, top))
+    }
+
+    /// Solves `T x = b` for every column of `b`, where `T` is this array's lower (or upper)
+    /// triangle. Entries on the other side of the diagonal are ignored, as is the diagonal
+    /// itself if `unit_diagonal`.
+    pub fn solve_triangular(
+        &self,
+        b: &OpenCLArray,
+        lower: bool,
+        unit_diagonal: bool,
+    ) -> Result<OpenCLArray, Error> {
+        assert_eq!(self.rows, self.cols);
+        assert_eq!(b.rows, self.rows);
+        let (n, r) = (self.rows, b.cols);
+        let t = self.contiguous()?;
+        let x = b.contiguous_copy()?;
+
+        for step in 0..n {
+            let k = if lower { step } else { n - 1 - step };
+            let (start, end) = if lower { (k + 1, n) } else { (0, k) };
+            let args = [
+                Arg::Buffer(&t.v),
+                Arg::Buffer(&x.v),
+                Arg::Ulong(n as u64),
+                Arg::Ulong(r as u64),
+                Arg::Ulong(k as u64),
+            ];
+            if !unit_diagonal {
+                self.backend.enq_kernel("trsm_divide", &args, One(r))?;
+            }
+            let mut args = args.to_vec();
+            args.extend(&[Arg::Ulong(start as u64), Arg::Ulong(end as u64)]);
+            self.backend
+                .enq_kernel("trsm_eliminate", &args, Two(end - start, r))?;
+        }
+        Ok(x)
+    }
+
+    /// Solves `A x = b` for every column of `b` through an LU factorization of this array
+    pub fn solve(&self, b: &OpenCLArray) -> Result<OpenCLArray, Error> {
+        self.lu()?.solve(b)
+    }
+
+    pub fn inv(&self) -> Result<OpenCLArray, Error> {
+        let identity = OpenCLArray::eye(self.backend.clone(), self.rows)?;
+        self.solve(&identity)
+    }
+
+    /// The determinant, read back from the device
+    pub fn det(&self) -> Result<f32, Error> {
+        if self.is_empty() {
+            assert_eq!(self.rows, self.cols);
+            return Ok(1.);
+        }
+        self.lu()?.det()
+    }
+}
@@ -0,0 +1,269 @@
+use crate::blas::*;
+use crate::config::*;
+use crate::opencl::*;
+
+use ndarray::prelude::*;
+use ocl::error::Error;
+
+use std::ops::Range;
+
+// Data parallelism over several OpenCL devices. Devices generally don't share a context, so
+// everything moving between them is staged through the host; the reads are enqueued on every
+// device before waiting on any, so the transfers overlap.
+
+/// Several backends, usually on different devices, which large ops are split across by rows
+#[derive(Debug, Clone)]
+pub struct MultiBackEnd {
+    pub backends: Vec<CLBackEnd>,
+    // Relative share of the rows each backend gets
+    weights: Vec<f32>,
+}
+
+/// An array split by rows into consecutive blocks, each on its own device
+#[derive(Debug, Clone)]
+pub struct ShardedArray {
+    pub rows: usize,
+    pub cols: usize,
+    /// The blocks in row order. Backends which got no rows have no shard.
+    pub shards: Vec<OpenCLArray>,
+}
+
+/// How `MultiBackEnd::all_reduce` combines the per-device arrays
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReduceOp {
+    Sum,
+    Mean,
+}
+
+impl MultiBackEnd {
+    /// Splits work evenly across `backends`
+    pub fn new(backends: Vec<CLBackEnd>) -> Self {
+        assert!(!backends.is_empty());
+        let weights = vec![1.; backends.len()];
+        MultiBackEnd { backends, weights }
+    }
+
+    /// One backend per config, e.g. two GPUs, or a GPU and a CPU OpenCL implementation
+    pub fn from_configs(configs: &[BackendConfig]) -> Result<Self, Error> {
+        let backends = configs
+            .iter()
+            .map(|config| CLBackEnd::from_config(config.clone()))
+            .collect::<Result<_, Error>>()?;
+        Ok(MultiBackEnd::new(backends))
+    }
+
+    /// Gives each backend a share of the rows proportional to its weight, so a faster device
+    /// can take more of the work
+    pub fn with_weights(mut self, weights: &[f32]) -> Self {
+        assert_eq!(weights.len(), self.backends.len());
+        assert!(weights.iter().all(|&w| w >= 0.) && weights.iter().any(|&w| w > 0.));
+        self.weights = weights.to_vec();
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.backends.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.backends.is_empty()
+    }
+
+    /// The block of rows each backend gets when `rows` rows are split across them
+    pub fn row_ranges(&self, rows: usize) -> Vec<Range<usize>> {
+        let total: f32 = self.weights.iter().sum();
+        let mut cumulative = 0.;
+        let mut start = 0;
+        self.weights
+            .iter()
+            .enumerate()
+            .map(|(i, &w)| {
+                cumulative += w;
+                let end = if i + 1 == self.weights.len() {
+                    rows
+                } else {
+                    ((cumulative / total * rows as f32).round() as usize).clamp(start, rows)
+                };
+                let range = start..end;
+                start = end;
+                range
+            })
+            .collect()
+    }
+
+    /// Uploads `array` split by rows across the backends
+    pub fn shard_rows(&self, array: &Array2<f32>) -> Result<ShardedArray, Error> {
+        let shards = self
+            .backends
+            .iter()
+            .zip(self.row_ranges(array.nrows()))
+            .filter(|(_, rows)| !rows.is_empty())
+            .map(|(backend, rows)| {
+                OpenCLArray::from_array(backend.clone(), &array.slice(s![rows, ..]).to_owned())
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(ShardedArray {
+            rows: array.nrows(),
+            cols: array.ncols(),
+            shards,
+        })
+    }
+
+    /// Uploads a full copy of `array` to every backend, e.g. the weights every shard is
+    /// multiplied by
+    pub fn replicate(&self, array: &Array2<f32>) -> Result<Vec<OpenCLArray>, Error> {
+        self.backends
+            .iter()
+            .map(|backend| OpenCLArray::from_array(backend.clone(), array))
+            .collect()
+    }
+
+    /// Combines one array per backend, all of the same shape, and writes the result back
+    /// into every one of them. Averaging the gradients of a data-parallel mini-batch is
+    /// `all_reduce(&mut gradients, ReduceOp::Mean)`.
+    pub fn all_reduce(&self, arrays: &mut [OpenCLArray], op: ReduceOp) -> Result<(), Error> {
+        assert_eq!(arrays.len(), self.backends.len());
+        let (rows, cols) = (arrays[0].rows, arrays[0].cols);
+        assert!(arrays.iter().all(|a| (a.rows, a.cols) == (rows, cols)));
+
+        // Every device accumulates into its own copy, but all of them have to start from the
+        // same sum, so it is formed on the host from reads issued to all devices at once
+        let reads = arrays
+            .iter()
+            .map(|a| a.to_vec_async())
+            .collect::<Result<Vec<_>, Error>>()?;
+        let mut sum = vec![0.; rows * cols];
+        for read in reads {
+            for (total, x) in sum.iter_mut().zip(read.wait()?.iter()) {
+                *total += x;
+            }
+        }
+        if op == ReduceOp::Mean {
+            let n = arrays.len() as f32;
+            sum.iter_mut().for_each(|x| *x /= n);
+        }
+
+        for array in arrays.iter_mut() {
+            let reduced = OpenCLArray::from_vec(array.backend.clone(), rows, cols, sum.clone())?;
+            copy_region(&reduced, 0, array, 0, rows * cols)?;
+            // The sum is row-major, so a transposed view now holds a plain array
+            array.transposed = false;
+        }
+        Ok(())
+    }
+
+    /// Waits for every backend's queues to finish
+    pub fn synchronize(&self) -> Result<(), Error> {
+        for backend in &self.backends {
+            backend.synchronize()?;
+        }
+        Ok(())
+    }
+}
+
+impl ShardedArray {
+    // A sharded array with the same row split as this one and `cols` columns, uninitialized
+    fn like(&self, cols: usize) -> Result<ShardedArray, Error> {
+        let shards = self
+            .shards
+            .iter()
+            .map(|shard| OpenCLArray::uninitialized(shard.backend.clone(), shard.rows, cols))
+            .collect::<Result<_, Error>>()?;
+        Ok(ShardedArray {
+            rows: self.rows,
+            cols,
+            shards,
+        })
+    }
+
+    // Runs `op` on every shard with the matching shard of `other`, into a new sharded array
+    fn zip_with<F>(&self, other: &ShardedArray, op: F) -> Result<ShardedArray, Error>
+    where
+        F: Fn(&OpenCLArray, &OpenCLArray, &mut OpenCLArray) -> Result<(), Error>,
+    {
+        assert_eq!((self.rows, self.cols), (other.rows, other.cols));
+        assert_eq!(self.shards.len(), other.shards.len());
+        let mut result = self.like(self.cols)?;
+        for ((a, b), c) in self
+            .shards
+            .iter()
+            .zip(&other.shards)
+            .zip(&mut result.shards)
+        {
+            assert_eq!(a.rows, b.rows);
+            op(a, b, c)?;
+        }
+        Ok(result)
+    }
+
+    /// `self . b` with every device multiplying its own rows, where `b` holds one copy of the
+    /// right-hand side per backend, as made by `MultiBackEnd::replicate`
+    pub fn dot(&self, b: &[OpenCLArray]) -> Result<ShardedArray, Error> {
+        let cols = b[0].cols;
+        let mut result = self.like(cols)?;
+        for (a, c) in self.shards.iter().zip(&mut result.shards) {
+            // The copy on the same device as this shard
+            let b = b
+                .iter()
+                .find(|b| b.backend.shares_context(&a.backend))
+                .expect("no copy of the right-hand side on one of the shards' devices");
+            a.dot(b, c)?;
+        }
+        Ok(result)
+    }
+
+    pub fn add(&self, other: &ShardedArray) -> Result<ShardedArray, Error> {
+        self.zip_with(other, OpenCLArray::add)
+    }
+
+    pub fn subtract(&self, other: &ShardedArray) -> Result<ShardedArray, Error> {
+        self.zip_with(other, OpenCLArray::subtract)
+    }
+
+    pub fn hadamard(&self, other: &ShardedArray) -> Result<ShardedArray, Error> {
+        self.zip_with(other, OpenCLArray::hadamard)
+    }
+
+    pub fn scalar_multiply(&self, coeff: f32) -> Result<ShardedArray, Error> {
+        self.zip_with(self, |a, _, c| a.scalar_multiply(coeff, c))
+    }
+
+    pub fn sigmoid(&self) -> Result<ShardedArray, Error> {
+        self.zip_with(self, |a, _, c| a.sigmoid(c))
+    }
+
+    pub fn sigmoid_prime(&self) -> Result<ShardedArray, Error> {
+        self.zip_with(self, |a, _, c| a.sigmoid_prime(c))
+    }
+
+    /// Assembles the whole array on `backend`
+    pub fn gather(&self, backend: &CLBackEnd) -> Result<OpenCLArray, Error> {
+        let result = OpenCLArray::uninitialized(backend.clone(), self.rows, self.cols)?;
+        let mut offset = 0;
+        for shard in &self.shards {
+            let local = if shard.backend.shares_context(backend) {
+                shard.clone()
+            } else {
+                shard.to_backend(backend)?
+            };
+            copy_region(&local, 0, &result, offset, shard.len())?;
+            offset += shard.len();
+        }
+        Ok(result)
+    }
+
+    /// Reads every shard back and stacks them, the reads overlapping
+    pub fn to_array(&self) -> Result<Array2<f32>, Error> {
+        let reads = self
+            .shards
+            .iter()
+            .map(|shard| shard.to_vec_async())
+            .collect::<Result<Vec<_>, Error>>()?;
+        let mut data = Vec::with_capacity(self.rows * self.cols);
+        for read in reads {
+            data.extend_from_slice(&read.wait()?);
+        }
+        Ok(Array::from_shape_vec((self.rows, self.cols), data)
+            .expect("shards don't add up to the array's shape"))
+    }
+}
@@ -1,12 +1,24 @@
+use crate::blas::*;
+use crate::config::*;
+use crate::device::*;
+use crate::kernels::*;
+use crate::pool::*;
+use crate::profiler::*;
+use crate::tuning::*;
+
+use log::{debug, info};
 use ndarray::prelude::*;
 
 use std::iter::FromIterator;
-use std::time::{Duration, Instant};
+use std::sync::Arc;
 
 // Note: From benchmarking, the highest contribution to the runtime of this function is the conversion from an Array2 struct into a vector. In the context of a dense neural network, it's probably possible to do all of that overhead at the beginning, then keep exchanging the already-built vectors back and forth.
-use ocl::enums::DeviceSpecifier::*;
 use ocl::error::Error;
-use ocl::{Buffer, Device, MemFlags, Platform, ProQue, SpatialDims::*};
+use ocl::flags::CommandQueueProperties;
+use ocl::{
+    Buffer, Device, Event, FutureReadGuard, FutureWriteGuard, ProQue, Queue, RwVec, SpatialDims,
+    SpatialDims::*,
+};
 
 #[derive(Debug, Clone)]
 pub struct OpenCLArray {
@@ -14,6 +26,11 @@ pub struct OpenCLArray {
     pub v: Buffer<f32>,
     pub rows: usize,
     pub cols: usize,
+    // Set on views made by `t_view`: `v` then holds the `cols x rows` array this one is the
+    // transpose of
+    pub(crate) transposed: bool,
+    // Shared by every clone aliasing `v`; returns the buffer to the backend's pool on drop
+    _lease: Arc<Lease>,
 }
 
 pub fn create_vec(arr: &Array2<f32>) -> Vec<f32> {
@@ -21,20 +38,18 @@ pub fn create_vec(arr: &Array2<f32>) -> Vec<f32> {
 }
 
 impl OpenCLArray {
+    /// A zero-filled array. The buffer comes from the backend's pool and is cleared on the
+    /// device, without any host-side allocation.
     pub fn new(backend: CLBackEnd, rows: usize, cols: usize) -> Result<Self, Error> {
-        let a = vec![0.; rows * cols];
-        let buffer = Buffer::builder()
-            .queue(backend.proque.queue().clone())
-            .flags(MemFlags::new().read_write())
-            .len(Two(rows, cols))
-            .copy_host_slice(&a)
-            .build()?;
-        Ok(OpenCLArray {
-            backend: backend,
-            v: buffer,
-            rows: rows,
-            cols: cols,
-        })
+        let lease = BufferPool::alloc_zeroed(&backend.pool, backend.queue(), rows * cols)?;
+        Ok(OpenCLArray::from_lease(backend, rows, cols, lease))
+    }
+
+    /// An array whose contents are whatever its (possibly recycled) buffer last held, for
+    /// outputs that are about to be completely overwritten
+    pub fn uninitialized(backend: CLBackEnd, rows: usize, cols: usize) -> Result<Self, Error> {
+        let lease = BufferPool::alloc(&backend.pool, backend.queue(), rows * cols)?;
+        Ok(OpenCLArray::from_lease(backend, rows, cols, lease))
     }
 
     pub fn from_vec(
@@ -44,331 +59,675 @@ impl OpenCLArray {
         v: Vec<f32>,
     ) -> Result<OpenCLArray, Error> {
         assert_eq!(v.len(), rows * cols);
-        let buffer = Buffer::builder()
-            .queue(backend.proque.queue().clone())
-            .flags(MemFlags::new().read_write())
-            .len(Two(rows, cols))
-            .copy_host_slice(&v)
-            .build()?;
-        let mut arr = OpenCLArray {
-            backend: backend,
-            v: buffer,
-            rows: rows,
-            cols: cols,
-        };
+        let arr = OpenCLArray::uninitialized(backend, rows, cols)?;
+        let mut event = Event::empty();
+        arr.v
+            .write(&v)
+            .queue(arr.backend.queue())
+            .enew(&mut event)
+            .enq()?;
+        arr.backend.profile("write", CommandKind::Transfer, event);
         Ok(arr)
     }
 
     pub fn from_array(backend: CLBackEnd, array: &Array2<f32>) -> Result<OpenCLArray, Error> {
         let v = create_vec(array);
         let (rows, cols) = (array.nrows(), array.ncols());
-        assert_eq!(v.len(), rows * cols);
-        let buffer = Buffer::builder()
-            .queue(backend.proque.queue().clone())
-            .flags(MemFlags::new().read_write())
-            .len(Two(rows, cols))
-            .copy_host_slice(&v)
-            .build()?;
-        let mut arr = OpenCLArray {
-            backend: backend,
-            v: buffer,
-            rows: rows,
-            cols: cols,
-        };
-        Ok(arr)
+        OpenCLArray::from_vec(backend, rows, cols, v)
+    }
+
+    pub(crate) fn from_lease(backend: CLBackEnd, rows: usize, cols: usize, lease: Lease) -> Self {
+        OpenCLArray {
+            backend,
+            v: lease.buffer().clone(),
+            rows,
+            cols,
+            transposed: false,
+            _lease: Arc::new(lease),
+        }
+    }
+
+    /// Another handle to the same device memory, which is all `clone` makes: writes through
+    /// either one, including in-place ops like `square`, are seen by both. `deep_clone` makes
+    /// an independent copy.
+    pub fn share(&self) -> OpenCLArray {
+        self.clone()
+    }
+
+    /// A new array with its own buffer holding a copy of this one's contents, copied on the
+    /// device. A transposed view stays a view.
+    pub fn deep_clone(&self) -> Result<OpenCLArray, Error> {
+        let mut copy = OpenCLArray::uninitialized(self.backend.clone(), self.rows, self.cols)?;
+        copy.transposed = self.transposed;
+        if !self.is_empty() {
+            let mut event = Event::empty();
+            self.v
+                .cmd()
+                .queue(self.backend.queue())
+                .copy(&copy.v, None, Some(self.len()))
+                .enew(&mut event)
+                .enq()?;
+            self.backend.profile("copy", CommandKind::Transfer, event);
+        }
+        Ok(copy)
+    }
+
+    /// Overwrites `other`, which must have the same shape, with this array's contents. The
+    /// copy stays on the device when both backends share a context, and `other` then takes
+    /// on this array's layout, transposed view or not. Otherwise it is staged through the
+    /// host.
+    pub fn copy_to(&self, other: &mut OpenCLArray) -> Result<(), Error> {
+        assert_eq!((self.rows, self.cols), (other.rows, other.cols));
+        if !self.backend.shares_context(&other.backend) {
+            let data = self.clone().to_vec()?;
+            let mut event = Event::empty();
+            other
+                .v
+                .write(&data)
+                .queue(other.backend.queue())
+                .enew(&mut event)
+                .enq()?;
+            other.backend.profile("write", CommandKind::Transfer, event);
+            other.transposed = false;
+            return Ok(());
+        }
+
+        other.transposed = self.transposed;
+        if self.v.as_core().as_ptr() == other.v.as_core().as_ptr() {
+            return Ok(());
+        }
+        // The copy runs on this array's queue, after whatever is queued for `other`, and
+        // anything enqueued for `other` afterwards waits for it
+        let separate_queues = self.backend.queue().as_ptr() != other.backend.queue().as_ptr();
+        if separate_queues {
+            self.backend.wait_for(&other.backend.marker()?)?;
+        }
+        copy_region(self, 0, other, 0, self.len())?;
+        if separate_queues {
+            other.backend.wait_for(&self.backend.marker()?)?;
+        }
+        Ok(())
+    }
+
+    /// A copy of the array on `backend`, which may be on another device
+    pub fn to_backend(&self, backend: &CLBackEnd) -> Result<OpenCLArray, Error> {
+        let mut copy = OpenCLArray::uninitialized(backend.clone(), self.rows, self.cols)?;
+        self.copy_to(&mut copy)?;
+        Ok(copy)
+    }
+
+    /// Moves the array to `backend`. A backend sharing this one's context takes over the
+    /// buffer as it is; any other gets a copy.
+    pub fn into_backend(self, backend: &CLBackEnd) -> Result<OpenCLArray, Error> {
+        if !self.backend.shares_context(backend) {
+            return self.to_backend(backend);
+        }
+        backend.wait_for(&self.backend.marker()?)?;
+        Ok(OpenCLArray {
+            backend: backend.clone(),
+            ..self
+        })
     }
 
     pub fn to_vec(self) -> Result<Vec<f32>, Error> {
+        if self.transposed {
+            return self.contiguous()?.to_vec();
+        }
         let mut vec_result = vec![0.; self.rows * self.cols];
-        self.v.read(&mut vec_result).enq()?;
+        let mut event = Event::empty();
+        self.v
+            .read(&mut vec_result)
+            .queue(self.backend.queue())
+            .enew(&mut event)
+            .enq()?;
+        self.backend.profile("read", CommandKind::Transfer, event);
         Ok(vec_result)
     }
 
+    /// Enqueues a non-blocking read of the array, returning a future which resolves
+    /// to the host data once the read (and everything queued before it) has completed
+    pub fn to_vec_async(&self) -> Result<FutureWriteGuard<Vec<f32>>, Error> {
+        // A transposed view is first copied into a row-major temporary on the same queue
+        let source = self.contiguous()?;
+        let rw_vec = RwVec::from(vec![0.; self.rows * self.cols]);
+        let mut event = Event::empty();
+        let future = source
+            .v
+            .read(rw_vec)
+            .queue(self.backend.queue())
+            .enew(&mut event)
+            .enq_async()?;
+        self.backend
+            .profile("read_async", CommandKind::Transfer, event);
+        Ok(future)
+    }
+
+    /// Enqueues a non-blocking upload of `data` into the array. The returned future
+    /// hands the host data back once the device has finished reading it, so a staging
+    /// vector can be reused for the next mini-batch. `data` is row-major, so a transposed view
+    /// written to stops being one, as with `copy_to`.
+    pub fn write_async(&mut self, data: RwVec<f32>) -> Result<FutureReadGuard<Vec<f32>>, Error> {
+        assert_eq!(data.len_stale(), self.rows * self.cols);
+        self.transposed = false;
+        let mut event = Event::empty();
+        let future = self
+            .v
+            .write(data)
+            .queue(self.backend.queue())
+            .enew(&mut event)
+            .enq_async()?;
+        self.backend
+            .profile("write_async", CommandKind::Transfer, event);
+        Ok(future)
+    }
+
+    /// Moves all subsequent operations on this array onto the backend's queue `idx`
+    pub fn set_queue(&mut self, idx: usize) {
+        self.backend = self.backend.on_queue(idx);
+        self.v.set_default_queue(self.backend.queue().clone());
+    }
+
     pub fn to_array(self) -> Result<Array2<f32>, Error> {
-        let mut vec_result = vec![0.; self.rows * self.cols];
-        self.v.read(&mut vec_result).enq()?;
+        let (rows, cols) = (self.rows, self.cols);
+        let vec_result = self.to_vec()?;
 
         // println!("vec_result: {:?}",vec_result);
-        let arr = Array::from_shape_vec((self.rows, self.cols), vec_result)
+        let arr = Array::from_shape_vec((rows, cols), vec_result)
             .expect("Coudn't convert result to properly sized array");
         Ok(arr)
     }
 
-    pub fn square(&mut self) -> Result<(), Error> {
-        let mut kern = self
-            .backend
-            .proque
-            .kernel_builder("square")
-            .arg(&self.v)
-            .build()?;
+    /// The number of elements, `rows * cols`
+    pub fn len(&self) -> usize {
+        self.rows * self.cols
+    }
 
-        kern.set_default_global_work_size(One(self.rows * self.cols)); // This one alone works for MNIST-size sets
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 
-        unsafe {
-            kern.enq()?;
-        }
+    /// Squares every element in place. Being element-wise, it works on a transposed view as
+    /// well, squaring the buffer it shares.
+    pub fn square(&mut self) -> Result<(), Error> {
+        self.square_event().map(|_| ())
+    }
 
-        Ok(())
+    /// `square`, returning an event which completes with it
+    pub fn square_event(&mut self) -> Result<Event, Error> {
+        self.backend.enq_kernel_event(
+            "square",
+            &[Arg::Buffer(&self.v), Arg::Ulong(self.len() as u64)],
+            One(self.len()),
+        )
     }
 
-    pub fn t(&mut self) -> Result<OpenCLArray, Error> {
-        let v = vec![0.; self.rows * self.cols];
-        let buffer = Buffer::builder()
-            .queue(self.backend.proque.queue().clone())
-            .flags(MemFlags::new().read_write())
-            .len(Two(self.cols, self.rows))
-            .copy_host_slice(&v)
-            .build()?;
-
-        let mut kern = self
-            .backend
-            .proque
-            .kernel_builder("transpose")
-            .arg(&self.v)
-            .arg(&buffer)
-            .arg(self.rows)
-            .arg(self.cols)
-            .build()?;
-
-        kern.set_default_global_work_size(Two(self.rows, self.cols));
-
-        unsafe {
-            kern.enq()?;
-        }
+    // Writes the transpose of the `rows x cols` array held in `v` into `dst`
+    fn transpose_into(&self, rows: usize, cols: usize, dst: &OpenCLArray) -> Result<(), Error> {
+        self.backend.enq_kernel(
+            "transpose_tiled",
+            &[
+                Arg::Buffer(&self.v),
+                Arg::Buffer(&dst.v),
+                Arg::Ulong(rows as u64),
+                Arg::Ulong(cols as u64),
+            ],
+            Two(cols, rows),
+        )
+    }
 
-        let mut result_ocl_array = OpenCLArray::new(self.backend.clone(), self.cols, self.rows)?;
-        result_ocl_array.v = buffer;
-        Ok(result_ocl_array)
+    /// The transpose, as a new array
+    pub fn t(&self) -> Result<OpenCLArray, Error> {
+        if self.transposed {
+            // The buffer already holds the transpose
+            let mut result = self.deep_clone()?;
+            result.transposed = false;
+            std::mem::swap(&mut result.rows, &mut result.cols);
+            return Ok(result);
+        }
+        let result = OpenCLArray::uninitialized(self.backend.clone(), self.cols, self.rows)?;
+        self.transpose_into(self.rows, self.cols, &result)?;
+        Ok(result)
     }
 
+    /// Transposes the array in place. Square arrays and views keep their buffer; other arrays
+    /// get a new one.
     pub fn t_v2(&mut self) -> Result<(), Error> {
-        let v = vec![0.; self.rows * self.cols];
-        let buffer = Buffer::builder()
-            .queue(self.backend.proque.queue().clone())
-            .flags(MemFlags::new().read_write())
-            .len(Two(self.cols, self.rows))
-            .copy_host_slice(&v)
-            .build()?;
-
-        let mut kern = self
-            .backend
-            .proque
-            .kernel_builder("transpose")
-            .arg(&self.v)
-            .arg(&buffer)
-            .arg(self.rows)
-            .arg(self.cols)
-            .build()?;
-
-        kern.set_default_global_work_size(Two(self.rows, self.cols));
-
-        unsafe {
-            kern.enq()?;
+        if self.transposed {
+            self.transposed = false;
+            std::mem::swap(&mut self.rows, &mut self.cols);
+        } else if self.rows == self.cols {
+            self.backend.enq_kernel(
+                "transpose_square",
+                &[Arg::Buffer(&self.v), Arg::Ulong(self.rows as u64)],
+                Two(self.rows, self.cols),
+            )?;
+        } else {
+            *self = self.t()?;
         }
+        Ok(())
+    }
 
-        self.v = buffer;
-        let (rows,cols) = (self.rows,self.cols);
-        self.rows = cols;
-        self.cols = rows;
+    /// The transpose as a view sharing this array's buffer, without launching anything. `dot`,
+    /// `t`, `t_v2` and the reads back to the host use the view as it is; other ops taking it as
+    /// an input go through `contiguous` first, and those writing their result into an array
+    /// need one which isn't a view.
+    pub fn t_view(&self) -> OpenCLArray {
+        let mut view = self.clone();
+        view.transposed = !self.transposed;
+        std::mem::swap(&mut view.rows, &mut view.cols);
+        view
+    }
 
-        Ok(())
+    /// Whether this is a view made by `t_view`, whose buffer holds the transpose
+    pub fn is_transposed(&self) -> bool {
+        self.transposed
     }
 
-    pub fn dot(&self, b: &OpenCLArray, c: &mut OpenCLArray) -> Result<(), Error> {
-        let (n, m, k) = (self.rows, self.cols, b.cols);
+    /// A plain row-major array with the same contents: a new array for a transposed view, and
+    /// otherwise a clone sharing the buffer
+    pub fn contiguous(&self) -> Result<OpenCLArray, Error> {
+        if !self.transposed {
+            return Ok(self.clone());
+        }
+        let result = OpenCLArray::uninitialized(self.backend.clone(), self.rows, self.cols)?;
+        self.transpose_into(self.cols, self.rows, &result)?;
+        Ok(result)
+    }
 
-        // let kern_start = Instant::now();
-        let mut kern = self
-            .backend
-            .proque
-            .kernel_builder("dot_product")
-            .arg(&self.v)
-            .arg(&b.v)
-            .arg(&c.v)
-            .arg(&m)
-            .arg(&k)
-            .build()?;
-
-        kern.set_default_global_work_size(Two(n, k)); // This one alone works for MNIST-size sets
-        // println!("dot product kernel build time: {}",kern_start.elapsed().as_nanos() );
-
-        // let enq_start = Instant::now();
-        unsafe {
-            kern.enq()?;
+    // A plain row-major copy which never shares this array's buffer, for ops working in place
+    // on a copy of their input
+    pub(crate) fn contiguous_copy(&self) -> Result<OpenCLArray, Error> {
+        if self.transposed {
+            self.contiguous()
+        } else {
+            self.deep_clone()
         }
-        // println!("dot product enq  runtime: {}",kern_start.elapsed().as_nanos() );
+    }
 
+    pub fn dot(&self, b: &OpenCLArray, c: &mut OpenCLArray) -> Result<(), Error> {
+        self.dot_event(b, c).map(|_| ())
+    }
 
-        Ok(())
+    /// `dot`, returning an event which completes with it
+    pub fn dot_event(&self, b: &OpenCLArray, c: &mut OpenCLArray) -> Result<Event, Error> {
+        assert_eq!(self.cols, b.rows);
+        let (n, m, k) = (self.rows, self.cols, b.cols);
+        assert_eq!((c.rows, c.cols), (n, k));
+        assert!(!c.transposed);
+
+        if self.transposed || b.transposed {
+            return self.backend.enq_kernel_event(
+                "dot_product_transposed",
+                &[
+                    Arg::Buffer(&self.v),
+                    Arg::Buffer(&b.v),
+                    Arg::Buffer(&c.v),
+                    Arg::Ulong(n as u64),
+                    Arg::Ulong(m as u64),
+                    Arg::Ulong(k as u64),
+                    Arg::Ulong(self.transposed as u64),
+                    Arg::Ulong(b.transposed as u64),
+                ],
+                Two(n, k),
+            );
+        }
+        self.backend.enq_kernel_event(
+            "dot_product",
+            &[
+                Arg::Buffer(&self.v),
+                Arg::Buffer(&b.v),
+                Arg::Buffer(&c.v),
+                Arg::Ulong(n as u64),
+                Arg::Ulong(m as u64),
+                Arg::Ulong(k as u64),
+            ],
+            Two(n, k),
+        )
     }
 
     pub fn hadamard(&self, b: &OpenCLArray, c: &mut OpenCLArray) -> Result<(), Error> {
+        self.hadamard_event(b, c).map(|_| ())
+    }
+
+    /// `hadamard`, returning an event which completes with it
+    pub fn hadamard_event(&self, b: &OpenCLArray, c: &mut OpenCLArray) -> Result<Event, Error> {
         assert_eq!((self.rows, self.cols), (b.rows, b.cols));
+        assert!(!c.transposed);
+        let (a, b) = (self.contiguous()?, b.contiguous()?);
         let (n, m) = (self.rows, self.cols);
 
-        let mut kern = self
-            .backend
-            .proque
-            .kernel_builder("hadamard")
-            .arg(&self.v)
-            .arg(&b.v)
-            .arg(&c.v)
-            .build()?;
+        self.backend.enq_kernel_event(
+            "hadamard",
+            &[
+                Arg::Buffer(&a.v),
+                Arg::Buffer(&b.v),
+                Arg::Buffer(&c.v),
+                Arg::Ulong((n * m) as u64),
+            ],
+            One(n * m),
+        )
+    }
 
-        kern.set_default_global_work_size(One(n * m)); // This one alone works for MNIST-size sets
+    pub fn add(&self, b: &OpenCLArray, c: &mut OpenCLArray) -> Result<(), Error> {
+        self.add_event(b, c).map(|_| ())
+    }
 
-        unsafe {
-            kern.enq()?;
-        }
+    /// `add`, returning an event which completes with it
+    pub fn add_event(&self, b: &OpenCLArray, c: &mut OpenCLArray) -> Result<Event, Error> {
+        assert_eq!((self.rows, self.cols), (b.rows, b.cols));
+        assert!(!c.transposed);
+        let (a, b) = (self.contiguous()?, b.contiguous()?);
+        let (n, m) = (self.rows, self.cols);
 
-        Ok(())
+        self.backend.enq_kernel_event(
+            "add",
+            &[
+                Arg::Buffer(&a.v),
+                Arg::Buffer(&b.v),
+                Arg::Buffer(&c.v),
+                Arg::Ulong((n * m) as u64),
+            ],
+            One(n * m),
+        )
     }
 
-    pub fn add(&self, b: &OpenCLArray, c: &mut OpenCLArray) -> Result<(), Error> {
+    pub fn subtract(&self, b: &OpenCLArray, c: &mut OpenCLArray) -> Result<(), Error> {
+        self.subtract_event(b, c).map(|_| ())
+    }
+
+    /// `subtract`, returning an event which completes with it
+    pub fn subtract_event(&self, b: &OpenCLArray, c: &mut OpenCLArray) -> Result<Event, Error> {
         assert_eq!((self.rows, self.cols), (b.rows, b.cols));
+        assert!(!c.transposed);
+        let (a, b) = (self.contiguous()?, b.contiguous()?);
         let (n, m) = (self.rows, self.cols);
 
-        let mut kern = self
-            .backend
-            .proque
-            .kernel_builder("add")
-            .arg(&self.v)
-            .arg(&b.v)
-            .arg(&c.v)
-            .build()?;
+        self.backend.enq_kernel_event(
+            "subtract",
+            &[
+                Arg::Buffer(&a.v),
+                Arg::Buffer(&b.v),
+                Arg::Buffer(&c.v),
+                Arg::Ulong((n * m) as u64),
+            ],
+            One(n * m),
+        )
+    }
 
-        kern.set_default_global_work_size(One(n * m)); // This one alone works for MNIST-size sets
+    pub fn scalar_multiply(&self, coeff: f32, b: &mut OpenCLArray) -> Result<(), Error> {
+        self.scalar_multiply_event(coeff, b).map(|_| ())
+    }
 
-        unsafe {
-            kern.enq()?;
-        }
+    /// `scalar_multiply`, returning an event which completes with it
+    pub fn scalar_multiply_event(&self, coeff: f32, b: &mut OpenCLArray) -> Result<Event, Error> {
+        assert!(!b.transposed);
+        let a = self.contiguous()?;
+        let (n, m) = (self.rows, self.cols);
 
-        Ok(())
+        self.backend.enq_kernel_event(
+            "multiply_by_scalar",
+            &[
+                Arg::Buffer(&a.v),
+                Arg::Buffer(&b.v),
+                Arg::Float(coeff),
+                Arg::Ulong((n * m) as u64),
+            ],
+            One(n * m),
+        )
     }
 
-    pub fn subtract(&self, b: &OpenCLArray, c: &mut OpenCLArray) -> Result<(), Error> {
-        assert_eq!((self.rows, self.cols), (b.rows, b.cols));
-        let (n, m) = (self.rows, self.cols);
+    pub fn sigmoid(&self, b: &mut OpenCLArray) -> Result<(), Error> {
+        self.sigmoid_event(b).map(|_| ())
+    }
 
-        let mut kern = self
-            .backend
-            .proque
-            .kernel_builder("subtract")
-            .arg(&self.v)
-            .arg(&b.v)
-            .arg(&c.v)
-            .build()?;
+    /// `sigmoid`, returning an event which completes with it
+    pub fn sigmoid_event(&self, b: &mut OpenCLArray) -> Result<Event, Error> {
+        assert!(!b.transposed);
+        let a = self.contiguous()?;
+        let (n, m) = (self.rows, self.cols);
 
-        kern.set_default_global_work_size(One(n * m)); // This one alone works for MNIST-size sets
+        self.backend.enq_kernel_event(
+            "sigmoid",
+            &[
+                Arg::Buffer(&a.v),
+                Arg::Buffer(&b.v),
+                Arg::Ulong((n * m) as u64),
+            ],
+            One(n * m),
+        )
+    }
 
-        unsafe {
-            kern.enq()?;
-        }
-        Ok(())
+    pub fn sigmoid_prime(&self, b: &mut OpenCLArray) -> Result<(), Error> {
+        self.sigmoid_prime_event(b).map(|_| ())
     }
 
-    pub fn scalar_multiply(&self, coeff: f32, b: &mut OpenCLArray) -> Result<(), Error> {
+    /// `sigmoid_prime`, returning an event which completes with it
+    pub fn sigmoid_prime_event(&self, b: &mut OpenCLArray) -> Result<Event, Error> {
+        assert!(!b.transposed);
+        let a = self.contiguous()?;
         let (n, m) = (self.rows, self.cols);
 
-        let mut kern = self
-            .backend
-            .proque
-            .kernel_builder("multiply_by_scalar")
-            .arg(&self.v)
-            .arg(&b.v)
-            .arg(&coeff)
-            .build()?;
+        self.backend.enq_kernel_event(
+            "sigmoid_prime",
+            &[
+                Arg::Buffer(&a.v),
+                Arg::Buffer(&b.v),
+                Arg::Ulong((n * m) as u64),
+            ],
+            One(n * m),
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CLBackEnd {
+    pub proque: ProQue,
+    pub queues: Vec<Queue>,
+    pub active_queue: usize,
+    pub pool: Arc<BufferPool>,
+    pub kernels: Arc<KernelCache>,
+    pub profiler: Option<Arc<Profiler>>,
+    pub autotuner: Option<Arc<Autotuner>>,
+    /// The settings this backend was built from
+    pub config: BackendConfig,
+}
+
+impl CLBackEnd {
+    /// Builds a backend with the default `BackendConfig`: one queue, no profiling and no
+    /// autotuning
+    pub fn new(gpu_type: &str) -> ocl::Result<Self> {
+        CLBackEnd::with_queues(gpu_type, 1)
+    }
 
-        kern.set_default_global_work_size(One(n * m)); // This one alone works for MNIST-size sets
+    /// Builds a backend with `num_queues` in-order command queues on the same device and
+    /// context, e.g. one for compute and one for staging the next mini-batch. The first
+    /// queue is the one owned by the `ProQue` and is active by default.
+    pub fn with_queues(gpu_type: &str, num_queues: usize) -> ocl::Result<Self> {
+        CLBackEnd::from_config(BackendConfig {
+            queues: num_queues,
+            ..BackendConfig::new(gpu_type)
+        })
+    }
 
-        unsafe {
-            kern.enq()?;
+    /// Like `with_queues`, but with profiling enabled on every queue: each kernel launch and
+    /// transfer is recorded in `profiler`. Profiling adds a little overhead to every command.
+    pub fn with_profiler(gpu_type: &str, num_queues: usize) -> ocl::Result<Self> {
+        CLBackEnd::from_config(BackendConfig {
+            queues: num_queues,
+            profiling: true,
+            ..BackendConfig::new(gpu_type)
+        })
+    }
+
+    pub fn from_config(config: BackendConfig) -> ocl::Result<Self> {
+        if config.queues == 0 {
+            return Err("a backend needs at least one queue".into());
+        }
+        let properties = config.queue_properties();
+        let device = select_device(&config.device, config.platform.as_deref())?;
+        let proque = build_proque(device, &config.build_options(), properties)?;
+        let mut queues = vec![proque.queue().clone()];
+        for _ in 1..config.queues {
+            queues.push(Queue::new(proque.context(), proque.device(), properties)?);
         }
+        let autotuner = if config.autotune {
+            let device = proque.device().name()?;
+            Some(Arc::new(Autotuner::new(&device, Autotuner::default_path())))
+        } else {
+            None
+        };
+        Ok(CLBackEnd {
+            proque,
+            queues,
+            active_queue: 0,
+            pool: Arc::new(BufferPool::new()),
+            kernels: Arc::new(KernelCache::new()),
+            profiler: if config.profiling {
+                Some(Arc::new(Profiler::new()))
+            } else {
+                None
+            },
+            autotuner,
+            config,
+        })
+    }
 
-        Ok(())
+    /// The queue that arrays built from this backend enqueue their work on
+    pub fn queue(&self) -> &Queue {
+        &self.queues[self.active_queue]
     }
 
-    pub fn sigmoid(&self, b: &mut OpenCLArray) -> Result<(), Error> {
-        let (n, m) = (self.rows, self.cols);
+    /// Returns a handle to the same device and context whose work goes to queue `idx`
+    pub fn on_queue(&self, idx: usize) -> CLBackEnd {
+        assert!(idx < self.queues.len());
+        let mut backend = self.clone();
+        backend.active_queue = idx;
+        backend
+    }
 
-        let mut kern = self
-            .backend
-            .proque
-            .kernel_builder("sigmoid")
-            .arg(&self.v)
-            .arg(&b.v)
-            .build()?;
+    /// Enqueues the kernel `name` from `functions.cl` on the active queue. The `Kernel` object
+    /// is built on first use and cached, so later calls only rebind `args`. Tunable kernels
+    /// are launched with the local size picked by the `autotuner`, if there is one.
+    pub fn enq_kernel(&self, name: &str, args: &[Arg], gws: SpatialDims) -> Result<(), Error> {
+        self.enq_kernel_event(name, args, gws).map(|_| ())
+    }
 
-        kern.set_default_global_work_size(One(n * m)); // This one alone works for MNIST-size sets
+    /// Like `enq_kernel`, but returns an event which completes with the launch, for other
+    /// queues to `wait_for` or the host to wait on. An empty launch enqueues nothing and gets
+    /// a marker instead.
+    pub fn enq_kernel_event(
+        &self,
+        name: &str,
+        args: &[Arg],
+        gws: SpatialDims,
+    ) -> Result<Event, Error> {
+        let lws = match &self.autotuner {
+            Some(tuner) => {
+                tuner.local_size(&self.kernels, &self.proque, self.queue(), name, args, gws)?
+            }
+            None => None,
+        };
+        let mut event = Event::empty();
+        self.kernels
+            .enq(&self.proque, self.queue(), name, args, gws, lws, &mut event)?;
+        if event.is_empty() {
+            return self.marker();
+        }
+        self.profile(name, CommandKind::Kernel, event.clone());
+        Ok(event)
+    }
 
-        unsafe {
-            kern.enq()?;
+    /// Hands `event` to the profiler, if this backend has one and the command was enqueued
+    pub fn profile(&self, name: &str, kind: CommandKind, event: Event) {
+        if event.is_empty() {
+            return;
         }
+        if let Some(profiler) = &self.profiler {
+            profiler.record(name, kind, self.active_queue, event);
+        }
+    }
 
-        Ok(())
+    /// Returns an event which completes once everything enqueued so far on the active
+    /// queue (e.g. the op that was just called) has completed
+    pub fn marker(&self) -> Result<Event, Error> {
+        self.queue().enqueue_marker::<&Event>(None)
     }
 
-    pub fn sigmoid_prime(&self, b: &mut OpenCLArray) -> Result<(), Error> {
-        let (n, m) = (self.rows, self.cols);
+    /// Makes all work subsequently enqueued on the active queue wait for `event`, which
+    /// may come from any other queue of this backend
+    pub fn wait_for(&self, event: &Event) -> Result<(), Error> {
+        self.queue().enqueue_marker(Some(event)).map(|_| ())
+    }
 
-        let mut kern = self
-            .backend
-            .proque
-            .kernel_builder("sigmoid_prime")
-            .arg(&self.v)
-            .arg(&b.v)
-            .build()?;
+    /// What the backend's device reports about itself
+    pub fn device_info(&self) -> Result<DeviceInfo, Error> {
+        let platform = self.proque.context().platform()?.unwrap_or_default();
+        DeviceInfo::query(&platform, &self.proque.device())
+    }
 
-        kern.set_default_global_work_size(One(n * m)); // This one alone works for MNIST-size sets
+    /// Device memory held by this backend's arrays and by its cache of freed buffers
+    pub fn pool_stats(&self) -> PoolStats {
+        self.pool.stats()
+    }
 
-        unsafe {
-            kern.enq()?;
+    /// Blocks until every command enqueued on every queue of this backend has completed
+    pub fn synchronize(&self) -> Result<(), Error> {
+        for queue in &self.queues {
+            queue.finish()?;
         }
-
         Ok(())
     }
-}
-
-#[derive(Debug, Clone)]
-pub struct CLBackEnd {
-    pub proque: ProQue,
-}
 
-impl CLBackEnd {
-    pub fn new(gpu_type: &str) -> ocl::Result<Self> {
-        let proque = build_ocl_proque(gpu_type.to_string())?;
-        Ok(CLBackEnd { proque: proque })
+    /// Whether both backends use the same OpenCL context, so their buffers can be used by
+    /// either one's kernels and copied between them on the device
+    pub fn shares_context(&self, other: &CLBackEnd) -> bool {
+        self.proque.context().as_core().as_ptr() == other.proque.context().as_core().as_ptr()
     }
 }
 
-pub fn build_ocl_proque(gpu_type: String) -> ocl::Result<ProQue> {
-    let src = include_str!("cl/functions.cl");
+pub fn build_ocl_proque(
+    gpu_type: String,
+    queue_properties: Option<CommandQueueProperties>,
+) -> ocl::Result<ProQue> {
+    build_proque(select_device(&gpu_type, None)?, "", queue_properties)
+}
 
+// The last device whose name contains `gpu_type`, optionally only on platforms whose name
+// contains `platform_name`
+fn select_device(gpu_type: &str, platform_name: Option<&str>) -> ocl::Result<Device> {
     let mut dev = None;
-    let platforms = Platform::list();
-    for p_idx in 0..platforms.len() {
-        let platform = &platforms[p_idx];
-        let devices = Device::list_all(platform)?;
-        for d_idx in 0..devices.len() {
-            let device = devices[d_idx];
-            println!("Device: {:?}", device.name());
-            if device.name()?.to_string().contains(&gpu_type) {
-                dev = Some(device);
-            }
-            //let deviceinforesult = core::get_device_info(&device, DeviceInfo::MaxComputeUnits);
-            //let units = deviceinforesult.to_string().parse().unwrap();
+    for (platform, device) in list_devices()? {
+        debug!(
+            "Found device {:?} on platform {:?}",
+            device.name()?,
+            platform.name()?
+        );
+        let on_platform = match platform_name {
+            Some(name) => platform.name()?.contains(name),
+            None => true,
+        };
+        if on_platform && device.name()?.contains(gpu_type) {
+            dev = Some(device);
         }
     }
+    Ok(dev.ok_or_else(|| format!("No OpenCL device name contains {:?}", gpu_type))?)
+}
 
-    println!("The desired GPU device is: {:?}", dev);
-    //println!("The WORK_SIZE is {}",WORK_SIZE);
-    let mut ocl_pq = ProQue::builder().src(src).device(dev.unwrap()).build()?;
+fn build_proque(
+    device: Device,
+    build_options: &str,
+    queue_properties: Option<CommandQueueProperties>,
+) -> ocl::Result<ProQue> {
+    let src = include_str!("cl/functions.cl");
+    let ocl_pq = build_proque_cached(device, src, build_options, queue_properties)?;
 
-    println!("The specified device is: {}", ocl_pq.device().name()?);
-    println!(
+    info!("Using OpenCL device {}", ocl_pq.device().name()?);
+    debug!(
         "It has a maximum working group size of {}",
         ocl_pq.device().max_wg_size()?
     );
-    assert!(ocl_pq.device().is_available().unwrap());
+    if !ocl_pq.device().is_available()? {
+        return Err(format!("OpenCL device {} is not available", ocl_pq.device().name()?).into());
+    }
     Ok(ocl_pq)
 }
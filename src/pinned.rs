@@ -0,0 +1,199 @@
+use crate::opencl::*;
+use crate::pool::*;
+use crate::profiler::*;
+
+use ocl::error::Error;
+use ocl::flags::MapFlags;
+use ocl::{Buffer, Event, MemFlags, MemMap};
+
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+
+/// Page-locked host memory allocated by the driver (`CL_MEM_ALLOC_HOST_PTR`), for staging
+/// data which goes to or from the device over and over, such as mini-batches. The host reads
+/// and writes it through `map`, and transfers between it and device arrays are enqueued
+/// without blocking, so they can overlap with kernels on another queue. On devices which
+/// share memory with the host, `stage` hands the memory itself to kernels instead of copying.
+#[derive(Debug)]
+pub struct PinnedHostBuffer {
+    backend: CLBackEnd,
+    buffer: Buffer<f32>,
+    len: usize,
+    unified: bool,
+}
+
+/// The contents of a `PinnedHostBuffer` mapped into host memory. Unmapped on drop, after
+/// which the device sees any writes made through it.
+#[derive(Debug)]
+pub struct PinnedMap<'a> {
+    map: MemMap<f32>,
+    len: usize,
+    // Holds the exclusive borrow, so there is only ever one mapping of the buffer
+    _buffer: PhantomData<&'a mut PinnedHostBuffer>,
+}
+
+impl PinnedHostBuffer {
+    /// `len` elements of pinned memory, which `backend`'s queue transfers to and from
+    pub fn new(backend: &CLBackEnd, len: usize) -> Result<Self, Error> {
+        let buffer = Buffer::<f32>::builder()
+            .queue(backend.queue().clone())
+            .flags(MemFlags::new().read_write().alloc_host_ptr())
+            .len(len.max(1))
+            .build()?;
+        Ok(PinnedHostBuffer {
+            backend: backend.clone(),
+            buffer,
+            len,
+            unified: backend.device_info()?.host_unified_memory,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether the device uses this memory directly, without transfers
+    pub fn is_zero_copy(&self) -> bool {
+        self.unified
+    }
+
+    /// Maps the buffer for reading and writing, once everything queued on the backend's queue
+    /// has completed
+    pub fn map(&mut self) -> Result<PinnedMap<'_>, Error> {
+        // Only one mapping can exist at a time, as it borrows `self` mutably
+        let map = unsafe {
+            self.buffer
+                .map()
+                .queue(self.backend.queue())
+                .flags(MapFlags::new().read().write())
+                .enq()?
+        };
+        Ok(PinnedMap {
+            map,
+            len: self.len,
+            _buffer: PhantomData,
+        })
+    }
+
+    /// Overwrites the buffer with `data`. The old contents are discarded rather than read
+    /// back from the device.
+    pub fn write(&mut self, data: &[f32]) -> Result<(), Error> {
+        assert_eq!(data.len(), self.len);
+        let mut map = unsafe {
+            self.buffer
+                .map()
+                .queue(self.backend.queue())
+                .write_invalidate()
+                .enq()?
+        };
+        map[..self.len].copy_from_slice(data);
+        Ok(())
+    }
+
+    pub fn to_vec(&mut self) -> Result<Vec<f32>, Error> {
+        Ok(self.map()?.to_vec())
+    }
+
+    /// Enqueues a copy of the buffer into `array`, which must have the same length, without
+    /// waiting for it
+    pub fn upload(&self, array: &OpenCLArray) -> Result<(), Error> {
+        assert_eq!(array.len(), self.len);
+        assert!(!array.transposed, "upload into a contiguous array");
+        assert!(array.backend.shares_context(&self.backend));
+        // Nothing to copy for an array `stage` made on a zero-copy device
+        if self.is_empty() || self.aliases(array) {
+            return Ok(());
+        }
+        // The copy has to come after the last unmap, which went to this buffer's queue
+        let separate_queues = self.backend.queue().as_ptr() != array.backend.queue().as_ptr();
+        if separate_queues {
+            array.backend.wait_for(&self.backend.marker()?)?;
+        }
+        let mut event = Event::empty();
+        self.buffer
+            .cmd()
+            .queue(array.backend.queue())
+            .copy(&array.v, None, Some(self.len))
+            .enew(&mut event)
+            .enq()?;
+        array
+            .backend
+            .profile("upload_pinned", CommandKind::Transfer, event);
+        // Likewise the next map or write, on this buffer's queue, has to wait for the copy
+        if separate_queues {
+            self.backend.wait_for(&array.backend.marker()?)?;
+        }
+        Ok(())
+    }
+
+    /// Enqueues a copy of `array`, which must have the same length, into the buffer. The next
+    /// `map` sees it.
+    pub fn download(&mut self, array: &OpenCLArray) -> Result<(), Error> {
+        assert_eq!(array.len(), self.len);
+        assert!(
+            !array.transposed,
+            "download a transposed view through `contiguous` first"
+        );
+        assert!(array.backend.shares_context(&self.backend));
+        // Nothing to copy for an array `stage` made on a zero-copy device
+        if self.is_empty() || self.aliases(array) {
+            return Ok(());
+        }
+        let mut event = Event::empty();
+        array
+            .v
+            .cmd()
+            .queue(array.backend.queue())
+            .copy(&self.buffer, None, Some(self.len))
+            .enew(&mut event)
+            .enq()?;
+        array
+            .backend
+            .profile("download_pinned", CommandKind::Transfer, event);
+        if self.backend.queue().as_ptr() != array.backend.queue().as_ptr() {
+            self.backend.wait_for(&array.backend.marker()?)?;
+        }
+        Ok(())
+    }
+
+    fn aliases(&self, array: &OpenCLArray) -> bool {
+        self.buffer.as_core().as_ptr() == array.v.as_core().as_ptr()
+    }
+
+    /// The contents as a `rows x cols` array on the device. On a device sharing memory with
+    /// the host this is the pinned memory itself, which must then not be mapped while
+    /// kernels using the array are still queued; elsewhere it is an uploaded copy.
+    pub fn stage(&self, rows: usize, cols: usize) -> Result<OpenCLArray, Error> {
+        assert_eq!(rows * cols, self.len);
+        if self.unified {
+            let lease = Lease::unpooled(self.buffer.clone());
+            return Ok(OpenCLArray::from_lease(
+                self.backend.clone(),
+                rows,
+                cols,
+                lease,
+            ));
+        }
+        let array = OpenCLArray::uninitialized(self.backend.clone(), rows, cols)?;
+        self.upload(&array)?;
+        Ok(array)
+    }
+}
+
+impl Deref for PinnedMap<'_> {
+    type Target = [f32];
+
+    fn deref(&self) -> &[f32] {
+        &self.map[..self.len]
+    }
+}
+
+impl DerefMut for PinnedMap<'_> {
+    fn deref_mut(&mut self) -> &mut [f32] {
+        &mut self.map[..self.len]
+    }
+}
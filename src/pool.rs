@@ -0,0 +1,161 @@
+use ocl::error::Error;
+use ocl::{Buffer, MemFlags, Queue};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+// Requests up to this many elements are rounded up to the next power of two; larger ones are
+// rounded up to a multiple of it, so big arrays don't waste up to half their allocation
+const LARGE_CLASS: usize = 1 << 20;
+
+/// The number of elements actually allocated on the device for a request of `len` elements.
+/// Buffers are only ever recycled within the same size class.
+pub fn size_class(len: usize) -> usize {
+    let len = len.max(1);
+    if len <= LARGE_CLASS {
+        len.next_power_of_two()
+    } else {
+        len.div_ceil(LARGE_CLASS) * LARGE_CLASS
+    }
+}
+
+/// Snapshot of a pool's bookkeeping, in bytes of device memory
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PoolStats {
+    /// Bytes held by live arrays
+    pub bytes_in_use: usize,
+    /// High-water mark of `bytes_in_use`
+    pub peak_bytes_in_use: usize,
+    /// Bytes held by freed buffers waiting to be recycled
+    pub bytes_cached: usize,
+    /// Number of buffers created on the device
+    pub allocations: usize,
+    /// Number of requests served from the cache
+    pub reuses: usize,
+}
+
+#[derive(Debug, Default)]
+struct PoolState {
+    free: HashMap<usize, Vec<Buffer<f32>>>,
+    stats: PoolStats,
+}
+
+/// A caching allocator which keeps the device buffers of dropped arrays around and hands them
+/// out again to later arrays of the same size class, instead of going back to the driver.
+///
+/// Recycled buffers are re-bound to the requesting queue; work still pending on the buffer's
+/// previous queue is not waited for, so free arrays on the queue that will reuse them.
+#[derive(Debug, Default)]
+pub struct BufferPool {
+    state: Mutex<PoolState>,
+}
+
+impl BufferPool {
+    pub fn new() -> Self {
+        BufferPool::default()
+    }
+
+    /// Hands out a buffer of at least `len` elements bound to `queue`. Its contents are
+    /// whatever was last written to it.
+    pub fn alloc(pool: &Arc<BufferPool>, queue: &Queue, len: usize) -> Result<Lease, Error> {
+        let class = size_class(len);
+        let bytes = class * std::mem::size_of::<f32>();
+        let mut state = pool.state.lock().unwrap();
+
+        let recycled = state.free.get_mut(&class).and_then(|buffers| buffers.pop());
+        let buffer = match recycled {
+            Some(mut buffer) => {
+                buffer.set_default_queue(queue.clone());
+                state.stats.bytes_cached -= bytes;
+                state.stats.reuses += 1;
+                buffer
+            }
+            None => {
+                let buffer = Buffer::<f32>::builder()
+                    .queue(queue.clone())
+                    .flags(MemFlags::new().read_write())
+                    .len(class)
+                    .build()?;
+                state.stats.allocations += 1;
+                buffer
+            }
+        };
+
+        state.stats.bytes_in_use += bytes;
+        state.stats.peak_bytes_in_use = state.stats.peak_bytes_in_use.max(state.stats.bytes_in_use);
+
+        Ok(Lease {
+            buffer,
+            pool: Some(pool.clone()),
+        })
+    }
+
+    /// Hands out a buffer of at least `len` elements whose first `len` elements are zeroed
+    /// on the device
+    pub fn alloc_zeroed(pool: &Arc<BufferPool>, queue: &Queue, len: usize) -> Result<Lease, Error> {
+        let lease = BufferPool::alloc(pool, queue, len)?;
+        lease
+            .buffer
+            .cmd()
+            .queue(queue)
+            .fill(0., Some(len.max(1)))
+            .enq()?;
+        Ok(lease)
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        self.state.lock().unwrap().stats
+    }
+
+    /// Releases every cached buffer back to the driver. Buffers held by live arrays are
+    /// unaffected.
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.free.clear();
+        state.stats.bytes_cached = 0;
+    }
+
+    fn release(&self, buffer: Buffer<f32>) {
+        let class = buffer.len();
+        let bytes = class * std::mem::size_of::<f32>();
+        let mut state = self.state.lock().unwrap();
+        state.stats.bytes_in_use -= bytes;
+        state.stats.bytes_cached += bytes;
+        state.free.entry(class).or_default().push(buffer);
+    }
+}
+
+/// Ownership of a pooled buffer. The buffer goes back to its pool when the lease is dropped,
+/// so arrays share one lease between all handles aliasing the same device memory.
+pub struct Lease {
+    buffer: Buffer<f32>,
+    pool: Option<Arc<BufferPool>>,
+}
+
+impl Lease {
+    /// A lease on a buffer allocated outside any pool, which is simply released when dropped
+    pub fn unpooled(buffer: Buffer<f32>) -> Self {
+        Lease { buffer, pool: None }
+    }
+
+    pub fn buffer(&self) -> &Buffer<f32> {
+        &self.buffer
+    }
+}
+
+impl fmt::Debug for Lease {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Lease")
+            .field("len", &self.buffer.len())
+            .finish()
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        if let Some(pool) = &self.pool {
+            pool.release(self.buffer.clone());
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use ocl::enums::ProfilingInfo;
+use ocl::error::Error;
+use ocl::Event;
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommandKind {
+    Kernel,
+    Transfer,
+}
+
+/// Timestamps of one profiled command, in nanoseconds on the device clock
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProfileRecord {
+    pub name: String,
+    pub kind: CommandKind,
+    /// Index of the backend queue the command ran on
+    pub queue: usize,
+    pub queued: u64,
+    pub submit: u64,
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ProfileRecord {
+    /// Time spent executing on the device
+    pub fn duration_ns(&self) -> u64 {
+        self.end.saturating_sub(self.start)
+    }
+}
+
+/// Aggregate device time of every command recorded under one name
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct OpStats {
+    pub count: usize,
+    pub total_ns: u64,
+    pub min_ns: u64,
+    pub max_ns: u64,
+}
+
+impl OpStats {
+    pub fn mean_ns(&self) -> f64 {
+        if self.count == 0 {
+            0.
+        } else {
+            self.total_ns as f64 / self.count as f64
+        }
+    }
+}
+
+struct Pending {
+    name: String,
+    kind: CommandKind,
+    queue: usize,
+    event: Event,
+}
+
+/// Collects OpenCL profiling events for every kernel launch and transfer made through a
+/// backend built with `CLBackEnd::with_profiler`. Events are only resolved into timestamps
+/// when a report is asked for, so recording doesn't stall the queue.
+#[derive(Default)]
+pub struct Profiler {
+    pending: Mutex<Vec<Pending>>,
+    records: Mutex<Vec<ProfileRecord>>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler::default()
+    }
+
+    pub fn record(&self, name: &str, kind: CommandKind, queue: usize, event: Event) {
+        self.pending.lock().unwrap().push(Pending {
+            name: name.to_string(),
+            kind,
+            queue,
+            event,
+        });
+    }
+
+    /// Waits for every recorded command to complete and returns their timestamps, in the
+    /// order they were enqueued
+    pub fn records(&self) -> Result<Vec<ProfileRecord>, Error> {
+        let pending: Vec<Pending> = self.pending.lock().unwrap().drain(..).collect();
+        let mut records = self.records.lock().unwrap();
+        for p in pending {
+            p.event.wait_for()?;
+            let time = |info| -> Result<u64, Error> { Ok(p.event.profiling_info(info)?.time()?) };
+            records.push(ProfileRecord {
+                queued: time(ProfilingInfo::Queued)?,
+                submit: time(ProfilingInfo::Submit)?,
+                start: time(ProfilingInfo::Start)?,
+                end: time(ProfilingInfo::End)?,
+                name: p.name,
+                kind: p.kind,
+                queue: p.queue,
+            });
+        }
+        Ok(records.clone())
+    }
+
+    /// Per-name statistics over everything recorded so far
+    pub fn summary(&self) -> Result<BTreeMap<String, OpStats>, Error> {
+        let mut summary: BTreeMap<String, OpStats> = BTreeMap::new();
+        for record in self.records()? {
+            let ns = record.duration_ns();
+            let stats = summary.entry(record.name).or_default();
+            if stats.count == 0 || ns < stats.min_ns {
+                stats.min_ns = ns;
+            }
+            stats.max_ns = stats.max_ns.max(ns);
+            stats.total_ns += ns;
+            stats.count += 1;
+        }
+        Ok(summary)
+    }
+
+    /// Everything recorded so far in the Chrome trace event format, viewable in
+    /// `chrome://tracing` or Perfetto. Each backend queue is shown as its own thread.
+    pub fn chrome_trace(&self) -> Result<String, Error> {
+        let records = self.records()?;
+        let origin = records.iter().map(|r| r.queued).min().unwrap_or(0);
+
+        let mut json = String::from("{\"traceEvents\":[");
+        for (idx, record) in records.iter().enumerate() {
+            if idx > 0 {
+                json.push(',');
+            }
+            let cat = match record.kind {
+                CommandKind::Kernel => "kernel",
+                CommandKind::Transfer => "transfer",
+            };
+            let _ = write!(
+                json,
+                "{{\"name\":\"{}\",\"cat\":\"{}\",\"ph\":\"X\",\"pid\":0,\"tid\":{},\
+                 \"ts\":{:.3},\"dur\":{:.3},\"args\":{{\"queued_us\":{:.3},\"submit_us\":{:.3}}}}}",
+                record.name.replace('\\', "\\\\").replace('"', "\\\""),
+                cat,
+                record.queue,
+                (record.start - origin) as f64 / 1e3,
+                record.duration_ns() as f64 / 1e3,
+                (record.queued - origin) as f64 / 1e3,
+                (record.submit - origin) as f64 / 1e3,
+            );
+        }
+        json.push_str("],\"displayTimeUnit\":\"ns\"}");
+        Ok(json)
+    }
+
+    pub fn write_chrome_trace<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        fs::write(path, self.chrome_trace()?)?;
+        Ok(())
+    }
+
+    /// Forgets everything recorded so far
+    pub fn clear(&self) {
+        self.pending.lock().unwrap().clear();
+        self.records.lock().unwrap().clear();
+    }
+}
+
+impl std::fmt::Debug for Profiler {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Profiler")
+            .field("pending", &self.pending.lock().unwrap().len())
+            .field("records", &self.records.lock().unwrap().len())
+            .finish()
+    }
+}
@@ -0,0 +1,169 @@
+use crate::blas::*;
+use crate::kernels::*;
+use crate::opencl::*;
+
+use ndarray::Axis;
+use ocl::error::Error;
+use ocl::SpatialDims::*;
+
+// Joining and splitting arrays along rows (`Axis(0)`) or columns (`Axis(1)`), entirely on the
+// device. Blocks of whole rows are contiguous in the row-major buffers and are moved with
+// buffer copies; column blocks go through the strided `copy_block` kernel. Transposed views
+// are made contiguous first.
+
+// The `rows x cols` block of `src` starting at element `src_offset`, with consecutive rows
+// `src_stride` apart, copied into `dst` likewise
+#[allow(clippy::too_many_arguments)]
+fn copy_block(
+    src: &OpenCLArray,
+    src_offset: usize,
+    src_stride: usize,
+    dst: &OpenCLArray,
+    dst_offset: usize,
+    dst_stride: usize,
+    rows: usize,
+    cols: usize,
+) -> Result<(), Error> {
+    if src_stride == cols && dst_stride == cols {
+        return copy_region(src, src_offset, dst, dst_offset, rows * cols);
+    }
+    src.backend.enq_kernel(
+        "copy_block",
+        &[
+            Arg::Buffer(&src.v),
+            Arg::Buffer(&dst.v),
+            Arg::Ulong(rows as u64),
+            Arg::Ulong(cols as u64),
+            Arg::Ulong(src_offset as u64),
+            Arg::Ulong(src_stride as u64),
+            Arg::Ulong(dst_offset as u64),
+            Arg::Ulong(dst_stride as u64),
+        ],
+        Two(rows, cols),
+    )
+}
+
+impl OpenCLArray {
+    /// The arrays joined along `axis`: one above the other for `Axis(0)`, which needs them to
+    /// have the same number of columns, or side by side for `Axis(1)`
+    pub fn concatenate(axis: Axis, arrays: &[&OpenCLArray]) -> Result<OpenCLArray, Error> {
+        assert!(!arrays.is_empty());
+        assert!(axis.index() < 2);
+        let arrays = arrays
+            .iter()
+            .map(|a| a.contiguous())
+            .collect::<Result<Vec<_>, Error>>()?;
+        let (rows, cols) = if axis == Axis(0) {
+            let cols = arrays[0].cols;
+            assert!(arrays.iter().all(|a| a.cols == cols));
+            (arrays.iter().map(|a| a.rows).sum(), cols)
+        } else {
+            let rows = arrays[0].rows;
+            assert!(arrays.iter().all(|a| a.rows == rows));
+            (rows, arrays.iter().map(|a| a.cols).sum())
+        };
+
+        let result = OpenCLArray::uninitialized(arrays[0].backend.clone(), rows, cols)?;
+        let mut offset = 0;
+        for a in &arrays {
+            copy_block(a, 0, a.cols, &result, offset, cols, a.rows, a.cols)?;
+            offset += if axis == Axis(0) { a.len() } else { a.cols };
+        }
+        Ok(result)
+    }
+
+    /// Arrays of the same shape one above the other, the layout `batched_dot` takes a batch in
+    pub fn stack(arrays: &[&OpenCLArray]) -> Result<OpenCLArray, Error> {
+        assert!(arrays
+            .iter()
+            .all(|a| (a.rows, a.cols) == (arrays[0].rows, arrays[0].cols)));
+        OpenCLArray::concatenate(Axis(0), arrays)
+    }
+
+    /// The rows (`Axis(0)`) or columns (`Axis(1)`) before `index` and those from it on, as
+    /// two new arrays
+    pub fn split_at(&self, axis: Axis, index: usize) -> Result<(OpenCLArray, OpenCLArray), Error> {
+        let (a, len) = (self.contiguous()?, self.len_of(axis));
+        assert!(index <= len);
+        Ok((a.block(axis, 0, index)?, a.block(axis, index, len - index)?))
+    }
+
+    /// Consecutive blocks of `size` rows or columns, the last one smaller if `size` doesn't
+    /// divide the array; e.g. the mini-batches of a data set
+    pub fn chunks(&self, axis: Axis, size: usize) -> Result<Vec<OpenCLArray>, Error> {
+        assert!(size > 0);
+        let (a, len) = (self.contiguous()?, self.len_of(axis));
+        (0..len)
+            .step_by(size)
+            .map(|start| a.block(axis, start, size.min(len - start)))
+            .collect()
+    }
+
+    /// The array repeated `row_reps` times downwards and `col_reps` times across
+    pub fn tile(&self, row_reps: usize, col_reps: usize) -> Result<OpenCLArray, Error> {
+        let a = self.contiguous()?;
+        let (rows, cols) = (a.rows * row_reps, a.cols * col_reps);
+        let result = OpenCLArray::uninitialized(a.backend.clone(), rows, cols)?;
+        a.backend.enq_kernel(
+            "tile",
+            &[
+                Arg::Buffer(&a.v),
+                Arg::Buffer(&result.v),
+                Arg::Ulong(a.rows as u64),
+                Arg::Ulong(a.cols as u64),
+                Arg::Ulong(rows as u64),
+                Arg::Ulong(cols as u64),
+            ],
+            Two(rows, cols),
+        )?;
+        Ok(result)
+    }
+
+    /// Every row (`Axis(0)`) or column (`Axis(1)`) repeated `reps` times in place, so
+    /// `[a, b]` becomes `[a, a, b, b]` for two repetitions
+    pub fn repeat(&self, axis: Axis, reps: usize) -> Result<OpenCLArray, Error> {
+        assert!(axis.index() < 2);
+        let a = self.contiguous()?;
+        let (row_reps, col_reps) = if axis == Axis(0) {
+            (reps, 1)
+        } else {
+            (1, reps)
+        };
+        let (rows, cols) = (a.rows * row_reps, a.cols * col_reps);
+        let result = OpenCLArray::uninitialized(a.backend.clone(), rows, cols)?;
+        a.backend.enq_kernel(
+            "repeat",
+            &[
+                Arg::Buffer(&a.v),
+                Arg::Buffer(&result.v),
+                Arg::Ulong(a.cols as u64),
+                Arg::Ulong(rows as u64),
+                Arg::Ulong(cols as u64),
+                Arg::Ulong(row_reps as u64),
+                Arg::Ulong(col_reps as u64),
+            ],
+            Two(rows, cols),
+        )?;
+        Ok(result)
+    }
+
+    fn len_of(&self, axis: Axis) -> usize {
+        match axis.index() {
+            0 => self.rows,
+            1 => self.cols,
+            _ => panic!("arrays only have axes 0 and 1"),
+        }
+    }
+
+    // A copy of `len` rows or columns starting at `start`, of an array which isn't a view
+    fn block(&self, axis: Axis, start: usize, len: usize) -> Result<OpenCLArray, Error> {
+        let (rows, cols, offset) = if axis == Axis(0) {
+            (len, self.cols, start * self.cols)
+        } else {
+            (self.rows, len, start)
+        };
+        let result = OpenCLArray::uninitialized(self.backend.clone(), rows, cols)?;
+        copy_block(self, offset, self.cols, &result, 0, cols, rows, cols)?;
+        Ok(result)
+    }
+}
@@ -0,0 +1,352 @@
+use crate::kernels::*;
+use crate::opencl::*;
+
+use ocl::error::Error;
+use ocl::SpatialDims::*;
+
+// Krylov solvers for `A x = b`, where `b` and `x` are `n x 1` arrays. Every vector stays on
+// the device; only the scalars the iterations branch on (inner products and norms) are read
+// back.
+
+/// Anything which can compute `y = A x` for an `n x 1` array `x`, so the solvers don't need
+/// `A` to be stored densely. Dense arrays and closures implement it.
+pub trait LinearOperator {
+    fn apply(&self, x: &OpenCLArray, y: &mut OpenCLArray) -> Result<(), Error>;
+}
+
+impl LinearOperator for OpenCLArray {
+    fn apply(&self, x: &OpenCLArray, y: &mut OpenCLArray) -> Result<(), Error> {
+        self.dot(x, y)
+    }
+}
+
+impl<F> LinearOperator for F
+where
+    F: Fn(&OpenCLArray, &mut OpenCLArray) -> Result<(), Error>,
+{
+    fn apply(&self, x: &OpenCLArray, y: &mut OpenCLArray) -> Result<(), Error> {
+        self(x, y)
+    }
+}
+
+/// Computes `z = M^-1 r` for some cheap approximation `M` of `A`
+pub trait Preconditioner {
+    fn apply(&self, r: &OpenCLArray, z: &mut OpenCLArray) -> Result<(), Error>;
+}
+
+/// Scales by the inverse of `A`'s diagonal
+#[derive(Debug, Clone)]
+pub struct Jacobi {
+    /// `n x 1` array holding `1 / A[i][i]`
+    pub inverse_diagonal: OpenCLArray,
+}
+
+impl Jacobi {
+    pub fn new(a: &OpenCLArray) -> Result<Self, Error> {
+        assert_eq!(a.rows, a.cols);
+        let inverse_diagonal = OpenCLArray::uninitialized(a.backend.clone(), a.rows, 1)?;
+        a.backend.enq_kernel(
+            "inverse_diagonal",
+            &[
+                Arg::Buffer(&a.v),
+                Arg::Buffer(&inverse_diagonal.v),
+                Arg::Ulong(a.rows as u64),
+            ],
+            One(a.rows),
+        )?;
+        Ok(Jacobi { inverse_diagonal })
+    }
+}
+
+impl Preconditioner for Jacobi {
+    fn apply(&self, r: &OpenCLArray, z: &mut OpenCLArray) -> Result<(), Error> {
+        self.inverse_diagonal.hadamard(r, z)
+    }
+}
+
+/// Stopping criteria and preconditioning shared by every solver
+#[derive(Clone, Copy)]
+pub struct SolverOptions<'a> {
+    /// Stop once `||b - A x|| <= tol * ||b||`
+    pub tol: f32,
+    /// Stop after this many iterations (matrix-vector products, for GMRES) regardless
+    pub max_iter: usize,
+    /// Krylov subspace dimension after which GMRES restarts
+    pub restart: usize,
+    pub preconditioner: Option<&'a dyn Preconditioner>,
+}
+
+impl<'a> Default for SolverOptions<'a> {
+    fn default() -> Self {
+        SolverOptions {
+            tol: 1e-5,
+            max_iter: 1000,
+            restart: 30,
+            preconditioner: None,
+        }
+    }
+}
+
+impl<'a> SolverOptions<'a> {
+    // z = M^-1 r, or a copy of r without a preconditioner
+    fn precondition(&self, r: &OpenCLArray, z: &mut OpenCLArray) -> Result<(), Error> {
+        match self.preconditioner {
+            Some(m) => m.apply(r, z),
+            None => z.axpby(1., r, 0.),
+        }
+    }
+}
+
+/// How a solve ended
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConvergenceReport {
+    pub converged: bool,
+    pub iterations: usize,
+    /// `||b - A x||` at the end, as tracked by the solver
+    pub residual_norm: f32,
+    /// The residual norm after every iteration, starting with the initial guess
+    pub residual_history: Vec<f32>,
+}
+
+impl ConvergenceReport {
+    fn new(residual_norm: f32) -> Self {
+        ConvergenceReport {
+            converged: false,
+            iterations: 0,
+            residual_norm,
+            residual_history: vec![residual_norm],
+        }
+    }
+
+    fn push(&mut self, residual_norm: f32) {
+        self.iterations += 1;
+        self.residual_norm = residual_norm;
+        self.residual_history.push(residual_norm);
+    }
+}
+
+fn zeros_like(x: &OpenCLArray) -> Result<OpenCLArray, Error> {
+    OpenCLArray::new(x.backend.clone(), x.rows, x.cols)
+}
+
+// r = b - A x
+fn residual<A: LinearOperator>(
+    a: &A,
+    b: &OpenCLArray,
+    x: &OpenCLArray,
+    r: &mut OpenCLArray,
+) -> Result<(), Error> {
+    a.apply(x, r)?;
+    r.axpby(1., b, -1.)
+}
+
+fn check_shapes(b: &OpenCLArray, x: &OpenCLArray) {
+    assert_eq!(b.cols, 1);
+    assert_eq!((x.rows, x.cols), (b.rows, b.cols));
+}
+
+/// Preconditioned conjugate gradient, for symmetric positive definite `A`. `x` holds the
+/// initial guess and is overwritten with the solution.
+pub fn cg<A: LinearOperator>(
+    a: &A,
+    b: &OpenCLArray,
+    x: &mut OpenCLArray,
+    options: &SolverOptions,
+) -> Result<ConvergenceReport, Error> {
+    check_shapes(b, x);
+    let target = options.tol * b.norm()?;
+    let mut r = zeros_like(b)?;
+    residual(a, b, x, &mut r)?;
+    let mut report = ConvergenceReport::new(r.norm()?);
+    if report.residual_norm <= target {
+        report.converged = true;
+        return Ok(report);
+    }
+
+    let mut z = zeros_like(b)?;
+    options.precondition(&r, &mut z)?;
+    let mut p = z.deep_clone()?;
+    let mut ap = zeros_like(b)?;
+    let mut rz = r.inner(&z)?;
+
+    while report.iterations < options.max_iter {
+        a.apply(&p, &mut ap)?;
+        let alpha = rz / p.inner(&ap)?;
+        x.axpy(alpha, &p)?;
+        r.axpy(-alpha, &ap)?;
+        report.push(r.norm()?);
+        if report.residual_norm <= target {
+            report.converged = true;
+            break;
+        }
+
+        options.precondition(&r, &mut z)?;
+        let rz_next = r.inner(&z)?;
+        p.axpby(1., &z, rz_next / rz)?;
+        rz = rz_next;
+    }
+    Ok(report)
+}
+
+/// Right-preconditioned BiCGSTAB, for general square `A`. `x` holds the initial guess and is
+/// overwritten with the solution. Stops early, unconverged, on breakdown.
+pub fn bicgstab<A: LinearOperator>(
+    a: &A,
+    b: &OpenCLArray,
+    x: &mut OpenCLArray,
+    options: &SolverOptions,
+) -> Result<ConvergenceReport, Error> {
+    check_shapes(b, x);
+    let target = options.tol * b.norm()?;
+    let mut r = zeros_like(b)?;
+    residual(a, b, x, &mut r)?;
+    let mut report = ConvergenceReport::new(r.norm()?);
+    if report.residual_norm <= target {
+        report.converged = true;
+        return Ok(report);
+    }
+
+    let r_hat = r.deep_clone()?;
+    let mut p = zeros_like(b)?;
+    let mut v = zeros_like(b)?;
+    let mut y = zeros_like(b)?;
+    let mut z = zeros_like(b)?;
+    let mut t = zeros_like(b)?;
+    let (mut rho, mut alpha, mut omega) = (1., 1., 1.);
+
+    while report.iterations < options.max_iter {
+        let rho_next = r_hat.inner(&r)?;
+        if rho_next == 0. || omega == 0. {
+            break;
+        }
+        let beta = (rho_next / rho) * (alpha / omega);
+        rho = rho_next;
+
+        // p = r + beta (p - omega v)
+        p.axpy(-omega, &v)?;
+        p.axpby(1., &r, beta)?;
+        options.precondition(&p, &mut y)?;
+        a.apply(&y, &mut v)?;
+        alpha = rho / r_hat.inner(&v)?;
+        x.axpy(alpha, &y)?;
+
+        // r now holds s = r - alpha v
+        r.axpy(-alpha, &v)?;
+        let s_norm = r.norm()?;
+        if s_norm <= target {
+            report.push(s_norm);
+            report.converged = true;
+            break;
+        }
+
+        options.precondition(&r, &mut z)?;
+        a.apply(&z, &mut t)?;
+        omega = t.inner(&r)? / t.inner(&t)?;
+        x.axpy(omega, &z)?;
+        r.axpy(-omega, &t)?;
+        report.push(r.norm()?);
+        if report.residual_norm <= target {
+            report.converged = true;
+            break;
+        }
+    }
+    Ok(report)
+}
+
+/// Restarted, right-preconditioned GMRES, for general square `A`. `x` holds the initial guess
+/// and is overwritten with the solution. Each restart keeps `options.restart` basis vectors on
+/// the device; the small Hessenberg least-squares problem is solved on the host.
+pub fn gmres<A: LinearOperator>(
+    a: &A,
+    b: &OpenCLArray,
+    x: &mut OpenCLArray,
+    options: &SolverOptions,
+) -> Result<ConvergenceReport, Error> {
+    check_shapes(b, x);
+    assert!(options.restart > 0);
+    let m = options.restart;
+    let target = options.tol * b.norm()?;
+    let mut r = zeros_like(b)?;
+    residual(a, b, x, &mut r)?;
+    let mut report = ConvergenceReport::new(r.norm()?);
+    let mut z = zeros_like(b)?;
+    let mut w = zeros_like(b)?;
+
+    while report.residual_norm > target && report.iterations < options.max_iter {
+        let beta = report.residual_norm;
+        let mut basis = vec![zeros_like(b)?];
+        basis[0].axpby(1. / beta, &r, 0.)?;
+
+        // Column j of the Hessenberg matrix, already rotated into upper triangular form
+        let mut h: Vec<Vec<f32>> = Vec::with_capacity(m);
+        let mut rotations: Vec<(f32, f32)> = Vec::with_capacity(m);
+        let mut g = vec![0.; m + 1];
+        g[0] = beta;
+
+        for j in 0..m {
+            options.precondition(&basis[j], &mut z)?;
+            a.apply(&z, &mut w)?;
+
+            // Modified Gram-Schmidt against the basis so far
+            let mut column = vec![0.; j + 2];
+            for (i, v) in basis.iter().enumerate() {
+                column[i] = w.inner(v)?;
+                w.axpy(-column[i], v)?;
+            }
+            column[j + 1] = w.norm()?;
+            let breakdown = column[j + 1] == 0.;
+            if !breakdown {
+                let mut v = zeros_like(b)?;
+                v.axpby(1. / column[j + 1], &w, 0.)?;
+                basis.push(v);
+            }
+
+            for (i, &(c, s)) in rotations.iter().enumerate() {
+                let (hi, hk) = (column[i], column[i + 1]);
+                column[i] = c * hi + s * hk;
+                column[i + 1] = -s * hi + c * hk;
+            }
+            let norm = column[j].hypot(column[j + 1]);
+            let (c, s) = if norm == 0. {
+                (1., 0.)
+            } else {
+                (column[j] / norm, column[j + 1] / norm)
+            };
+            column[j] = norm;
+            column[j + 1] = 0.;
+            g[j + 1] = -s * g[j];
+            g[j] *= c;
+            rotations.push((c, s));
+            h.push(column);
+
+            report.push(g[j + 1].abs());
+            if breakdown || report.residual_norm <= target || report.iterations >= options.max_iter
+            {
+                break;
+            }
+        }
+
+        // Back-substitute H y = g, then x += M^-1 (V y)
+        let k = h.len();
+        let mut y = vec![0.; k];
+        for i in (0..k).rev() {
+            let sum: f32 = (i + 1..k).map(|l| h[l][i] * y[l]).sum();
+            y[i] = (g[i] - sum) / h[i][i];
+        }
+        let mut update = zeros_like(b)?;
+        for (v, &yi) in basis.iter().zip(&y) {
+            update.axpy(yi, v)?;
+        }
+        options.precondition(&update, &mut z)?;
+        x.axpy(1., &z)?;
+
+        // The true residual, which the rotated estimate can drift from
+        residual(a, b, x, &mut r)?;
+        report.residual_norm = r.norm()?;
+        if let Some(last) = report.residual_history.last_mut() {
+            *last = report.residual_norm;
+        }
+    }
+    report.converged = report.residual_norm <= target;
+    Ok(report)
+}
@@ -0,0 +1,236 @@
+use crate::kernels::*;
+use crate::opencl::*;
+use crate::solvers::*;
+
+use ndarray::prelude::*;
+use ocl::error::Error;
+use ocl::{Buffer, MemFlags, Queue, SpatialDims::*};
+
+/// A sparse matrix in compressed sparse row format, stored on the device. Column indices are
+/// sorted within each row and there are no duplicate entries.
+#[derive(Debug, Clone)]
+pub struct OpenCLCsrMatrix {
+    pub backend: CLBackEnd,
+    pub rows: usize,
+    pub cols: usize,
+    /// `rows + 1` offsets into `col_idx` and `values`; row `i` holds entries
+    /// `row_ptr[i]..row_ptr[i + 1]`
+    pub row_ptr: Buffer<u64>,
+    pub col_idx: Buffer<u64>,
+    /// The non-zero entries, as a `1 x nnz` array
+    pub values: OpenCLArray,
+}
+
+/// Host-side row pointers, column indices and values of a CSR matrix
+pub type CsrParts = (Vec<u64>, Vec<u64>, Vec<f32>);
+
+pub(crate) fn index_buffer(queue: &Queue, data: &[u64]) -> Result<Buffer<u64>, Error> {
+    // OpenCL doesn't allow empty buffers, so an empty matrix gets a one-element placeholder
+    let buffer = Buffer::<u64>::builder()
+        .queue(queue.clone())
+        .flags(MemFlags::new().read_only())
+        .len(data.len().max(1))
+        .build()?;
+    if !data.is_empty() {
+        buffer.write(data).enq()?;
+    }
+    Ok(buffer)
+}
+
+pub(crate) fn read_indices(
+    buffer: &Buffer<u64>,
+    queue: &Queue,
+    len: usize,
+) -> Result<Vec<u64>, Error> {
+    let mut data = vec![0; len];
+    if len > 0 {
+        buffer.read(&mut data).queue(queue).enq()?;
+    }
+    Ok(data)
+}
+
+// Checks the structure the kernels rely on: `rows + 1` non-decreasing row pointers spanning
+// all the values, and in-range, strictly increasing column indices within every row
+fn validate_csr(
+    rows: usize,
+    cols: usize,
+    row_ptr: &[u64],
+    col_idx: &[u64],
+    nnz: usize,
+) -> Result<(), String> {
+    if row_ptr.len() != rows + 1 || row_ptr[0] != 0 {
+        return Err(format!("expected {} row pointers starting at 0", rows + 1));
+    }
+    if let Some(i) = row_ptr.windows(2).position(|w| w[0] > w[1]) {
+        return Err(format!("row pointers decrease at row {}", i));
+    }
+    if row_ptr[rows] as usize != nnz || col_idx.len() != nnz {
+        return Err(format!(
+            "{} values and {} column indices for {} entries",
+            nnz,
+            col_idx.len(),
+            row_ptr[rows]
+        ));
+    }
+    for (i, w) in row_ptr.windows(2).enumerate() {
+        let row = &col_idx[w[0] as usize..w[1] as usize];
+        if let Some(&j) = row.iter().find(|&&j| j as usize >= cols) {
+            return Err(format!("column {} in row {} is out of range", j, i));
+        }
+        if row.windows(2).any(|p| p[0] >= p[1]) {
+            return Err(format!("columns in row {} aren't sorted and unique", i));
+        }
+    }
+    Ok(())
+}
+
+impl OpenCLCsrMatrix {
+    /// Uploads a matrix already in CSR form, with sorted and unique column indices in each
+    /// row. Malformed input is an error rather than being uploaded.
+    pub fn from_csr(
+        backend: CLBackEnd,
+        rows: usize,
+        cols: usize,
+        row_ptr: Vec<u64>,
+        col_idx: Vec<u64>,
+        values: Vec<f32>,
+    ) -> Result<Self, Error> {
+        validate_csr(rows, cols, &row_ptr, &col_idx, values.len())
+            .map_err(|msg| Error::from(format!("invalid CSR matrix: {}", msg)))?;
+
+        let nnz = values.len();
+        Ok(OpenCLCsrMatrix {
+            row_ptr: index_buffer(backend.queue(), &row_ptr)?,
+            col_idx: index_buffer(backend.queue(), &col_idx)?,
+            values: OpenCLArray::from_vec(backend.clone(), 1, nnz, values)?,
+            backend,
+            rows,
+            cols,
+        })
+    }
+
+    /// Builds a matrix from `(row, col, value)` triplets in any order. Values given for the
+    /// same entry more than once are summed.
+    pub fn from_triplets(
+        backend: CLBackEnd,
+        rows: usize,
+        cols: usize,
+        triplets: &[(usize, usize, f32)],
+    ) -> Result<Self, Error> {
+        let mut triplets = triplets.to_vec();
+        triplets.sort_by_key(|&(i, j, _)| (i, j));
+
+        let mut row_ptr = vec![0u64; rows + 1];
+        let mut col_idx: Vec<u64> = Vec::with_capacity(triplets.len());
+        let mut values: Vec<f32> = Vec::with_capacity(triplets.len());
+        let mut last = None;
+        for (i, j, x) in triplets {
+            assert!(i < rows && j < cols);
+            if last == Some((i, j)) {
+                *values.last_mut().unwrap() += x;
+                continue;
+            }
+            last = Some((i, j));
+            row_ptr[i + 1] += 1;
+            col_idx.push(j as u64);
+            values.push(x);
+        }
+        for i in 0..rows {
+            row_ptr[i + 1] += row_ptr[i];
+        }
+        OpenCLCsrMatrix::from_csr(backend, rows, cols, row_ptr, col_idx, values)
+    }
+
+    /// Keeps the non-zero entries of a dense matrix
+    pub fn from_array(backend: CLBackEnd, array: &Array2<f32>) -> Result<Self, Error> {
+        let triplets: Vec<(usize, usize, f32)> = array
+            .indexed_iter()
+            .filter(|(_, &x)| x != 0.)
+            .map(|((i, j), &x)| (i, j, x))
+            .collect();
+        OpenCLCsrMatrix::from_triplets(backend, array.nrows(), array.ncols(), &triplets)
+    }
+
+    /// The number of stored entries
+    pub fn nnz(&self) -> usize {
+        self.values.cols
+    }
+
+    /// Copies the row pointers, column indices and values back to the host
+    pub fn to_csr(&self) -> Result<CsrParts, Error> {
+        let queue = self.backend.queue();
+        Ok((
+            read_indices(&self.row_ptr, queue, self.rows + 1)?,
+            read_indices(&self.col_idx, queue, self.nnz())?,
+            self.values.clone().to_vec()?,
+        ))
+    }
+
+    /// `y = A x` for a `cols x 1` array `x`
+    pub fn spmv(&self, x: &OpenCLArray, y: &mut OpenCLArray) -> Result<(), Error> {
+        assert_eq!(x.cols, 1);
+        self.spmm(x, y)
+    }
+
+    /// `c = A b` for a dense `cols x k` array `b`
+    pub fn spmm(&self, b: &OpenCLArray, c: &mut OpenCLArray) -> Result<(), Error> {
+        assert_eq!(b.rows, self.cols);
+        assert_eq!((c.rows, c.cols), (self.rows, b.cols));
+        assert!(!c.transposed);
+        let b = b.contiguous()?;
+
+        self.backend.enq_kernel(
+            "csr_spmm",
+            &[
+                Arg::Indices(&self.row_ptr),
+                Arg::Indices(&self.col_idx),
+                Arg::Buffer(&self.values.v),
+                Arg::Buffer(&b.v),
+                Arg::Buffer(&c.v),
+                Arg::Ulong(self.rows as u64),
+                Arg::Ulong(b.cols as u64),
+            ],
+            Two(self.rows, b.cols),
+        )
+    }
+
+    /// The transposed matrix, also in CSR form. The conversion is done on the host.
+    pub fn t(&self) -> Result<OpenCLCsrMatrix, Error> {
+        let (row_ptr, col_idx, values) = self.to_csr()?;
+        let mut triplets = Vec::with_capacity(values.len());
+        for i in 0..self.rows {
+            for p in row_ptr[i] as usize..row_ptr[i + 1] as usize {
+                triplets.push((col_idx[p] as usize, i, values[p]));
+            }
+        }
+        OpenCLCsrMatrix::from_triplets(self.backend.clone(), self.cols, self.rows, &triplets)
+    }
+
+    /// Expands the matrix into a dense array on the device
+    pub fn to_dense(&self) -> Result<OpenCLArray, Error> {
+        let dense = OpenCLArray::new(self.backend.clone(), self.rows, self.cols)?;
+        self.backend.enq_kernel(
+            "csr_to_dense",
+            &[
+                Arg::Indices(&self.row_ptr),
+                Arg::Indices(&self.col_idx),
+                Arg::Buffer(&self.values.v),
+                Arg::Buffer(&dense.v),
+                Arg::Ulong(self.rows as u64),
+                Arg::Ulong(self.cols as u64),
+            ],
+            One(self.rows),
+        )?;
+        Ok(dense)
+    }
+
+    pub fn to_array(&self) -> Result<Array2<f32>, Error> {
+        self.to_dense()?.to_array()
+    }
+}
+
+impl LinearOperator for OpenCLCsrMatrix {
+    fn apply(&self, x: &OpenCLArray, y: &mut OpenCLArray) -> Result<(), Error> {
+        self.spmv(x, y)
+    }
+}
@@ -0,0 +1,237 @@
+// Differential testing: every op is run on the device and on an ndarray reference over the
+// same random inputs, for a spread of shapes, and the results are compared element by element.
+//
+// The device is picked with `CARYA_TEST_DEVICE` (a substring of its name, "GeForce" by
+// default) and optionally `CARYA_TEST_PLATFORM`, so the suite also runs on a CPU OpenCL
+// implementation, e.g. `CARYA_TEST_DEVICE=cpu CARYA_TEST_PLATFORM=Portable cargo test` for
+// POCL. `CARYA_TEST_SEED` changes the random inputs; failures print the seed to rerun with.
+
+use crate::config::*;
+use crate::opencl::*;
+
+use ndarray::prelude::*;
+use ndarray_rand::rand::rngs::StdRng;
+use ndarray_rand::rand::{Rng, SeedableRng};
+use ndarray_rand::rand_distr::Uniform;
+use ndarray_rand::RandomExt;
+use ocl::Error;
+use proptest::prelude::*;
+
+use std::env;
+
+const DEFAULT_SEED: u64 = 0x5eed;
+
+/// Dimensions every differential test covers: one, odd sizes, sizes either side of the
+/// default tile sides, and a few primes too large to fit in a single work-group
+pub const EDGE_DIMS: &[usize] = &[1, 2, 3, 7, 15, 16, 17, 31, 64, 97, 257, 509];
+
+/// The backend settings selected by the environment
+pub fn test_config() -> BackendConfig {
+    BackendConfig {
+        platform: env::var("CARYA_TEST_PLATFORM").ok(),
+        ..BackendConfig::new(&env::var("CARYA_TEST_DEVICE").unwrap_or_else(|_| "GeForce".into()))
+    }
+}
+
+pub fn test_backend() -> Result<CLBackEnd, Error> {
+    CLBackEnd::from_config(test_config())
+}
+
+pub fn test_seed() -> u64 {
+    env::var("CARYA_TEST_SEED")
+        .ok()
+        .and_then(|seed| seed.parse().ok())
+        .unwrap_or(DEFAULT_SEED)
+}
+
+/// How far a device result may be from the reference. An element passes if it is within
+/// `ulps` units in the last place, or within `absolute + relative * max(|actual|, |expected|)`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tolerance {
+    pub ulps: u32,
+    pub relative: f32,
+    pub absolute: f32,
+}
+
+impl Tolerance {
+    pub const EXACT: Tolerance = Tolerance {
+        ulps: 0,
+        relative: 0.,
+        absolute: 0.,
+    };
+
+    pub fn ulps(ulps: u32) -> Self {
+        Tolerance {
+            ulps,
+            ..Tolerance::EXACT
+        }
+    }
+
+    /// For sums of `terms` products of values in [-1, 1], whose rounding error grows with
+    /// the number of terms and depends on the order they are added in
+    pub fn accumulated(terms: usize) -> Self {
+        Tolerance {
+            ulps: 4,
+            relative: 1e-5,
+            absolute: 1e-6 * terms.max(1) as f32,
+        }
+    }
+
+    pub fn accepts(&self, actual: f32, expected: f32) -> bool {
+        if actual == expected || (actual.is_nan() && expected.is_nan()) {
+            return true;
+        }
+        let bound = self.absolute + self.relative * actual.abs().max(expected.abs());
+        ulp_distance(actual, expected) <= self.ulps || (actual - expected).abs() <= bound
+    }
+}
+
+/// The number of representable floats between `a` and `b`, saturating for NaN or values of
+/// wildly different magnitudes
+pub fn ulp_distance(a: f32, b: f32) -> u32 {
+    if a.is_nan() || b.is_nan() {
+        return u32::MAX;
+    }
+    // Maps the floats onto the integers in order, with both zeros on 0
+    let ordered = |x: f32| {
+        let bits = x.to_bits() as i32;
+        if bits < 0 {
+            -i64::from(bits & i32::MAX)
+        } else {
+            i64::from(bits)
+        }
+    };
+    let distance = (ordered(a) - ordered(b)).abs();
+    distance.min(i64::from(u32::MAX)) as u32
+}
+
+/// Describes the first element outside `tolerance`, its index and both values
+pub fn compare(
+    op: &str,
+    actual: &Array2<f32>,
+    expected: &Array2<f32>,
+    tolerance: Tolerance,
+) -> Result<(), String> {
+    if actual.dim() != expected.dim() {
+        return Err(format!(
+            "{}: shape {:?} != {:?}",
+            op,
+            actual.dim(),
+            expected.dim()
+        ));
+    }
+    let mismatch = actual
+        .indexed_iter()
+        .zip(expected.iter())
+        .find(|((_, &x), &y)| !tolerance.accepts(x, y));
+    match mismatch {
+        Some(((index, &x), &y)) => Err(format!(
+            "{} on a {:?} result: {} != {} at {:?} ({} ulps, tolerance {:?})",
+            op,
+            actual.dim(),
+            x,
+            y,
+            index,
+            ulp_distance(x, y),
+            tolerance
+        )),
+        None => Ok(()),
+    }
+}
+
+pub fn assert_all_close(
+    op: &str,
+    actual: &Array2<f32>,
+    expected: &Array2<f32>,
+    tolerance: Tolerance,
+) {
+    if let Err(message) = compare(op, actual, expected, tolerance) {
+        panic!("{} (CARYA_TEST_SEED={})", message, test_seed());
+    }
+}
+
+/// An array of the given shape with entries in [-1, 1]
+pub fn array_strategy(shape: (usize, usize)) -> impl Strategy<Value = Array2<f32>> {
+    proptest::collection::vec(-1f32..=1., shape.0 * shape.1)
+        .prop_map(move |v| Array2::from_shape_vec(shape, v).unwrap())
+}
+
+/// Operands sized by `shapes` from a dimension triple `(n, m, k)`, each dimension between 1
+/// and `max_dim`. The dimensions shrink first, so proptest reports the smallest shapes which
+/// still fail.
+pub fn operands_strategy(
+    max_dim: usize,
+    shapes: fn(usize, usize, usize) -> Vec<(usize, usize)>,
+) -> impl Strategy<Value = Vec<Array2<f32>>> {
+    (1..=max_dim, 1..=max_dim, 1..=max_dim).prop_flat_map(move |(n, m, k)| {
+        shapes(n, m, k)
+            .into_iter()
+            .map(array_strategy)
+            .collect::<Vec<_>>()
+    })
+}
+
+/// Runs ops on the test device and compares them against a CPU reference
+pub struct Differential {
+    pub backend: CLBackEnd,
+    pub rng: StdRng,
+    /// Random dimensions drawn in addition to `EDGE_DIMS`
+    pub random_dims: usize,
+}
+
+impl Differential {
+    pub fn new() -> Result<Self, Error> {
+        Ok(Differential {
+            backend: test_backend()?,
+            rng: StdRng::seed_from_u64(test_seed()),
+            random_dims: 4,
+        })
+    }
+
+    /// Dimension triples `(n, m, k)` to size the operands with: every edge dimension paired
+    /// with random others, then a few entirely random triples
+    pub fn dims(&mut self) -> Vec<(usize, usize, usize)> {
+        let mut dims = vec![(1, 1, 1)];
+        for &d in EDGE_DIMS {
+            let (m, k) = (self.rng.gen_range(1, 40), self.rng.gen_range(1, 40));
+            dims.push((d, m, k));
+            dims.push((m, d, k));
+            dims.push((k, m, d));
+        }
+        for _ in 0..self.random_dims {
+            let mut dim = || self.rng.gen_range(1, 300);
+            dims.push((dim(), dim(), dim()));
+        }
+        dims
+    }
+
+    /// For every dimension triple, fills operands of the shapes `shapes` returns with values
+    /// in [-1, 1], then checks `device` against `reference` on them
+    pub fn check<S, D, R>(
+        &mut self,
+        op: &str,
+        tolerance: Tolerance,
+        shapes: S,
+        device: D,
+        reference: R,
+    ) -> Result<(), Error>
+    where
+        S: Fn(usize, usize, usize) -> Vec<(usize, usize)>,
+        D: Fn(&[OpenCLArray]) -> Result<OpenCLArray, Error>,
+        R: Fn(&[Array2<f32>]) -> Array2<f32>,
+    {
+        for (n, m, k) in self.dims() {
+            let inputs: Vec<Array2<f32>> = shapes(n, m, k)
+                .into_iter()
+                .map(|shape| Array::random_using(shape, Uniform::new(-1., 1.), &mut self.rng))
+                .collect();
+            let operands = inputs
+                .iter()
+                .map(|input| OpenCLArray::from_array(self.backend.clone(), input))
+                .collect::<Result<Vec<_>, Error>>()?;
+            let actual = device(&operands)?.to_array()?;
+            assert_all_close(op, &actual, &reference(&inputs), tolerance);
+        }
+        Ok(())
+    }
+}
@@ -1,23 +1,31 @@
+use crate::config::*;
+use crate::device::*;
+use crate::fill::*;
+use crate::indexing::*;
+use crate::kernels::*;
 use crate::opencl::*;
+use crate::pinned::*;
+use crate::pool::*;
+use crate::test_harness::*;
 
 use ndarray::prelude::*;
 #[cfg(test)]
 use ndarray_rand::rand_distr::Uniform;
 #[cfg(test)]
 use ndarray_rand::RandomExt;
+use proptest::prelude::*;
+use proptest::test_runner::{TestCaseError, TestRunner};
 
-use std::time::{Instant,Duration};
-
-use ocl::Error;
+use ocl::{Error, RwVec};
 
 
 #[test]
 #[serial]
 fn vec_squared() -> Result<(), Error> {
-    let backend = CLBackEnd::new("GeForce")?;
+    let backend = test_backend()?;
     let (n, m) = (1, 20);
     let mut a = OpenCLArray::from_vec(backend, n, m, vec![0.5; m * n])?;
-    &a.square();
+    a.square()?;
     let a_result = a.to_vec()?;
     println!("a_result: {:?}", a_result);
     assert_eq!(a_result, vec![0.25; n * m]);
@@ -28,11 +36,11 @@ fn vec_squared() -> Result<(), Error> {
 #[test]
 #[serial]
 fn array_squared() -> Result<(), Error> {
-    let backend = CLBackEnd::new("GeForce")?;
+    let backend = test_backend()?;
     let (n, m) = (20, 20);
-    let mut array = Array2::<f32>::from_elem((n, m), 2.);
+    let array = Array2::<f32>::from_elem((n, m), 2.);
     let mut a = OpenCLArray::from_array(backend, &array)?;
-    &a.square();
+    a.square()?;
     let array_result = a.to_array()?;
     println!("a_result:\n{:?}", array_result);
     assert_eq!(array_result, array.mapv(|x| x.powf(2.0)));
@@ -43,11 +51,11 @@ fn array_squared() -> Result<(), Error> {
 #[test]
 #[serial]
 fn array_transpose() -> Result<(), Error> {
-    let backend = CLBackEnd::new("GeForce")?;
+    let backend = test_backend()?;
 
-    let mut array = array![[1., 2., 3.], [4., 5., 6.]];
+    let array = array![[1., 2., 3.], [4., 5., 6.]];
 
-    let mut a = OpenCLArray::from_array(backend, &array)?;
+    let a = OpenCLArray::from_array(backend, &array)?;
     let b = a.t()?;
     let result = b.to_array()?;
     println!("result: {:#?}", result);
@@ -59,7 +67,8 @@ fn array_transpose() -> Result<(), Error> {
 #[test]
 #[serial]
 fn array_dot() -> Result<(), Error> {
-    let backend = CLBackEnd::new("GeForce")?;
+    // Timings of the same product live in `benches/ops.rs`
+    let backend = test_backend()?;
     // let a = array![[1., 2., 3.], [4., 5., 6.]];
     // let b = array![[1.,1.],[1.,1.],[1.,1.]];
     let (n,m,k) = (10000,784,10);
@@ -68,20 +77,11 @@ fn array_dot() -> Result<(), Error> {
 
     let mut c_gpu = OpenCLArray::new(backend.clone(),n,k)?;
     
-    let mut start = Instant::now();
     let c = a.dot(&b);
-    println!("c_cpu = a.b : {} ms",start.elapsed().as_millis());
 
-    start = Instant::now();
     let a_gpu = OpenCLArray::from_array(backend.clone(), &a)?;
-    println!("a -> gpu: {} ns",start.elapsed().as_nanos());
-    start = Instant::now();
     let b_gpu = OpenCLArray::from_array(backend, &b)?;
-    println!("b -> gpu: {} ns",start.elapsed().as_nanos());
-    start = Instant::now();
-    let mut gpu_start = Instant::now();
     a_gpu.dot(&b_gpu,&mut c_gpu)?;
-    println!("c_gpu = a.b : {} ms",gpu_start.elapsed().as_millis());
     let c_gpu = c_gpu.to_array()?;
 
     // println!("c:\n{:#?}", c);
@@ -107,7 +107,7 @@ fn array_dot() -> Result<(), Error> {
 #[test]
 #[serial]
 fn array_hadamard() -> Result<(), Error> {
-    let backend = CLBackEnd::new("GeForce")?;
+    let backend = test_backend()?;
 
     let a = Array::random((10, 3), Uniform::new(0., 1.));
     let b = Array::random((10, 3), Uniform::new(0., 1.));
@@ -130,7 +130,7 @@ fn array_hadamard() -> Result<(), Error> {
 #[test]
 #[serial]
 fn array_add() -> Result<(), Error> {
-    let backend = CLBackEnd::new("GeForce")?;
+    let backend = test_backend()?;
 
     let a = Array::random((10, 3), Uniform::new(0., 1.));
     let b = Array::random((10, 3), Uniform::new(0., 1.));
@@ -153,7 +153,7 @@ fn array_add() -> Result<(), Error> {
 #[test]
 #[serial]
 fn array_subtract() -> Result<(), Error> {
-    let backend = CLBackEnd::new("GeForce")?;
+    let backend = test_backend()?;
 
     let a = Array::random((10, 3), Uniform::new(0., 1.));
     let b = Array::random((10, 3), Uniform::new(0., 1.));
@@ -180,11 +180,11 @@ fn sigmoid_op(x: f32) -> f32 {
 #[test]
 #[serial]
 fn array_sigmoid() -> Result<(), Error> {
-    let backend = CLBackEnd::new("GeForce")?;
+    let backend = test_backend()?;
     let a: Array2<f32> = Array::random((8, 10), Uniform::new(0.49, 0.51));
     let (n, m): (usize, usize) = (a.nrows(), a.ncols());
 
-    let b = a.mapv(|x| sigmoid_op(x));
+    let b = a.mapv(sigmoid_op);
 
     let a_gpu = OpenCLArray::from_array(backend.clone(), &a)?;
     let mut b_gpu = OpenCLArray::new(backend,a_gpu.rows,a_gpu.cols)?;
@@ -214,11 +214,11 @@ fn sigmoid_prime_op(x: f32) -> f32 {
 #[test]
 #[serial]
 fn array_sigmoid_prime() -> Result<(), Error> {
-    let backend = CLBackEnd::new("GeForce")?;
+    let backend = test_backend()?;
     let a: Array2<f32> = Array::random((8, 10), Uniform::new(0.49, 0.51));
     let (n, m): (usize, usize) = (a.nrows(), a.ncols());
 
-    let b = a.mapv(|x| sigmoid_prime_op(x));
+    let b = a.mapv(sigmoid_prime_op);
 
     let a_gpu = OpenCLArray::from_array(backend.clone(), &a)?;
     let mut b_gpu = OpenCLArray::new(backend,a_gpu.rows,a_gpu.cols)?;
@@ -245,24 +245,12 @@ fn array_sigmoid_prime() -> Result<(), Error> {
 #[test]
 #[serial]
 fn array_transpose_versions() -> Result<(), Error> {
-    let backend = CLBackEnd::new("GeForce")?;
+    let backend = test_backend()?;
 
-    let mut array = array![[1., 2., 3.], [4., 5., 6.]];
+    let array = array![[1., 2., 3.], [4., 5., 6.]];
 
     let mut a = OpenCLArray::from_array(backend, &array)?;
-    let mut start = Instant::now();
-    for _ in 0..10 {
-        let b = a.t()?;
-    }
-    println!("t_version 1 time: {}",start.elapsed().as_nanos());
     let b = a.t()?;
-
-
-    start = Instant::now();
-    for _ in 0..10 {
-        a.t_v2()?;
-    }
-    println!("t_version 2 time: {}",start.elapsed().as_nanos());
     a.t_v2()?;
 
     let result = b.to_array()?;
@@ -277,3 +265,1714 @@ fn array_transpose_versions() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[test]
+#[serial]
+fn array_async_queues() -> Result<(), Error> {
+    let backend = CLBackEnd::from_config(BackendConfig {
+        queues: 2,
+        ..test_config()
+    })?;
+    let (compute, upload) = (backend.on_queue(0), backend.on_queue(1));
+
+    let a = Array::random((64, 32), Uniform::new(0., 1.));
+    let b = Array::random((32, 16), Uniform::new(0., 1.));
+    let a_gpu = OpenCLArray::from_array(compute.clone(), &a)?;
+    let b_gpu = OpenCLArray::from_array(compute.clone(), &b)?;
+    let mut c_gpu = OpenCLArray::new(compute.clone(), 64, 16)?;
+    let dot_event = a_gpu.dot_event(&b_gpu, &mut c_gpu)?;
+
+    // Stage the next batch on the second queue while the product is computed
+    let next = Array::random((64, 32), Uniform::new(0., 1.));
+    let mut next_gpu = OpenCLArray::new(upload.clone(), 64, 32)?;
+    let staged = next_gpu.write_async(RwVec::from(create_vec(&next)))?;
+    let upload_event = upload.marker()?;
+
+    upload.wait_for(&dot_event)?;
+    compute.wait_for(&upload_event)?;
+    let c_gpu = c_gpu.to_vec_async()?.wait()?;
+    assert_eq!(staged.wait()?.len(), 64 * 32);
+    backend.synchronize()?;
+
+    let c = a.dot(&b);
+    for (x, y) in c.iter().zip(c_gpu.iter()) {
+        assert!((x - y).abs() < 1e-3);
+    }
+
+    next_gpu.set_queue(0);
+    assert_eq!(next_gpu.clone().to_array()?, next);
+
+    // Transposed views read back in their own layout, and take row-major writes
+    let next_t = create_vec(&next.t().to_owned());
+    assert_eq!(next_gpu.t_view().to_vec_async()?.wait()?.to_vec(), next_t);
+    let mut view = next_gpu.t_view();
+    view.write_async(RwVec::from(next_t))?.wait()?;
+    assert!(!view.is_transposed());
+    assert_eq!(view.to_array()?, next.t());
+
+    // An op with nothing to launch still hands back an event to wait on
+    let mut empty = OpenCLArray::new(compute.clone(), 0, 3)?;
+    empty.square_event()?.wait_for()?;
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn array_pool_reuse() -> Result<(), Error> {
+    let backend = test_backend()?;
+    let (n, m) = (30, 30);
+
+    let a = OpenCLArray::from_vec(backend.clone(), n, m, vec![3.; n * m])?;
+    let in_use = backend.pool_stats().bytes_in_use;
+    assert_eq!(in_use, size_class(n * m) * std::mem::size_of::<f32>());
+    drop(a);
+    assert_eq!(backend.pool_stats().bytes_in_use, 0);
+    assert_eq!(backend.pool_stats().bytes_cached, in_use);
+
+    // The recycled buffer still holds 3s, so this also checks the device-side zero fill
+    let b = OpenCLArray::new(backend.clone(), m, n)?;
+    let stats = backend.pool_stats();
+    assert_eq!((stats.allocations, stats.reuses), (1, 1));
+    assert_eq!(stats.peak_bytes_in_use, in_use);
+    assert_eq!(b.to_vec()?, vec![0.; n * m]);
+
+    backend.pool.clear();
+    assert_eq!(backend.pool_stats().bytes_cached, 0);
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn kernel_cache_reuse() -> Result<(), Error> {
+    let backend = test_backend()?;
+    let (n, m) = (4, 5);
+
+    let a = OpenCLArray::from_vec(backend.clone(), n, m, vec![1.; n * m])?;
+    let mut b = OpenCLArray::new(backend.clone(), n, m)?;
+    for _ in 0..10 {
+        a.add(&a, &mut b)?;
+        b.square()?;
+    }
+    assert_eq!(backend.kernels.len(), 2);
+    assert_eq!(b.to_vec()?, vec![4.; n * m]);
+
+    // A second backend on the same device hits the on-disk program cache
+    if let Some(dir) = program_cache_dir() {
+        let key = program_cache_key(
+            &backend.proque.device(),
+            include_str!("cl/functions.cl"),
+            "",
+        )?;
+        assert!(dir.join(format!("{:016x}.bin", key)).exists());
+        let cached = test_backend()?;
+        let c = OpenCLArray::from_vec(cached, n, m, vec![3.; n * m])?;
+        c.clone().square()?;
+        assert_eq!(c.to_vec()?, vec![9.; n * m]);
+    }
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn profiler_records() -> Result<(), Error> {
+    let backend = CLBackEnd::from_config(BackendConfig {
+        profiling: true,
+        ..test_config()
+    })?;
+    let (n, m, k) = (64, 32, 16);
+
+    let a = OpenCLArray::from_vec(backend.clone(), n, m, vec![1.; n * m])?;
+    let b = OpenCLArray::from_vec(backend.clone(), m, k, vec![1.; m * k])?;
+    let mut c = OpenCLArray::new(backend.clone(), n, k)?;
+    for _ in 0..3 {
+        a.dot(&b, &mut c)?;
+    }
+    c.sigmoid(&mut c.clone())?;
+    c.to_vec()?;
+
+    let profiler = backend.profiler.as_ref().unwrap();
+    let summary = profiler.summary()?;
+    assert_eq!(summary["dot_product"].count, 3);
+    assert_eq!(summary["sigmoid"].count, 1);
+    assert_eq!(summary["write"].count, 2);
+    assert_eq!(summary["read"].count, 1);
+    for record in profiler.records()? {
+        assert!(record.queued <= record.submit);
+        assert!(record.submit <= record.start);
+        assert!(record.start <= record.end);
+    }
+
+    let trace = profiler.chrome_trace()?;
+    assert!(trace.starts_with("{\"traceEvents\":["));
+    assert_eq!(trace.matches("\"ph\":\"X\"").count(), 7);
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn backend_device_info() -> Result<(), Error> {
+    let backend = test_backend()?;
+    let info = backend.device_info()?;
+    assert!(info.name.contains(&test_config().device));
+    assert!(info.available);
+    assert_eq!(info.max_work_group_size, backend.proque.max_wg_size()?);
+    assert!(list_device_info()?.contains(&info));
+
+    assert!(CLBackEnd::new("No such device").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn work_size_rounding() {
+    use ocl::SpatialDims::*;
+
+    assert_eq!(default_local_size(One(1000), 1024), One(256));
+    assert_eq!(default_local_size(One(1000), 100), One(64));
+    assert_eq!(default_local_size(Two(10, 10), 1024), Two(16, 16));
+    assert_eq!(default_local_size(Two(10, 10), 128), Two(8, 8));
+    assert_eq!(round_global_size(One(1000), One(256)), One(1024));
+    assert_eq!(round_global_size(Two(17, 3), Two(16, 16)), Two(32, 16));
+    assert_eq!(round_up(256, 256), 256);
+}
+
+#[test]
+#[serial]
+fn odd_sized_ops() -> Result<(), Error> {
+    let backend = test_backend()?;
+
+    for &(n, m, k) in &[(1, 1, 1), (7, 13, 3), (257, 1, 31), (1, 1031, 2)] {
+        let a = Array::random((n, m), Uniform::new(-1., 1.));
+        let b = Array::random((m, k), Uniform::new(-1., 1.));
+        let a_gpu = OpenCLArray::from_array(backend.clone(), &a)?;
+        let b_gpu = OpenCLArray::from_array(backend.clone(), &b)?;
+
+        let mut c_gpu = OpenCLArray::new(backend.clone(), n, k)?;
+        a_gpu.dot(&b_gpu, &mut c_gpu)?;
+        for (x, y) in c_gpu.to_array()?.iter().zip(a.dot(&b).iter()) {
+            assert!((x - y).abs() < 1e-4);
+        }
+
+        let mut sum_gpu = OpenCLArray::new(backend.clone(), n, m)?;
+        a_gpu.add(&a_gpu, &mut sum_gpu)?;
+        assert_eq!(sum_gpu.to_array()?, &a + &a);
+
+        assert_eq!(a_gpu.t()?.to_array()?, a.t());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn npy_round_trip() -> Result<(), Error> {
+    use crate::io::*;
+
+    let a = Array::random((5, 3), Uniform::new(-1., 1.));
+    let mut bytes = Vec::new();
+    write_npy(&mut bytes, &a)?;
+    assert_eq!((bytes.len() - a.len() * 4) % 64, 0);
+    assert_eq!(read_npy(&bytes[..])?, a);
+
+    let mut bytes = Vec::new();
+    write_native(&mut bytes, &a)?;
+    assert_eq!(read_native(&bytes[..])?, a);
+
+    let mut archive = std::io::Cursor::new(Vec::new());
+    write_npz(&mut archive, vec![("a", &a), ("a_t", &a.t().to_owned())])?;
+    archive.set_position(0);
+    let arrays = read_npz(archive)?;
+    assert_eq!(arrays["a"], a);
+    assert_eq!(arrays["a_t"], a.t());
+
+    // A float64 array as written by `numpy.save`
+    let mut header = b"\x93NUMPY\x01\x00\x76\x00".to_vec();
+    let dict = "{'descr': '<f8', 'fortran_order': False, 'shape': (1, 1), }";
+    header.extend(format!("{:<117}\n", dict).bytes());
+    header.extend(&1f64.to_le_bytes());
+    assert!(read_npy(&header[..]).is_err());
+
+    // Headers claiming more data than follows, or more than fits in memory
+    assert!(read_native(&bytes[..bytes.len() - 1]).is_err());
+    let mut huge = bytes[..8].to_vec();
+    huge.extend(&u64::MAX.to_le_bytes());
+    huge.extend(&2u64.to_le_bytes());
+    assert!(read_native(&huge[..]).is_err());
+    let mut huge = b"\x93NUMPY\x01\x00\x76\x00".to_vec();
+    let dict = "{'descr': '<f4', 'fortran_order': False, 'shape': (1000000, 1000000), }";
+    huge.extend(format!("{:<117}\n", dict).bytes());
+    assert!(read_npy(&huge[..]).is_err());
+    // A version 2 header claiming 4 GiB in a 12-byte file, and a truncated version 1 header
+    assert!(read_npy(&b"\x93NUMPY\x02\x00\xff\xff\xff\xff"[..]).is_err());
+    assert!(read_npy(&b"\x93NUMPY\x01\x00\x76\x00{'descr'"[..]).is_err());
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn array_save_load() -> Result<(), Error> {
+    let backend = test_backend()?;
+    let dir = std::env::temp_dir();
+
+    let a = Array::random((4, 6), Uniform::new(-1., 1.));
+    let a_gpu = OpenCLArray::from_array(backend.clone(), &a)?;
+
+    a_gpu.save_npy(dir.join("carya_array.npy"))?;
+    let b_gpu = OpenCLArray::load_npy(backend.clone(), dir.join("carya_array.npy"))?;
+    assert_eq!(b_gpu.to_array()?, a);
+
+    a_gpu.save(dir.join("carya_array.carya"))?;
+    let b_gpu = OpenCLArray::load(backend.clone(), dir.join("carya_array.carya"))?;
+    assert_eq!(b_gpu.to_array()?, a);
+
+    OpenCLArray::save_npz(dir.join("carya_arrays.npz"), &[("w", &a_gpu)])?;
+    let arrays = OpenCLArray::load_npz(backend, dir.join("carya_arrays.npz"))?;
+    assert_eq!(arrays["w"].clone().to_array()?, a);
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn backend_from_config() -> Result<(), Error> {
+    let config = BackendConfig {
+        build_options: vec!["-cl-fast-relaxed-math".to_string()],
+        queues: 2,
+        ..test_config()
+    };
+    assert!(config.queue_properties().is_none());
+    let backend = CLBackEnd::from_config(config.clone())?;
+    assert_eq!(backend.queues.len(), 2);
+    assert_eq!(backend.config, config);
+
+    let a = Array::random((3, 5), Uniform::new(-1., 1.));
+    let snapshot = OpenCLArray::from_array(backend.clone(), &a)?.snapshot()?;
+    assert_eq!((snapshot.rows, snapshot.cols), (3, 5));
+    assert_eq!(snapshot.to_array()?, a);
+    assert_eq!(snapshot.to_device(backend)?.to_array()?, a);
+
+    let no_queues = BackendConfig {
+        queues: 0,
+        ..test_config()
+    };
+    assert!(CLBackEnd::from_config(no_queues).is_err());
+
+    Ok(())
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn backend_config_serde() {
+    let config = BackendConfig {
+        platform: Some("NVIDIA".to_string()),
+        build_options: vec!["-cl-mad-enable".to_string()],
+        queues: 3,
+        profiling: true,
+        autotune: true,
+        ..BackendConfig::new("GeForce")
+    };
+    let json = serde_json::to_string(&config).unwrap();
+    assert_eq!(
+        serde_json::from_str::<BackendConfig>(&json).unwrap(),
+        config
+    );
+
+    // Missing fields take their defaults
+    let partial: BackendConfig = serde_json::from_str(r#"{"device": "Radeon"}"#).unwrap();
+    assert_eq!(partial, BackendConfig::new("Radeon"));
+}
+
+fn assert_close(a: &Array2<f32>, b: &Array2<f32>, tol: f32) {
+    assert_eq!(a.dim(), b.dim());
+    for (x, y) in a.iter().zip(b.iter()) {
+        assert!((x - y).abs() < tol, "{} != {}", x, y);
+    }
+}
+
+#[test]
+#[serial]
+fn linalg_solvers() -> Result<(), Error> {
+    let backend = test_backend()?;
+    let n = 9;
+
+    // Diagonally dominant, so well conditioned but still needing pivots
+    let a = Array::random((n, n), Uniform::new(-1., 1.)) + Array2::<f32>::eye(n) * 4.;
+    let b = Array::random((n, 3), Uniform::new(-1., 1.));
+    let a_gpu = OpenCLArray::from_array(backend.clone(), &a)?;
+    let b_gpu = OpenCLArray::from_array(backend.clone(), &b)?;
+
+    let lu = a_gpu.lu()?;
+    let perm = lu.permutation()?;
+    let pa = Array::from_shape_fn((n, n), |(i, j)| a[[perm[i] as usize, j]]);
+    assert_close(&lu.l()?.to_array()?.dot(&lu.u()?.to_array()?), &pa, 1e-4);
+
+    let x = a_gpu.solve(&b_gpu)?.to_array()?;
+    assert_close(&a.dot(&x), &b, 1e-4);
+    assert_close(&a.dot(&a_gpu.inv()?.to_array()?), &Array2::eye(n), 1e-4);
+
+    let upper = lu.u()?;
+    let x = upper.solve_triangular(&b_gpu, false, false)?.to_array()?;
+    assert_close(&upper.to_array()?.dot(&x), &b, 1e-4);
+
+    let m = array![[4., 3., 0.], [3., 4., -1.], [0., -1., 4.]];
+    let m_gpu = OpenCLArray::from_array(backend.clone(), &m)?;
+    assert!((m_gpu.det()? - 24.).abs() < 1e-4);
+
+    let l = m_gpu.cholesky()?.to_array()?;
+    assert_close(&l.dot(&l.t()), &m, 1e-4);
+    assert_eq!(l[[0, 1]], 0.);
+
+    let c = Array::random((6, 4), Uniform::new(-1., 1.));
+    let (q, r) = OpenCLArray::from_array(backend, &c)?.qr()?;
+    let (q, r) = (q.to_array()?, r.to_array()?);
+    assert_close(&q.dot(&r), &c, 1e-4);
+    assert_close(&q.t().dot(&q), &Array2::eye(6), 1e-4);
+    assert_eq!(r[[3, 2]], 0.);
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn iterative_solvers() -> Result<(), Error> {
+    use crate::solvers::*;
+
+    let backend = test_backend()?;
+    let n = 40;
+
+    // Symmetric positive definite, with an uneven diagonal for Jacobi to help with
+    let m = Array::random((n, n), Uniform::new(-0.5, 0.5));
+    let a = m.dot(&m.t()) + Array::from_diag(&Array::linspace(1., 50., n));
+    let b = Array::random((n, 1), Uniform::new(-1., 1.));
+    let a_gpu = OpenCLArray::from_array(backend.clone(), &a)?;
+    let b_gpu = OpenCLArray::from_array(backend.clone(), &b)?;
+
+    let jacobi = Jacobi::new(&a_gpu)?;
+    let preconditioned = SolverOptions {
+        preconditioner: Some(&jacobi),
+        ..SolverOptions::default()
+    };
+    let restarted = SolverOptions {
+        restart: 10,
+        ..SolverOptions::default()
+    };
+
+    type Solver = fn(
+        &OpenCLArray,
+        &OpenCLArray,
+        &mut OpenCLArray,
+        &SolverOptions,
+    ) -> Result<ConvergenceReport, Error>;
+    let solvers: [Solver; 3] = [cg, bicgstab, gmres];
+    for solve in &solvers {
+        for options in &[SolverOptions::default(), preconditioned, restarted] {
+            let mut x = OpenCLArray::new(backend.clone(), n, 1)?;
+            let report = solve(&a_gpu, &b_gpu, &mut x, options)?;
+            assert!(report.converged);
+            assert_eq!(report.residual_history.len(), report.iterations + 1);
+            let r = &b - &a.dot(&x.to_array()?);
+            assert!(r.mapv(|e| e * e).sum().sqrt() < 1e-3);
+        }
+    }
+
+    // Matrix-free: A = 2I
+    let double = |x: &OpenCLArray, y: &mut OpenCLArray| x.scalar_multiply(2., y);
+    let mut x = OpenCLArray::new(backend, n, 1)?;
+    assert!(cg(&double, &b_gpu, &mut x, &SolverOptions::default())?.converged);
+    assert_close(&x.to_array()?, &(&b / 2.), 1e-5);
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn csr_matrix() -> Result<(), Error> {
+    use crate::sparse::*;
+
+    let backend = test_backend()?;
+    let dense = array![[1., 0., 0., 2.], [0., 0., 0., 0.], [0., 3., 4., 0.]];
+    let a = OpenCLCsrMatrix::from_array(backend.clone(), &dense)?;
+    assert_eq!(a.nnz(), 4);
+    assert_eq!(a.to_array()?, dense);
+    assert_eq!(a.t()?.to_array()?, dense.t());
+
+    // Duplicates are summed, order doesn't matter
+    let triplets = [(2, 2, 1.), (0, 3, 2.), (2, 1, 3.), (0, 0, 1.), (2, 2, 3.)];
+    let b = OpenCLCsrMatrix::from_triplets(backend.clone(), 3, 4, &triplets)?;
+    assert_eq!(b.to_csr()?, a.to_csr()?);
+
+    // Malformed CSR input is rejected
+    let csr = |row_ptr: Vec<u64>, col_idx: Vec<u64>| {
+        let values = vec![1.; col_idx.len()];
+        OpenCLCsrMatrix::from_csr(backend.clone(), 2, 3, row_ptr, col_idx, values)
+    };
+    assert!(csr(vec![0, 1, 2], vec![2, 0]).is_ok());
+    assert!(csr(vec![0, 1, 2], vec![3, 0]).is_err());
+    assert!(csr(vec![0, 2, 2], vec![2, 1]).is_err());
+    assert!(csr(vec![0, 2, 2], vec![1, 1]).is_err());
+    assert!(csr(vec![0, 2, 1], vec![0]).is_err());
+    assert!(csr(vec![0, 1], vec![0]).is_err());
+
+    let x = Array::random((4, 5), Uniform::new(-1., 1.));
+    let x_gpu = OpenCLArray::from_array(backend.clone(), &x)?;
+    let mut y_gpu = OpenCLArray::new(backend.clone(), 3, 5)?;
+    a.spmm(&x_gpu, &mut y_gpu)?;
+    assert_close(&y_gpu.to_array()?, &dense.dot(&x), 1e-5);
+
+    let empty = OpenCLCsrMatrix::from_triplets(backend.clone(), 2, 3, &[])?;
+    assert_eq!(empty.to_array()?, Array2::<f32>::zeros((2, 3)));
+    let v = OpenCLArray::from_vec(backend.clone(), 3, 1, vec![1., 2., 3.])?;
+    let mut w = OpenCLArray::from_vec(backend, 2, 1, vec![5., 5.])?;
+    empty.spmv(&v, &mut w)?;
+    assert_eq!(w.to_vec()?, vec![0., 0.]);
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn blas_routines() -> Result<(), Error> {
+    let backend = test_backend()?;
+    let (n, m) = (37, 2000);
+
+    let x = Array::random((1, m), Uniform::new(-1., 1.));
+    let y = Array::random((m, 1), Uniform::new(-1., 1.));
+    let x_gpu = OpenCLArray::from_array(backend.clone(), &x)?;
+    let mut y_gpu = OpenCLArray::from_array(backend.clone(), &y)?;
+
+    // Row and column vectors mix freely
+    assert!((x_gpu.inner(&y_gpu)? - x.dot(&y)[[0, 0]]).abs() < 1e-2);
+    assert!((x_gpu.nrm2()? - x.mapv(|e| e * e).sum().sqrt()).abs() < 1e-3);
+    assert!((x_gpu.asum()? - x.mapv(f32::abs).sum()).abs() < 1e-2);
+
+    let mut spike = Array2::zeros((1, m));
+    spike[[0, 1500]] = -3.;
+    spike[[0, 1700]] = 3.;
+    let spike_gpu = OpenCLArray::from_array(backend.clone(), &spike)?;
+    assert_eq!(spike_gpu.iamax()?, Some(1500));
+
+    y_gpu.axpy(2., &x_gpu)?;
+    y_gpu.scal(0.5)?;
+    assert_close(
+        &y_gpu.clone().to_array()?,
+        &((&y + &(&x.t() * 2.)) * 0.5),
+        1e-5,
+    );
+
+    let a = Array::random((n, m), Uniform::new(-1., 1.));
+    let mut a_gpu = OpenCLArray::from_array(backend.clone(), &a)?;
+    let mut z_gpu = OpenCLArray::uninitialized(backend.clone(), n, 1)?;
+    a_gpu.gemv(2., &x_gpu, 0., &mut z_gpu)?;
+    assert_close(&z_gpu.clone().to_array()?, &(a.dot(&x.t()) * 2.), 1e-3);
+
+    a_gpu.ger(-1., &z_gpu, &x_gpu)?;
+    let z = z_gpu.to_array()?;
+    assert_close(&a_gpu.to_array()?, &(&a - &z.dot(&x)), 1e-3);
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn batched_dot() -> Result<(), Error> {
+    let backend = test_backend()?;
+    let (batch, n, m, k) = (5, 3, 7, 4);
+
+    let a: Vec<Array2<f32>> = (0..batch)
+        .map(|_| Array::random((n, m), Uniform::new(-1., 1.)))
+        .collect();
+    let b: Vec<Array2<f32>> = (0..batch)
+        .map(|_| Array::random((m, k), Uniform::new(-1., 1.)))
+        .collect();
+    let stack = |xs: &[Array2<f32>]| {
+        let views: Vec<_> = xs.iter().map(|x| x.view()).collect();
+        ndarray::stack(Axis(0), &views).unwrap()
+    };
+
+    let a_gpu = OpenCLArray::from_array(backend.clone(), &stack(&a))?;
+    let b_gpu = OpenCLArray::from_array(backend.clone(), &stack(&b))?;
+    let mut c_gpu = OpenCLArray::new(backend.clone(), batch * n, k)?;
+    a_gpu.batched_dot(&b_gpu, &mut c_gpu, batch)?;
+    let products: Vec<_> = a.iter().zip(&b).map(|(x, y)| x.dot(y)).collect();
+    assert_close(&c_gpu.to_array()?, &stack(&products), 1e-4);
+
+    // One right-hand matrix broadcast over the batch
+    let b0_gpu = OpenCLArray::from_array(backend.clone(), &b[0])?;
+    let mut c_gpu = OpenCLArray::new(backend.clone(), batch * n, k)?;
+    a_gpu.batched_dot(&b0_gpu, &mut c_gpu, batch)?;
+    let products: Vec<_> = a.iter().map(|x| x.dot(&b[0])).collect();
+    assert_close(&c_gpu.to_array()?, &stack(&products), 1e-4);
+
+    let a_many = a
+        .iter()
+        .map(|x| OpenCLArray::from_array(backend.clone(), x))
+        .collect::<Result<Vec<_>, Error>>()?;
+    let c_many = OpenCLArray::batched_dot_many(&a_many, &[b0_gpu])?;
+    for (c, x) in c_many.into_iter().zip(&a) {
+        assert_close(&c.to_array()?, &x.dot(&b[0]), 1e-4);
+    }
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn eigen_and_svd() -> Result<(), Error> {
+    use crate::eigen::*;
+
+    let backend = test_backend()?;
+    let n = 11;
+    let m = Array::random((n, n), Uniform::new(-1., 1.));
+    let a = &m + &m.t();
+
+    let eigen = OpenCLArray::from_array(backend.clone(), &a)?.eigh()?;
+    assert!(eigen.values.windows(2).all(|w| w[0] >= w[1]));
+    let v = eigen.vectors.to_array()?;
+    let lambda = Array::from_diag(&Array::from(eigen.values));
+    assert_close(&a.dot(&v), &v.dot(&lambda), 1e-4);
+    assert_close(&v.t().dot(&v), &Array2::eye(n), 1e-4);
+
+    // Rank 4, so the truncated SVD reconstructs it
+    let x = Array::random((60, 4), Uniform::new(-1., 1.));
+    let y = Array::random((4, 40), Uniform::new(-1., 1.));
+    let low_rank = x.dot(&y);
+    let svd =
+        OpenCLArray::from_array(backend, &low_rank)?.truncated_svd(4, &SvdOptions::default())?;
+    assert!(svd.s.windows(2).all(|w| w[0] >= w[1]));
+    let (u, vt) = (svd.u.to_array()?, svd.vt.to_array()?);
+    let s = Array::from_diag(&Array::from(svd.s));
+    assert_close(&u.dot(&s).dot(&vt), &low_rank, 1e-3);
+    assert_close(&u.t().dot(&u), &Array2::eye(4), 1e-3);
+    assert_close(&vt.dot(&vt.t()), &Array2::eye(4), 1e-3);
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn transpose_views() -> Result<(), Error> {
+    let backend = test_backend()?;
+
+    // Sizes which aren't multiples of the tile side
+    for &(n, m) in &[(1, 1), (1, 40), (37, 53), (64, 16)] {
+        let a = Array::random((n, m), Uniform::new(-1., 1.));
+        let a_gpu = OpenCLArray::from_array(backend.clone(), &a)?;
+        assert_eq!(a_gpu.t()?.to_array()?, a.t());
+        assert_eq!(a_gpu.t_view().to_array()?, a.t());
+        assert_eq!(a_gpu.t_view().contiguous()?.to_array()?, a.t());
+        assert_eq!(a_gpu.t_view().t()?.to_array()?, a);
+        assert!(!a_gpu.t_view().t_view().is_transposed());
+
+        let mut b_gpu = a_gpu.deep_clone()?;
+        b_gpu.t_v2()?;
+        assert_eq!(b_gpu.to_array()?, a.t());
+    }
+
+    let square = Array::random((33, 33), Uniform::new(-1., 1.));
+    let mut square_gpu = OpenCLArray::from_array(backend.clone(), &square)?;
+    let buffer = square_gpu.v.clone();
+    square_gpu.t_v2()?;
+    assert_eq!(square_gpu.v.as_core().as_ptr(), buffer.as_core().as_ptr());
+    assert_eq!(square_gpu.to_array()?, square.t());
+
+    let a = Array::random((20, 30), Uniform::new(-1., 1.));
+    let b = Array::random((20, 10), Uniform::new(-1., 1.));
+    let a_gpu = OpenCLArray::from_array(backend.clone(), &a)?;
+    let b_gpu = OpenCLArray::from_array(backend.clone(), &b)?;
+    let mut c_gpu = OpenCLArray::uninitialized(backend.clone(), 30, 10)?;
+    a_gpu.t_view().dot(&b_gpu, &mut c_gpu)?;
+    assert_close(&c_gpu.clone().to_array()?, &a.t().dot(&b), 1e-4);
+
+    let mut d_gpu = OpenCLArray::uninitialized(backend, 30, 30)?;
+    a_gpu.t_view().dot(&a_gpu, &mut d_gpu)?;
+    assert_close(&d_gpu.to_array()?, &a.t().dot(&a), 1e-4);
+    b_gpu.t_view().dot(&b_gpu.t_view().t_view(), &mut c_gpu)?;
+    assert_close(&c_gpu.to_array()?, &b.t().dot(&b), 1e-4);
+
+    Ok(())
+}
+
+// Wraps a scalar result so the harness can compare it
+fn scalar(like: &OpenCLArray, x: f32) -> Result<OpenCLArray, Error> {
+    OpenCLArray::from_vec(like.backend.clone(), 1, 1, vec![x])
+}
+
+#[test]
+#[serial]
+fn differential_elementwise() -> Result<(), Error> {
+    let mut diff = Differential::new()?;
+    let pair = |n, m, _| vec![(n, m), (n, m)];
+    let single = |n, m, _| vec![(n, m)];
+    let output = |x: &OpenCLArray| OpenCLArray::uninitialized(x.backend.clone(), x.rows, x.cols);
+    let transcendental = Tolerance {
+        ulps: 8,
+        absolute: 1e-6,
+        ..Tolerance::EXACT
+    };
+
+    diff.check(
+        "add",
+        Tolerance::EXACT,
+        pair,
+        |x| {
+            let mut c = output(&x[0])?;
+            x[0].add(&x[1], &mut c)?;
+            Ok(c)
+        },
+        |x| &x[0] + &x[1],
+    )?;
+    diff.check(
+        "subtract",
+        Tolerance::EXACT,
+        pair,
+        |x| {
+            let mut c = output(&x[0])?;
+            x[0].subtract(&x[1], &mut c)?;
+            Ok(c)
+        },
+        |x| &x[0] - &x[1],
+    )?;
+    diff.check(
+        "hadamard",
+        Tolerance::EXACT,
+        pair,
+        |x| {
+            let mut c = output(&x[0])?;
+            x[0].hadamard(&x[1], &mut c)?;
+            Ok(c)
+        },
+        |x| &x[0] * &x[1],
+    )?;
+    diff.check(
+        "scalar_multiply",
+        Tolerance::EXACT,
+        single,
+        |x| {
+            let mut c = output(&x[0])?;
+            x[0].scalar_multiply(-1.5, &mut c)?;
+            Ok(c)
+        },
+        |x| &x[0] * -1.5,
+    )?;
+    diff.check(
+        "square",
+        Tolerance::EXACT,
+        single,
+        |x| {
+            let mut c = x[0].clone();
+            c.square()?;
+            Ok(c)
+        },
+        |x| x[0].mapv(|v| v * v),
+    )?;
+    diff.check(
+        "sigmoid",
+        transcendental,
+        single,
+        |x| {
+            let mut c = output(&x[0])?;
+            x[0].sigmoid(&mut c)?;
+            Ok(c)
+        },
+        |x| x[0].mapv(sigmoid_op),
+    )?;
+    diff.check(
+        "sigmoid_prime",
+        transcendental,
+        single,
+        |x| {
+            let mut c = output(&x[0])?;
+            x[0].sigmoid_prime(&mut c)?;
+            Ok(c)
+        },
+        |x| x[0].mapv(sigmoid_prime_op),
+    )
+}
+
+#[test]
+#[serial]
+fn differential_products() -> Result<(), Error> {
+    use crate::sparse::*;
+
+    let mut diff = Differential::new()?;
+    let product = |x: &OpenCLArray, y: &OpenCLArray| {
+        let mut c = OpenCLArray::uninitialized(x.backend.clone(), x.rows, y.cols)?;
+        x.dot(y, &mut c)?;
+        Ok(c)
+    };
+
+    // The tolerance only depends on the inner dimension, which the dims cover up to 509
+    diff.check(
+        "dot",
+        Tolerance::accumulated(509),
+        |n, m, k| vec![(n, m), (m, k)],
+        |x| product(&x[0], &x[1]),
+        |x| x[0].dot(&x[1]),
+    )?;
+    diff.check(
+        "dot of transposed views",
+        Tolerance::accumulated(509),
+        |n, m, k| vec![(m, n), (k, m)],
+        |x| product(&x[0].t_view(), &x[1].t_view()),
+        |x| x[0].t().dot(&x[1].t()),
+    )?;
+    diff.check(
+        "t",
+        Tolerance::EXACT,
+        |n, m, _| vec![(n, m)],
+        |x| x[0].t(),
+        |x| x[0].t().to_owned(),
+    )?;
+    diff.check(
+        "batched_dot",
+        Tolerance::accumulated(509),
+        |n, m, k| vec![(3 * n, m), (3 * m, k)],
+        |x| {
+            let mut c = OpenCLArray::uninitialized(x[0].backend.clone(), x[0].rows, x[1].cols)?;
+            x[0].batched_dot(&x[1], &mut c, 3)?;
+            Ok(c)
+        },
+        |x| {
+            let (n, m) = (x[0].nrows() / 3, x[1].nrows() / 3);
+            let products: Vec<Array2<f32>> = (0..3)
+                .map(|i| {
+                    let a = x[0].slice(s![i * n..(i + 1) * n, ..]);
+                    a.dot(&x[1].slice(s![i * m..(i + 1) * m, ..]))
+                })
+                .collect();
+            let views: Vec<_> = products.iter().map(|p| p.view()).collect();
+            ndarray::stack(Axis(0), &views).unwrap()
+        },
+    )?;
+    diff.check(
+        "csr spmm",
+        Tolerance::accumulated(509),
+        |n, m, k| vec![(n, m), (m, k)],
+        |x| {
+            let a = OpenCLCsrMatrix::from_array(x[0].backend.clone(), &x[0].clone().to_array()?)?;
+            let mut c = OpenCLArray::uninitialized(x[0].backend.clone(), x[0].rows, x[1].cols)?;
+            a.spmm(&x[1], &mut c)?;
+            Ok(c)
+        },
+        |x| x[0].dot(&x[1]),
+    )
+}
+
+#[test]
+#[serial]
+fn differential_blas() -> Result<(), Error> {
+    let mut diff = Differential::new()?;
+    // Within an ulp, or absolutely where the terms cancel and leave a result near zero
+    let cancelling = Tolerance {
+        absolute: 1e-6,
+        ..Tolerance::ulps(1)
+    };
+
+    diff.check(
+        "axpby",
+        cancelling,
+        |n, m, _| vec![(n, m), (n, m)],
+        |x| {
+            let mut y = x[1].clone();
+            y.axpby(0.75, &x[0], -2.)?;
+            Ok(y)
+        },
+        |x| &x[0] * 0.75 + &x[1] * -2.,
+    )?;
+    diff.check(
+        "gemv",
+        Tolerance::accumulated(509),
+        |n, m, _| vec![(n, m), (m, 1), (n, 1)],
+        |x| {
+            let mut y = x[2].clone();
+            x[0].gemv(2., &x[1], 0.5, &mut y)?;
+            Ok(y)
+        },
+        |x| x[0].dot(&x[1]) * 2. + &x[2] * 0.5,
+    )?;
+    diff.check(
+        "ger",
+        cancelling,
+        |n, m, _| vec![(n, m), (n, 1), (1, m)],
+        |x| {
+            let mut a = x[0].clone();
+            a.ger(-1.5, &x[1], &x[2])?;
+            Ok(a)
+        },
+        |x| &x[0] + &(x[1].dot(&x[2]) * -1.5),
+    )?;
+    diff.check(
+        "inner",
+        Tolerance::accumulated(509 * 509),
+        |n, m, _| vec![(n, m), (n, m)],
+        |x| scalar(&x[0], x[0].inner(&x[1])?),
+        |x| arr2(&[[(&x[0] * &x[1]).sum()]]),
+    )?;
+    diff.check(
+        "asum",
+        Tolerance::accumulated(509 * 509),
+        |n, m, _| vec![(n, m)],
+        |x| scalar(&x[0], x[0].asum()?),
+        |x| arr2(&[[x[0].mapv(f32::abs).sum()]]),
+    )
+}
+
+#[test]
+#[serial]
+fn differential_transposed_views() -> Result<(), Error> {
+    use crate::sparse::*;
+
+    // Every operand is uploaded transposed and passed as a `t_view` of that, so each op sees
+    // an `n x m` view whose buffer holds the `m x n` transpose
+    let mut diff = Differential::new()?;
+    let views = |x: &[OpenCLArray]| x.iter().map(OpenCLArray::t_view).collect::<Vec<_>>();
+    let ts = |x: &[Array2<f32>]| x.iter().map(|a| a.t().to_owned()).collect::<Vec<_>>();
+    let pair = |n, m, _| vec![(m, n), (m, n)];
+    let single = |n, m, _| vec![(m, n)];
+    let output = |x: &OpenCLArray| OpenCLArray::uninitialized(x.backend.clone(), x.rows, x.cols);
+    let transcendental = Tolerance {
+        ulps: 8,
+        absolute: 1e-6,
+        ..Tolerance::EXACT
+    };
+    type Reference = fn(&Array2<f32>, &Array2<f32>) -> Array2<f32>;
+    let binary: &[(&str, BinaryOp, Reference)] = &[
+        ("add", OpenCLArray::add, |a, b| a + b),
+        ("subtract", OpenCLArray::subtract, |a, b| a - b),
+        ("hadamard", OpenCLArray::hadamard, |a, b| a * b),
+    ];
+    for &(name, op, reference) in binary {
+        diff.check(
+            &format!("{} of transposed views", name),
+            Tolerance::EXACT,
+            pair,
+            |x| {
+                let x = views(x);
+                let mut c = output(&x[0])?;
+                op(&x[0], &x[1], &mut c)?;
+                Ok(c)
+            },
+            |x| {
+                let x = ts(x);
+                reference(&x[0], &x[1])
+            },
+        )?;
+    }
+    type ElementOp = fn(f32) -> f32;
+    let unary: &[(&str, UnaryOp, ElementOp, Tolerance)] = &[
+        ("sigmoid", OpenCLArray::sigmoid, sigmoid_op, transcendental),
+        (
+            "sigmoid_prime",
+            OpenCLArray::sigmoid_prime,
+            sigmoid_prime_op,
+            transcendental,
+        ),
+        (
+            "scalar_multiply",
+            |a, b| a.scalar_multiply(-1.5, b),
+            |v| v * -1.5,
+            Tolerance::EXACT,
+        ),
+    ];
+    for &(name, op, reference, tolerance) in unary {
+        diff.check(
+            &format!("{} of a transposed view", name),
+            tolerance,
+            single,
+            |x| apply_unary(op, &x[0].t_view()),
+            |x| x[0].t().mapv(reference),
+        )?;
+    }
+    diff.check(
+        "square of a transposed view",
+        Tolerance::EXACT,
+        single,
+        |x| {
+            let mut c = x[0].t_view();
+            c.square()?;
+            Ok(c)
+        },
+        |x| x[0].t().mapv(|v| v * v),
+    )?;
+
+    diff.check(
+        "inner of a transposed view",
+        Tolerance::accumulated(509 * 509),
+        |n, m, _| vec![(m, n), (n, m)],
+        |x| scalar(&x[0], x[0].t_view().inner(&x[1])?),
+        |x| arr2(&[[(&x[0].t() * &x[1]).sum()]]),
+    )?;
+    diff.check(
+        "axpby of a transposed view",
+        Tolerance {
+            absolute: 1e-6,
+            ..Tolerance::ulps(1)
+        },
+        |n, m, _| vec![(m, n), (n, m)],
+        |x| {
+            let mut y = x[1].deep_clone()?;
+            y.axpby(0.75, &x[0].t_view(), -2.)?;
+            Ok(y)
+        },
+        |x| &x[0].t() * 0.75 + &x[1] * -2.,
+    )?;
+    diff.check(
+        "gemv of a transposed view",
+        Tolerance::accumulated(509),
+        |n, m, _| vec![(m, n), (m, 1), (n, 1)],
+        |x| {
+            let mut y = x[2].deep_clone()?;
+            x[0].t_view().gemv(2., &x[1], 0.5, &mut y)?;
+            Ok(y)
+        },
+        |x| x[0].t().dot(&x[1]) * 2. + &x[2] * 0.5,
+    )?;
+    diff.check(
+        "ger of transposed vectors",
+        Tolerance {
+            absolute: 1e-6,
+            ..Tolerance::ulps(1)
+        },
+        |n, m, _| vec![(n, m), (1, n), (m, 1)],
+        |x| {
+            let mut a = x[0].deep_clone()?;
+            a.ger(-1.5, &x[1].t_view(), &x[2].t_view())?;
+            Ok(a)
+        },
+        |x| &x[0] + &(x[1].t().dot(&x[2].t()) * -1.5),
+    )?;
+    diff.check(
+        "csr spmm of a transposed view",
+        Tolerance::accumulated(509),
+        |n, m, k| vec![(n, m), (k, m)],
+        |x| {
+            let a = OpenCLCsrMatrix::from_array(x[0].backend.clone(), &x[0].clone().to_array()?)?;
+            let mut c = OpenCLArray::uninitialized(x[0].backend.clone(), x[0].rows, x[1].rows)?;
+            a.spmm(&x[1].t_view(), &mut c)?;
+            Ok(c)
+        },
+        |x| x[0].dot(&x[1].t()),
+    )
+}
+
+#[test]
+#[serial]
+fn factorizations_of_transposed_views() -> Result<(), Error> {
+    let backend = test_backend()?;
+    let n = 9;
+
+    // Not symmetric, so factoring the buffer rather than the view would show
+    let a = Array::random((n, n), Uniform::new(-1., 1.)) + Array2::<f32>::eye(n) * 4.;
+    let view = OpenCLArray::from_array(backend.clone(), &a.t().to_owned())?.t_view();
+    assert!(view.is_transposed());
+
+    let lu = view.lu()?;
+    let perm = lu.permutation()?;
+    let pa = Array::from_shape_fn((n, n), |(i, j)| a[[perm[i] as usize, j]]);
+    assert_close(&lu.l()?.to_array()?.dot(&lu.u()?.to_array()?), &pa, 1e-4);
+    let b = Array::random((n, 2), Uniform::new(-1., 1.));
+    let b_view = OpenCLArray::from_array(backend.clone(), &b.t().to_owned())?.t_view();
+    assert_close(&a.dot(&view.solve(&b_view)?.to_array()?), &b, 1e-4);
+
+    let tall = Array::random((n, 5), Uniform::new(-1., 1.));
+    let tall_view = OpenCLArray::from_array(backend.clone(), &tall.t().to_owned())?.t_view();
+    let (q, r) = tall_view.qr()?;
+    assert_close(&q.to_array()?.dot(&r.to_array()?), &tall, 1e-4);
+
+    let s = &a + &a.t();
+    let eigen = OpenCLArray::from_array(backend, &s.t().to_owned())?
+        .t_view()
+        .eigh()?;
+    let vectors = eigen.vectors.to_array()?;
+    let values = Array2::from_diag(&Array1::from(eigen.values.clone()));
+    assert_close(&vectors.dot(&values).dot(&vectors.t()), &s, 1e-3);
+
+    Ok(())
+}
+
+#[test]
+fn tolerance_ulps() {
+    assert_eq!(ulp_distance(1., 1.), 0);
+    assert_eq!(ulp_distance(0., -0.), 0);
+    assert_eq!(ulp_distance(1., f32::from_bits(1f32.to_bits() + 3)), 3);
+    assert_eq!(
+        ulp_distance(-f32::MIN_POSITIVE, f32::MIN_POSITIVE),
+        2 * f32::MIN_POSITIVE.to_bits()
+    );
+    assert_eq!(ulp_distance(f32::NAN, 1.), u32::MAX);
+
+    assert!(Tolerance::EXACT.accepts(f32::NAN, f32::NAN));
+    assert!(!Tolerance::EXACT.accepts(1., 1. + f32::EPSILON));
+    assert!(Tolerance::ulps(1).accepts(1., 1. + f32::EPSILON));
+    assert!(Tolerance::accumulated(100).accepts(1e-5, 0.));
+    assert!(!Tolerance::accumulated(100).accepts(1e-3, 0.));
+}
+
+// Proptest runs each property for random operands of up to this many rows and columns
+const PROPERTY_MAX_DIM: usize = 40;
+
+fn prop_close(
+    op: &str,
+    actual: &Array2<f32>,
+    expected: &Array2<f32>,
+    tolerance: Tolerance,
+) -> Result<(), TestCaseError> {
+    compare(op, actual, expected, tolerance).map_err(TestCaseError::fail)
+}
+
+fn property_runner() -> TestRunner {
+    TestRunner::new(ProptestConfig::with_cases(32))
+}
+
+type BinaryOp = fn(&OpenCLArray, &OpenCLArray, &mut OpenCLArray) -> Result<(), Error>;
+type UnaryOp = fn(&OpenCLArray, &mut OpenCLArray) -> Result<(), Error>;
+
+// Runs `op` on `a` and `b` into a new array
+fn apply(op: BinaryOp, a: &OpenCLArray, b: &OpenCLArray) -> Result<OpenCLArray, Error> {
+    let mut c = OpenCLArray::uninitialized(a.backend.clone(), a.rows, b.cols)?;
+    op(a, b, &mut c)?;
+    Ok(c)
+}
+
+fn apply_unary(op: UnaryOp, a: &OpenCLArray) -> Result<OpenCLArray, Error> {
+    let mut b = OpenCLArray::uninitialized(a.backend.clone(), a.rows, a.cols)?;
+    op(a, &mut b)?;
+    Ok(b)
+}
+
+#[test]
+#[serial]
+fn transpose_properties() {
+    let backend = test_backend().unwrap();
+    let upload = |x: &Array2<f32>| OpenCLArray::from_array(backend.clone(), x);
+
+    // (A^T)^T == A
+    property_runner()
+        .run(
+            &operands_strategy(PROPERTY_MAX_DIM, |n, m, _| vec![(n, m)]),
+            |x| {
+                let a = upload(&x[0])?;
+                let twice = a.t()?.t()?.to_array()?;
+                prop_close("(A^T)^T", &twice, &x[0], Tolerance::EXACT)?;
+                let views = a.t_view().t_view().to_array()?;
+                prop_close("(A^T)^T of views", &views, &x[0], Tolerance::EXACT)
+            },
+        )
+        .unwrap();
+
+    // (A B)^T == B^T A^T
+    property_runner()
+        .run(
+            &operands_strategy(PROPERTY_MAX_DIM, |n, m, k| vec![(n, m), (m, k)]),
+            |x| {
+                let (a, b) = (upload(&x[0])?, upload(&x[1])?);
+                let lhs = apply(OpenCLArray::dot, &a, &b)?.t()?.to_array()?;
+                let rhs = apply(OpenCLArray::dot, &b.t()?, &a.t()?)?.to_array()?;
+                let tolerance = Tolerance::accumulated(x[0].ncols());
+                prop_close("(A B)^T vs B^T A^T", &lhs, &rhs, tolerance)?;
+                prop_close("(A B)^T", &lhs, &x[0].dot(&x[1]).t().to_owned(), tolerance)
+            },
+        )
+        .unwrap();
+}
+
+#[test]
+#[serial]
+fn elementwise_properties() {
+    type Reference = fn(&Array2<f32>, &Array2<f32>) -> Array2<f32>;
+
+    let backend = test_backend().unwrap();
+    let upload = |x: &Array2<f32>| OpenCLArray::from_array(backend.clone(), x);
+    let pair = operands_strategy(PROPERTY_MAX_DIM, |n, m, _| vec![(n, m), (n, m)]);
+
+    // A + B == B + A and A o B == B o A, exactly
+    let commutative: [(&str, BinaryOp, Reference); 2] = [
+        ("add", OpenCLArray::add, |a, b| a + b),
+        ("hadamard", OpenCLArray::hadamard, |a, b| a * b),
+    ];
+    for &(name, op, reference) in &commutative {
+        property_runner()
+            .run(&pair, |x| {
+                let (a, b) = (upload(&x[0])?, upload(&x[1])?);
+                let ab = apply(op, &a, &b)?.to_array()?;
+                let ba = apply(op, &b, &a)?.to_array()?;
+                prop_close(name, &ab, &ba, Tolerance::EXACT)?;
+                prop_close(name, &ab, &reference(&x[0], &x[1]), Tolerance::EXACT)
+            })
+            .unwrap();
+    }
+
+    // c (A + B) == c A + c B, up to the rounding of each side
+    let distributive = Tolerance {
+        ulps: 2,
+        absolute: 1e-6,
+        ..Tolerance::EXACT
+    };
+    property_runner()
+        .run(&(pair, -4f32..4.), |(x, c)| {
+            let (a, b) = (upload(&x[0])?, upload(&x[1])?);
+            let scaled = |x: &OpenCLArray| {
+                let mut y = OpenCLArray::uninitialized(x.backend.clone(), x.rows, x.cols)?;
+                x.scalar_multiply(c, &mut y)?;
+                Ok::<_, Error>(y)
+            };
+            let lhs = scaled(&apply(OpenCLArray::add, &a, &b)?)?.to_array()?;
+            let rhs = apply(OpenCLArray::add, &scaled(&a)?, &scaled(&b)?)?.to_array()?;
+            prop_close("c (A + B) vs c A + c B", &lhs, &rhs, distributive)?;
+            prop_close("c (A + B)", &lhs, &((&x[0] + &x[1]) * c), distributive)
+        })
+        .unwrap();
+
+    // sigmoid'(x) == s(x) (1 - s(x)), over inputs stretched to [-8, 8]
+    let transcendental = Tolerance {
+        ulps: 8,
+        absolute: 1e-6,
+        ..Tolerance::EXACT
+    };
+    property_runner()
+        .run(
+            &operands_strategy(PROPERTY_MAX_DIM, |n, m, _| vec![(n, m)]),
+            |x| {
+                let x = &x[0] * 8.;
+                let a = upload(&x)?;
+                let prime = apply_unary(OpenCLArray::sigmoid_prime, &a)?.to_array()?;
+                let s = apply_unary(OpenCLArray::sigmoid, &a)?;
+                let ones = upload(&Array2::ones(x.dim()))?;
+                let one_minus_s = apply(OpenCLArray::subtract, &ones, &s)?;
+                let product = apply(OpenCLArray::hadamard, &s, &one_minus_s)?.to_array()?;
+                prop_close(
+                    "sigmoid'(x) vs s(x) (1 - s(x))",
+                    &prime,
+                    &product,
+                    transcendental,
+                )?;
+                prop_close(
+                    "sigmoid'(x)",
+                    &prime,
+                    &x.mapv(sigmoid_prime_op),
+                    transcendental,
+                )
+            },
+        )
+        .unwrap();
+}
+
+#[test]
+fn tuning_file_round_trip() {
+    use crate::tuning::*;
+    use ocl::SpatialDims::*;
+
+    let entries = vec![
+        (
+            "GTX 960M".to_string(),
+            "dot_product".to_string(),
+            Two(32, 8),
+        ),
+        ("GTX 960M".to_string(), "dot_partial".to_string(), One(128)),
+        ("pthread".to_string(), "dot_product".to_string(), Two(8, 8)),
+    ];
+    let contents = format_tuning_file(&entries);
+    assert_eq!(contents.lines().next(), Some("GTX 960M\tdot_product\t32,8"));
+    assert_eq!(parse_tuning_file(&contents), entries);
+    assert!(parse_tuning_file("GTX 960M\tdot_product\n\tx\t1,a\n").is_empty());
+
+    let path = std::env::temp_dir().join("carya_tuning_round_trip.tsv");
+    std::fs::write(&path, &contents).unwrap();
+    let tuner = Autotuner::new("pthread", Some(path));
+    assert_eq!(tuner.best("dot_product"), Some(Two(8, 8)));
+    assert_eq!(tuner.tuned().len(), 1);
+    assert!(tuning_candidates("transpose_tiled")
+        .unwrap()
+        .contains(&Two(16, 16)));
+    assert!(tuning_candidates("add").is_none());
+}
+
+#[test]
+#[serial]
+fn autotuned_kernels() -> Result<(), Error> {
+    use crate::tuning::*;
+    use std::sync::Arc;
+
+    let mut backend = test_backend()?;
+    let path = std::env::temp_dir().join("carya_tuning.tsv");
+    let _ = std::fs::remove_file(&path);
+    let device = backend.device_info()?.name;
+    backend.autotuner = Some(Arc::new(Autotuner::new(&device, Some(path.clone()))));
+    let tuner = backend.autotuner.clone().unwrap();
+
+    // Too small to be worth tuning
+    let small = Array::random((10, 10), Uniform::new(-1., 1.));
+    let small_gpu = OpenCLArray::from_array(backend.clone(), &small)?;
+    assert_eq!(small_gpu.t()?.to_array()?, small.t());
+    assert!(tuner.best("transpose_tiled").is_none());
+
+    let (a, b) = (
+        Array::random((200, 150), Uniform::new(-1., 1.)),
+        Array::random((150, 120), Uniform::new(-1., 1.)),
+    );
+    let a_gpu = OpenCLArray::from_array(backend.clone(), &a)?;
+    let b_gpu = OpenCLArray::from_array(backend.clone(), &b)?;
+    let mut c_gpu = OpenCLArray::uninitialized(backend.clone(), 200, 120)?;
+    a_gpu.dot(&b_gpu, &mut c_gpu)?;
+    assert_all_close(
+        "tuned dot",
+        &c_gpu.to_array()?,
+        &a.dot(&b),
+        Tolerance::accumulated(150),
+    );
+    assert_eq!(a_gpu.t()?.to_array()?, a.t());
+
+    let dot = tuner.best("dot_product").unwrap();
+    assert!(tuning_candidates("dot_product").unwrap().contains(&dot));
+    assert!(tuner.best("transpose_tiled").is_some());
+
+    // Reductions always launch the same number of work items, so it's their length that
+    // decides whether they are tuned
+    small_gpu.inner(&small_gpu)?;
+    assert!(tuner.best("dot_partial").is_none());
+    let x = Array::random((1, 1 << 17), Uniform::new(-1., 1.));
+    let x_gpu = OpenCLArray::from_array(backend.clone(), &x)?;
+    assert_all_close(
+        "tuned inner",
+        &arr2(&[[x_gpu.inner(&x_gpu)?]]),
+        &arr2(&[[x.iter().map(|v| v * v).sum::<f32>()]]),
+        Tolerance::accumulated(1 << 17),
+    );
+    let reduction = tuner.best("dot_partial").unwrap();
+    assert!(tuning_candidates("dot_partial")
+        .unwrap()
+        .contains(&reduction));
+    assert_eq!(Autotuner::new(&device, Some(path)).tuned(), tuner.tuned());
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn multi_backend() -> Result<(), Error> {
+    use crate::multi::*;
+
+    // Two backends on the test device stand in for two devices
+    let multi = MultiBackEnd::new(vec![test_backend()?, test_backend()?]);
+    assert_eq!(multi.row_ranges(7), vec![0..4, 4..7]);
+    let weighted = multi.clone().with_weights(&[3., 1.]);
+    assert_eq!(weighted.row_ranges(8), vec![0..6, 6..8]);
+    assert_eq!(weighted.row_ranges(1), vec![0..1, 1..1]);
+
+    let (a, b, w) = (
+        Array::random((37, 20), Uniform::new(-1., 1.)),
+        Array::random((37, 20), Uniform::new(-1., 1.)),
+        Array::random((20, 9), Uniform::new(-1., 1.)),
+    );
+    let a_sharded = multi.shard_rows(&a)?;
+    let b_sharded = multi.shard_rows(&b)?;
+    assert_eq!(a_sharded.shards.len(), 2);
+    assert_eq!(a_sharded.to_array()?, a);
+
+    let product = a_sharded.dot(&multi.replicate(&w)?)?;
+    assert_all_close(
+        "sharded dot",
+        &product.to_array()?,
+        &a.dot(&w),
+        Tolerance::accumulated(20),
+    );
+    let sum = a_sharded.add(&b_sharded)?.scalar_multiply(2.)?;
+    assert_all_close(
+        "sharded add",
+        &sum.gather(&multi.backends[1])?.to_array()?,
+        &((&a + &b) * 2.),
+        Tolerance::ulps(1),
+    );
+
+    // A single row leaves the second backend without a shard
+    let row = a.slice(s![..1, ..]).to_owned();
+    let row_sharded = weighted.shard_rows(&row)?;
+    assert_eq!(row_sharded.shards.len(), 1);
+    assert_eq!(row_sharded.hadamard(&row_sharded)?.to_array()?, &row * &row);
+
+    let copy = a_sharded.shards[0].to_backend(&multi.backends[1])?;
+    assert_eq!(copy.to_array()?, a.slice(s![..19, ..]));
+
+    // The first gradient is a view, which the reduction reads and overwrites in its layout
+    let mut gradients = multi.replicate(&a)?;
+    let a_t = OpenCLArray::from_array(multi.backends[0].clone(), &a.t().to_owned())?;
+    gradients[0] = a_t.t_view();
+    gradients[1] = OpenCLArray::from_array(multi.backends[1].clone(), &b)?;
+    multi.all_reduce(&mut gradients, ReduceOp::Mean)?;
+    for gradient in gradients {
+        assert_all_close(
+            "all-reduce",
+            &gradient.to_array()?,
+            &((&a + &b) / 2.),
+            Tolerance::ulps(1),
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn copy_semantics() -> Result<(), Error> {
+    let backend = test_backend()?;
+    let a = Array::random((13, 21), Uniform::new(-1., 1.));
+    let mut a_gpu = OpenCLArray::from_array(backend.clone(), &a)?;
+
+    // Sharing aliases the buffer, deep clones don't
+    let shared = a_gpu.share();
+    let copy = a_gpu.deep_clone()?;
+    a_gpu.square()?;
+    assert_eq!(shared.to_array()?, a.mapv(|x| x * x));
+    assert_eq!(copy.share().to_array()?, a);
+
+    let mut other = OpenCLArray::new(backend.clone(), 13, 21)?;
+    copy.copy_to(&mut other)?;
+    assert_eq!(other.to_array()?, a);
+    let mut view_copy = OpenCLArray::new(backend.clone(), 21, 13)?;
+    copy.t_view().copy_to(&mut view_copy)?;
+    assert!(view_copy.is_transposed());
+    assert_eq!(view_copy.to_array()?, a.t());
+
+    // Another backend on the same device, with a separate context
+    let elsewhere = test_backend()?;
+    assert!(!backend.shares_context(&elsewhere));
+    assert!(backend.shares_context(&backend.on_queue(0)));
+    let moved = copy.to_backend(&elsewhere)?;
+    assert!(moved.backend.shares_context(&elsewhere));
+    assert_eq!(moved.to_array()?, a);
+    assert_eq!(copy.t_view().to_backend(&elsewhere)?.to_array()?, a.t());
+
+    let buffer = copy.v.clone();
+    let rebound = copy.into_backend(&backend.on_queue(0))?;
+    assert_eq!(rebound.v.as_core().as_ptr(), buffer.as_core().as_ptr());
+    assert_eq!(rebound.into_backend(&elsewhere)?.to_array()?, a);
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn pinned_transfers() -> Result<(), Error> {
+    let backend = test_backend()?;
+    let a = Array::random((24, 17), Uniform::new(-1., 1.));
+    let data = create_vec(&a);
+    let mut pinned = PinnedHostBuffer::new(&backend, data.len())?;
+    pinned.write(&data)?;
+    assert_eq!(pinned.to_vec()?, data);
+
+    let a_gpu = OpenCLArray::uninitialized(backend.clone(), 24, 17)?;
+    pinned.upload(&a_gpu)?;
+    assert_eq!(a_gpu.clone().to_array()?, a);
+    assert_eq!(pinned.stage(24, 17)?.to_array()?, a);
+
+    // Mapped writes reach the device, and downloads reach the mapping
+    pinned.map()?.iter_mut().for_each(|x| *x *= 2.);
+    let mut doubled = pinned.stage(24, 17)?;
+    assert_eq!(doubled.clone().to_array()?, &a * 2.);
+    doubled.square()?;
+    pinned.download(&doubled)?;
+    assert_eq!(pinned.to_vec()?, create_vec(&(&a * &a * 4.)));
+
+    // Through a second queue, as when overlapping transfers with compute
+    let config = BackendConfig {
+        queues: 2,
+        ..test_config()
+    };
+    let backend = CLBackEnd::from_config(config)?;
+    let mut pinned = PinnedHostBuffer::new(&backend, data.len())?;
+    pinned.write(&data)?;
+    let a_gpu = OpenCLArray::new(backend.on_queue(1), 24, 17)?;
+    pinned.upload(&a_gpu)?;
+    // Refilling the buffer straight away mustn't race the copy still queued on queue 1
+    pinned.write(&vec![0.; data.len()])?;
+    assert_eq!(a_gpu.to_array()?, a);
+    assert_eq!(
+        pinned.is_zero_copy(),
+        backend.device_info()?.host_unified_memory
+    );
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn device_constructors() -> Result<(), Error> {
+    let backend = test_backend()?;
+    let b = || backend.clone();
+
+    assert_eq!(
+        OpenCLArray::zeros(b(), 5, 7)?.to_array()?,
+        Array2::<f32>::zeros((5, 7))
+    );
+    assert_eq!(
+        OpenCLArray::ones(b(), 5, 7)?.to_array()?,
+        Array2::<f32>::ones((5, 7))
+    );
+    assert_eq!(
+        OpenCLArray::full(b(), 300, 3, -2.5)?.to_array()?,
+        Array2::from_elem((300, 3), -2.5)
+    );
+    assert_eq!(
+        OpenCLArray::eye(b(), 37)?.to_array()?,
+        Array2::<f32>::eye(37)
+    );
+    assert_eq!(
+        OpenCLArray::identity(b(), 4, 6)?.to_array()?,
+        Array::from_shape_fn((4, 6), |(i, j)| if i == j { 1. } else { 0. })
+    );
+
+    let range = OpenCLArray::arange(b(), 1., 2., 0.25)?;
+    assert_eq!(range.to_vec()?, vec![1., 1.25, 1.5, 1.75]);
+    assert!(OpenCLArray::arange(b(), 3., 1., 1.)?.is_empty());
+    assert_eq!(
+        OpenCLArray::arange(b(), 5., 0., -2.)?.to_vec()?,
+        vec![5., 3., 1.]
+    );
+    let points = OpenCLArray::linspace(b(), -1., 2., 101)?.to_vec()?;
+    assert_eq!((points[0], points[100]), (-1., 2.));
+    assert!((points[50] - 0.5).abs() < 1e-6);
+    assert_eq!(OpenCLArray::linspace(b(), 4., 9., 1)?.to_vec()?, vec![4.]);
+
+    let d = array![[1., -2., 3.]];
+    let d_gpu = OpenCLArray::from_array(b(), &d)?;
+    assert_eq!(
+        OpenCLArray::diag(&d_gpu)?.to_array()?,
+        Array2::from_diag(&d.row(0))
+    );
+    assert_eq!(
+        OpenCLArray::diag(&d_gpu)?.diagonal()?.to_vec()?,
+        d.row(0).to_vec()
+    );
+
+    let (rows, cols) = (9, 6);
+    let expected: Vec<(IndexFn, Array2<f32>)> = vec![
+        (
+            IndexFn::Affine {
+                row: 1.,
+                col: rows as f32,
+                offset: 0.5,
+            },
+            Array::from_shape_fn((rows, cols), |(i, j)| (i + j * rows) as f32 + 0.5),
+        ),
+        (
+            IndexFn::LowerTriangle,
+            Array::from_shape_fn((rows, cols), |(i, j)| if i >= j { 1. } else { 0. }),
+        ),
+        (
+            IndexFn::UpperTriangle,
+            Array::from_shape_fn((rows, cols), |(i, j)| if i <= j { 1. } else { 0. }),
+        ),
+        (
+            IndexFn::Band(1),
+            Array::from_shape_fn((rows, cols), |(i, j)| {
+                if (i as i64 - j as i64).abs() <= 1 {
+                    1.
+                } else {
+                    0.
+                }
+            }),
+        ),
+        (
+            IndexFn::Decay(0.5),
+            Array::from_shape_fn((rows, cols), |(i, j)| {
+                0.5f32.powi((i as i32 - j as i32).abs())
+            }),
+        ),
+        (
+            IndexFn::Hilbert,
+            Array::from_shape_fn((rows, cols), |(i, j)| 1. / (i + j + 1) as f32),
+        ),
+    ];
+    for (f, array) in expected {
+        assert_all_close(
+            &format!("{:?}", f),
+            &OpenCLArray::from_fn(b(), rows, cols, f)?.to_array()?,
+            &array,
+            Tolerance::ulps(2),
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn concatenate_and_split() -> Result<(), Error> {
+    let backend = test_backend()?;
+    let upload = |a: &Array2<f32>| OpenCLArray::from_array(backend.clone(), a);
+    let (a, b, c) = (
+        Array::random((5, 7), Uniform::new(-1., 1.)),
+        Array::random((3, 7), Uniform::new(-1., 1.)),
+        Array::random((5, 2), Uniform::new(-1., 1.)),
+    );
+    let (a_gpu, b_gpu, c_gpu) = (upload(&a)?, upload(&b)?, upload(&c)?);
+
+    let rows = OpenCLArray::concatenate(Axis(0), &[&a_gpu, &b_gpu, &a_gpu])?;
+    assert_eq!(
+        rows.to_array()?,
+        ndarray::stack(Axis(0), &[a.view(), b.view(), a.view()]).unwrap()
+    );
+    let cols = OpenCLArray::concatenate(Axis(1), &[&a_gpu, &c_gpu, &a_gpu.t_view().t_view()])?;
+    assert_eq!(
+        cols.to_array()?,
+        ndarray::stack(Axis(1), &[a.view(), c.view(), a.view()]).unwrap()
+    );
+    // A transposed view is joined as the array it shows
+    let mixed = OpenCLArray::concatenate(Axis(0), &[&b_gpu.t_view(), &c_gpu.t_view()])?;
+    assert_eq!(
+        mixed.to_array()?,
+        ndarray::stack(Axis(0), &[b.t(), c.t()]).unwrap()
+    );
+    let stacked = OpenCLArray::stack(&[&a_gpu, &a_gpu])?;
+    assert_eq!(
+        stacked.to_array()?,
+        ndarray::stack(Axis(0), &[a.view(), a.view()]).unwrap()
+    );
+
+    for &(axis, index) in &[(Axis(0), 0), (Axis(0), 2), (Axis(1), 3), (Axis(1), 7)] {
+        let (left, right) = a_gpu.split_at(axis, index)?;
+        let (left_ref, right_ref) = a.view().split_at(axis, index);
+        assert_eq!(left.to_array()?, left_ref);
+        assert_eq!(right.to_array()?, right_ref);
+    }
+    let (top, bottom) = a_gpu.t_view().split_at(Axis(0), 4)?;
+    assert_eq!(top.to_array()?, a.t().slice(s![..4, ..]));
+    assert_eq!(bottom.to_array()?, a.t().slice(s![4.., ..]));
+
+    for &axis in &[Axis(0), Axis(1)] {
+        let chunks = a_gpu.chunks(axis, 2)?;
+        let expected: Vec<_> = a.axis_chunks_iter(axis, 2).collect();
+        assert_eq!(chunks.len(), expected.len());
+        for (chunk, chunk_ref) in chunks.into_iter().zip(expected) {
+            assert_eq!(chunk.to_array()?, chunk_ref);
+        }
+    }
+
+    let small = array![[1., 2.], [3., 4.]];
+    let small_gpu = upload(&small)?;
+    assert_eq!(
+        small_gpu.tile(2, 3)?.to_array()?,
+        array![
+            [1., 2., 1., 2., 1., 2.],
+            [3., 4., 3., 4., 3., 4.],
+            [1., 2., 1., 2., 1., 2.],
+            [3., 4., 3., 4., 3., 4.]
+        ]
+    );
+    assert_eq!(
+        small_gpu.repeat(Axis(0), 2)?.to_array()?,
+        array![[1., 2.], [1., 2.], [3., 4.], [3., 4.]]
+    );
+    assert_eq!(
+        small_gpu.repeat(Axis(1), 3)?.to_array()?,
+        array![[1., 1., 1., 2., 2., 2.], [3., 3., 3., 4., 4., 4.]]
+    );
+    assert_eq!(
+        small_gpu.t_view().tile(1, 2)?.to_array()?,
+        array![[1., 3., 1., 3.], [2., 4., 2., 4.]]
+    );
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn gather_and_scatter() -> Result<(), Error> {
+    let backend = test_backend()?;
+    let a = Array::random((6, 4), Uniform::new(-1., 1.));
+    let a_gpu = OpenCLArray::from_array(backend.clone(), &a)?;
+
+    // A shuffled mini-batch, with a repeat
+    let batch = [4, 0, 5, 0];
+    let rows = OpenCLIndices::vector(backend.clone(), &batch)?;
+    assert_eq!(rows.to_vec()?, vec![4, 0, 5, 0]);
+    assert_eq!(
+        a_gpu.select_rows(&rows)?.to_array()?,
+        a.select(Axis(0), &batch)
+    );
+    let cols = OpenCLIndices::vector(backend.clone(), &[3, 1])?;
+    assert_eq!(
+        a_gpu.select_cols(&cols)?.to_array()?,
+        a.select(Axis(1), &[3, 1])
+    );
+    assert_eq!(
+        a_gpu.t_view().select_rows(&cols)?.to_array()?,
+        a.t().select(Axis(0), &[3, 1])
+    );
+
+    // The score of each row's label, and the gradient flowing back to it
+    let labels = OpenCLIndices::from_vec(backend.clone(), 6, 1, vec![0, 3, 3, 1, 2, 0])?;
+    assert_eq!((labels.rows(), labels.cols()), (6, 1));
+    let picked = a_gpu.gather(Axis(1), &labels)?.to_array()?;
+    let label_vec = labels.to_vec()?;
+    assert_eq!(
+        picked,
+        Array::from_shape_fn((6, 1), |(i, _)| a[[i, label_vec[i] as usize]])
+    );
+    let mut grad = OpenCLArray::zeros(backend.clone(), 6, 4)?;
+    let ones = OpenCLArray::ones(backend.clone(), 6, 1)?;
+    grad.scatter_add(Axis(1), &labels, &ones)?;
+    let one_hot = OpenCLArray::one_hot(&labels, 4)?.to_array()?;
+    assert_eq!(grad.to_array()?, one_hot);
+    assert_eq!(one_hot.sum_axis(Axis(1)), Array1::<f32>::ones(6));
+
+    let by_column = OpenCLIndices::from_vec(backend.clone(), 2, 4, vec![5, 0, 1, 5, 0, 0, 2, 5])?;
+    let gathered = a_gpu.gather(Axis(0), &by_column)?.to_array()?;
+    let by_column_vec = by_column.to_vec()?;
+    assert_eq!(
+        gathered,
+        Array::from_shape_fn((2, 4), |(i, j)| a[[by_column_vec[i * 4 + j] as usize, j]])
+    );
+    let mut scattered = OpenCLArray::zeros(backend.clone(), 6, 4)?;
+    scattered.scatter_add(
+        Axis(0),
+        &by_column,
+        &OpenCLArray::from_array(backend.clone(), &gathered)?,
+    )?;
+    let mut expected = Array2::<f32>::zeros((6, 4));
+    for i in 0..2 {
+        for j in 0..4 {
+            expected[[by_column_vec[i * 4 + j] as usize, j]] += gathered[[i, j]];
+        }
+    }
+    assert_eq!(scattered.to_array()?, expected);
+
+    // Embedding lookup and its gradient
+    let table = Array::random((5, 3), Uniform::new(-1., 1.));
+    let mut table_gpu = OpenCLArray::from_array(backend.clone(), &table)?;
+    let tokens = OpenCLIndices::vector(backend.clone(), &[2, 2, 4])?;
+    let embedded = table_gpu.select_rows(&tokens)?;
+    table_gpu.scatter_add_rows(&tokens, &embedded)?;
+    let mut updated = table.clone();
+    for &t in &[2, 2, 4] {
+        let row = table.row(t).to_owned();
+        updated.row_mut(t).scaled_add(1., &row);
+    }
+    assert_all_close(
+        "scatter_add_rows",
+        &table_gpu.to_array()?,
+        &updated,
+        Tolerance::ulps(1),
+    );
+
+    // Boolean masks
+    let mask = a.mapv(|x| if x > 0. { 1. } else { 0. });
+    let mask_gpu = OpenCLArray::from_array(backend.clone(), &mask)?;
+    let positive: Vec<f32> = a.iter().copied().filter(|&x| x > 0.).collect();
+    assert_eq!(a_gpu.masked_select(&mask_gpu)?.to_vec()?, positive);
+    let mut clipped = a_gpu.deep_clone()?;
+    clipped.masked_fill(&mask_gpu, 0.)?;
+    assert_eq!(clipped.to_array()?, a.mapv(|x| x.min(0.)));
+    let keep = OpenCLArray::from_vec(backend.clone(), 6, 1, vec![1., 0., 0., 1., 1., 0.])?;
+    assert_eq!(
+        a_gpu.select_rows_where(&keep)?.to_array()?,
+        a.select(Axis(0), &[0, 3, 4])
+    );
+    assert!(OpenCLIndices::nonzero(&OpenCLArray::zeros(backend.clone(), 2, 2)?)?.is_empty());
+
+    Ok(())
+}
@@ -0,0 +1,256 @@
+use crate::kernels::*;
+
+use log::{debug, warn};
+use ocl::error::Error;
+use ocl::{Event, ProQue, Queue, SpatialDims, SpatialDims::*};
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Timed launches of each candidate, after one untimed warm-up launch
+const TIMED_RUNS: u32 = 3;
+// Smaller launches use the default local size rather than being tuned, as their timings
+// wouldn't carry over to the sizes that matter
+const MIN_TUNING_WORK_ITEMS: usize = 1 << 14;
+// The reductions always launch the same number of partial sums whatever their input, so
+// they are held to a minimum input length instead
+const MIN_TUNING_REDUCTION_LEN: usize = 1 << 16;
+
+// The local sizes tried for each tunable kernel. Every candidate has to be valid for the
+// kernel: `transpose_tiled` needs square tiles no larger than its local memory tile.
+const DOT_CANDIDATES: &[SpatialDims] = &[
+    Two(8, 8),
+    Two(16, 16),
+    Two(16, 8),
+    Two(8, 16),
+    Two(32, 8),
+    Two(8, 32),
+    Two(32, 4),
+    Two(4, 32),
+];
+const TRANSPOSE_CANDIDATES: &[SpatialDims] = &[Two(4, 4), Two(8, 8), Two(16, 16)];
+const REDUCTION_CANDIDATES: &[SpatialDims] = &[One(32), One(64), One(128), One(256)];
+const REDUCTIONS: &[&str] = &[
+    "dot_partial",
+    "asum_partial",
+    "off_diagonal_partial",
+    "iamax_partial",
+];
+
+/// The candidate local sizes for `kernel`, or `None` if it isn't tuned
+pub fn tuning_candidates(kernel: &str) -> Option<&'static [SpatialDims]> {
+    match kernel {
+        "dot_product" | "dot_product_transposed" => Some(DOT_CANDIDATES),
+        "transpose_tiled" => Some(TRANSPOSE_CANDIDATES),
+        _ if REDUCTIONS.contains(&kernel) => Some(REDUCTION_CANDIDATES),
+        _ => None,
+    }
+}
+
+// Whether a launch of `kernel` is large enough to tune on. A reduction's length is its first
+// `ulong` argument.
+fn worth_tuning(kernel: &str, args: &[Arg], gws: SpatialDims) -> bool {
+    if !REDUCTIONS.contains(&kernel) {
+        return gws.to_len() >= MIN_TUNING_WORK_ITEMS;
+    }
+    let len = args.iter().find_map(|arg| match *arg {
+        Arg::Ulong(n) => Some(n as usize),
+        _ => None,
+    });
+    len.is_some_and(|n| n >= MIN_TUNING_REDUCTION_LEN)
+}
+
+/// Picks the local size of the tunable kernels (`dot`, the tiled transpose and the
+/// reductions) for one device. The first launch of each such kernel times every candidate
+/// on its actual arguments; the fastest one is used from then on and stored in a cache file
+/// shared by every backend on a device of the same name.
+#[derive(Debug)]
+pub struct Autotuner {
+    device: String,
+    path: Option<PathBuf>,
+    best: Mutex<HashMap<String, SpatialDims>>,
+}
+
+impl Autotuner {
+    /// An autotuner for the device named `device`, starting from the configurations already
+    /// stored for it in `path`, if any
+    pub fn new(device: &str, path: Option<PathBuf>) -> Self {
+        let best = path
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|contents| {
+                parse_tuning_file(&contents)
+                    .into_iter()
+                    .filter(|(d, _, _)| d == device)
+                    .map(|(_, kernel, lws)| (kernel, lws))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Autotuner {
+            device: device.to_string(),
+            path,
+            best: Mutex::new(best),
+        }
+    }
+
+    /// `tuning.tsv` in the program binary cache directory; see `program_cache_dir`
+    pub fn default_path() -> Option<PathBuf> {
+        program_cache_dir().map(|dir| dir.join("tuning.tsv"))
+    }
+
+    /// The local size chosen for `kernel`, if it has been tuned
+    pub fn best(&self, kernel: &str) -> Option<SpatialDims> {
+        self.best.lock().unwrap().get(kernel).copied()
+    }
+
+    /// Every kernel tuned so far and its local size
+    pub fn tuned(&self) -> HashMap<String, SpatialDims> {
+        self.best.lock().unwrap().clone()
+    }
+
+    /// The local size to launch `name` with over `gws`: the tuned one, found now by timing the
+    /// candidates if this is the kernel's first large launch, or `None` if it isn't tunable or
+    /// the launch is too small to use tuned sizes on
+    #[allow(clippy::too_many_arguments)]
+    pub fn local_size(
+        &self,
+        kernels: &KernelCache,
+        proque: &ProQue,
+        queue: &Queue,
+        name: &str,
+        args: &[Arg],
+        gws: SpatialDims,
+    ) -> Result<Option<SpatialDims>, Error> {
+        let candidates = match tuning_candidates(name) {
+            Some(candidates) => candidates,
+            None => return Ok(None),
+        };
+        if !worth_tuning(name, args, gws) {
+            return Ok(None);
+        }
+        if let Some(lws) = self.best(name) {
+            return Ok(Some(lws));
+        }
+
+        let max_wg_size = proque.device().max_wg_size()?;
+        let mut fastest: Option<(Duration, SpatialDims)> = None;
+        queue.finish()?;
+        for &lws in candidates.iter().filter(|lws| lws.to_len() <= max_wg_size) {
+            // A candidate the device rejects, e.g. for exceeding a per-dimension limit, is
+            // just skipped
+            let launch = || {
+                kernels.enq(
+                    proque,
+                    queue,
+                    name,
+                    args,
+                    gws,
+                    Some(lws),
+                    &mut Event::empty(),
+                )
+            };
+            if launch().and_then(|_| queue.finish()).is_err() {
+                continue;
+            }
+            let start = Instant::now();
+            for _ in 0..TIMED_RUNS {
+                launch()?;
+            }
+            queue.finish()?;
+            let elapsed = start.elapsed();
+            debug!(
+                "{} with local size {:?}: {:?}",
+                name,
+                lws,
+                elapsed / TIMED_RUNS
+            );
+            if fastest.is_none_or(|(time, _)| elapsed < time) {
+                fastest = Some((elapsed, lws));
+            }
+        }
+
+        let lws = match fastest {
+            Some((_, lws)) => lws,
+            None => return Ok(None),
+        };
+        debug!("Tuned {} on {} to local size {:?}", name, self.device, lws);
+        self.best.lock().unwrap().insert(name.to_string(), lws);
+        self.save();
+        Ok(Some(lws))
+    }
+
+    // Merges this device's configurations into the cache file. Failing to write it only costs
+    // retuning next time.
+    fn save(&self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let mut entries: Vec<(String, String, SpatialDims)> = fs::read_to_string(path)
+            .map(|contents| parse_tuning_file(&contents))
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(device, _, _)| device != &self.device)
+            .collect();
+        for (kernel, &lws) in self.best.lock().unwrap().iter() {
+            entries.push((self.device.clone(), kernel.clone(), lws));
+        }
+        entries.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+
+        let written = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(path, format_tuning_file(&entries)));
+        if let Err(e) = written {
+            warn!(
+                "Couldn't save tuned local sizes to {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// The lines `device<TAB>kernel<TAB>local size` of a tuning cache file, the local size's
+/// dimensions separated by commas. Malformed lines are skipped.
+pub fn parse_tuning_file(contents: &str) -> Vec<(String, String, SpatialDims)> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let (device, kernel, lws) = (fields.next()?, fields.next()?, fields.next()?);
+            let dims: Vec<usize> = lws
+                .split(',')
+                .map(|d| d.trim().parse().ok())
+                .collect::<Option<_>>()?;
+            let lws = match dims[..] {
+                [x] => One(x),
+                [x, y] => Two(x, y),
+                [x, y, z] => Three(x, y, z),
+                _ => return None,
+            };
+            Some((device.to_string(), kernel.to_string(), lws))
+        })
+        .collect()
+}
+
+pub fn format_tuning_file(entries: &[(String, String, SpatialDims)]) -> String {
+    entries
+        .iter()
+        .map(|(device, kernel, lws)| {
+            let dims: Vec<String> = match *lws {
+                One(x) => vec![x],
+                Two(x, y) => vec![x, y],
+                Three(x, y, z) => vec![x, y, z],
+                Unspecified => vec![],
+            }
+            .iter()
+            .map(usize::to_string)
+            .collect();
+            format!("{}\t{}\t{}\n", device, kernel, dims.join(","))
+        })
+        .collect()
+}